@@ -1,26 +1,240 @@
-use std::collections::HashMap;
+use std::collections::{ BTreeMap, HashSet };
+use std::fmt;
 use bigdecimal::BigDecimal;
 use num_traits::Zero;
+use serde::{ Deserialize, Serialize };
 
-use crate::transaction::{Transaction, TransactionType, DisputeState};
+use crate::money::Money;
+use crate::transaction::{Transaction, TransactionType, DisputeAction, DisputeActionType, DisputeState};
 
-#[derive(Clone, Debug)]
-/// Structure for tracking account state
+#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Structure for tracking account state. Settings that come from the command
+/// line each run are not serialized
 pub struct Account {
     /// The unique ID of the account
     pub id: u16,
     /// The account's current available balance. Available balance 
     /// can be utilized for withdrawals.
-    pub available_balance: BigDecimal,
+    pub available_balance: Money,
     /// The account's current held balance. Held balance relates to
-    /// disputed transactions
-    pub held_balance: BigDecimal,
+    /// disputed transactions and manual holds
+    pub held_balance: Money,
+    /// The part of the held balance placed on hold manually, rather than
+    /// by a dispute
+    pub manually_held: Money,
     /// The total list of transactions this account has experienced,
-    /// allowing us to later resolve disputes
-    pub transactions: HashMap<u32, Transaction>,
-    /// Whether the account has been frozen. An account is a frozen
-    /// if a chargeback has been processed on it
-    pub is_frozen: bool,
+    /// allowing us to later resolve disputes. Kept in transaction ID order,
+    /// so that anything listing them is reproducible
+    pub transactions: BTreeMap<u32, Transaction>,
+    /// Why the account has been frozen, or `None` if it has not been. An
+    /// account is frozen if a chargeback has been processed on it
+    pub freeze_reason: Option<FreezeReason>,
+    /// Whether the account was already frozen when it was loaded from a snapshot,
+    /// so that the transactions the freeze rejects are worth warning about
+    #[serde(skip)]
+    pub frozen_when_loaded: bool,
+    /// The number of disputes opened on the account's transactions, whatever
+    /// their outcome
+    pub dispute_count: u32,
+    /// The currency of the account's transactions, set by its first deposit that
+    /// gives one. Transactions in any other currency are rejected
+    pub currency: Option<String>,
+    /// The number of disputes after which the account is frozen, even if none
+    /// was charged back. Never frozen for its disputes alone if not set
+    #[serde(skip)]
+    pub freeze_after_disputes: Option<u32>,
+    /// Whether freezing the account also stops disputes, resolves, chargebacks, and
+    /// manual holds and releases, so that nothing about it can change once frozen
+    #[serde(skip)]
+    pub hard_freeze: bool,
+    /// The input sequence number of the last row that changed this account,
+    /// if tracked. Only tracked when verbose output columns are requested
+    pub last_seq: Option<u64>,
+    /// Whether the account must be explicitly opened before it accepts
+    /// deposits and withdrawals
+    #[serde(skip)]
+    pub require_open: bool,
+    /// Whether the account has been explicitly opened
+    pub is_open: bool,
+    /// Whether a chargeback requires the whole of the disputed transaction to
+    /// still be held, rejecting chargebacks on partially resolved disputes
+    pub chargeback_requires_full_hold: bool,
+    /// How far below zero withdrawals may take the available balance
+    #[serde(skip)]
+    pub overdraft_limit: Money,
+    /// The flat fee taken from the available balance with each withdrawal
+    #[serde(skip)]
+    pub withdrawal_fee: Money,
+    /// The number of decimal places to round the balances back to after each
+    /// applied dispute action, in case fees, interest, or opening balances left
+    /// them at some other precision. Left as they are if `None`
+    #[serde(skip)]
+    pub normalize_on_dispute: Option<i64>,
+    /// Whether withdrawals may be disputed as fraudulent. A disputed withdrawal
+    /// holds the withdrawn amount, which a chargeback returns to the available balance
+    pub withdrawal_disputes: bool,
+    /// Whether each kind of dispute action may only be applied to a transaction once,
+    /// so that a replayed feed can't re-dispute a transaction that was since resolved
+    #[serde(skip)]
+    pub strict_dispute_dedup: bool,
+    /// The dispute actions applied to each transaction, by transaction ID.
+    /// Only recorded under `strict_dispute_dedup`
+    pub applied_dispute_actions: HashSet<(u32, DisputeActionType)>,
+    /// What to do when a dispute action finds the account in a state that
+    /// should be impossible, such as the held balance about to go negative
+    #[serde(skip)]
+    pub on_invariant_violation: InvariantPolicy,
+    /// The transaction ID to trace, if any. Every decision the account makes
+    /// about that transaction is described in `trace_log`
+    #[serde(skip)]
+    pub trace_tx: Option<u32>,
+    /// Descriptions of the decisions made about `trace_tx`, in order,
+    /// until they are taken by the caller
+    #[serde(skip)]
+    pub trace_log: Vec<String>,
+}
+
+/// Describe a decision about a transaction in the account's `trace_log`,
+/// if that transaction is being traced. This only touches the tracing
+/// fields, so it can be used while a transaction is borrowed
+macro_rules! trace {
+    ($account:expr, $tx:expr, $($message:tt)*) => {
+        if $account.trace_tx == Some($tx) {
+            $account.trace_log.push(format!($($message)*));
+        }
+    };
+}
+
+/// The reason an account was frozen
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FreezeReason {
+    /// A disputed transaction was charged back
+    Chargeback { tx: u32 },
+    /// The account reached its limit of disputes
+    DisputeLimit { disputes: u32 },
+    /// The account was frozen by an operator
+    ManualFreeze,
+    /// The account has been closed
+    Closure
+}
+
+impl fmt::Display for FreezeReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FreezeReason::Chargeback { tx } => write!(f, "chargeback:{}", tx),
+            FreezeReason::DisputeLimit { disputes } => write!(f, "disputes:{}", disputes),
+            FreezeReason::ManualFreeze => write!(f, "manual_freeze"),
+            FreezeReason::Closure => write!(f, "closure"),
+        }
+    }
+}
+
+/// The effect a hypothetical transaction would have on an account,
+/// as computed by `Account::dry_apply`
+#[derive(Clone, Debug)]
+pub struct DryApplyReport {
+    /// The transaction that was hypothetically applied
+    pub transaction: Transaction,
+    /// Whether the account would have accepted the transaction
+    pub succeeded: bool,
+    /// The available balance before the transaction
+    pub available_before: Money,
+    /// The held balance before the transaction
+    pub held_before: Money,
+    /// The available balance after the transaction
+    pub available_after: Money,
+    /// The held balance after the transaction
+    pub held_after: Money,
+}
+
+impl fmt::Display for DryApplyReport {
+    /// Write the report, with every amount at the decimal places given as the
+    /// precision (as in `{:.2}`) if there is one
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let precision = f.precision();
+        let money = |amount: &Money| match precision {
+            Some(decimals) => format!("{:.*}", decimals, amount),
+            None => amount.to_string()
+        };
+        writeln!(f, "Dry apply of {:?} {} on client {}: {}",
+            self.transaction.transaction_type,
+            money(&self.transaction.amount),
+            self.transaction.client_id,
+            if self.succeeded { "would succeed" } else { "would be rejected" })?;
+        writeln!(f, "  available: {} -> {}", money(&self.available_before), money(&self.available_after))?;
+        write!(f, "  held: {} -> {}", money(&self.held_before), money(&self.held_after))
+    }
+}
+
+/// What happened when a transaction was registered with an account
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TransactionOutcome {
+    /// The transaction was applied to the balance and recorded
+    Applied,
+    /// The account is frozen
+    RejectedFrozen,
+    /// The account must be opened first
+    RejectedNotOpen,
+    /// The account already has a transaction with the same ID
+    RejectedDuplicate,
+    /// The withdrawal is for more than the available balance
+    RejectedInsufficientFunds,
+    /// The transaction is in a different currency from the account
+    RejectedCurrency,
+    /// The client is over its rate limit. Only decided by the engine
+    RejectedRate
+}
+
+/// What happened when a dispute action was applied to an account
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DisputeOutcome {
+    /// The action changed the dispute state of the transaction
+    Applied,
+    /// The action was for a different client's account
+    ClientMismatch,
+    /// The account has no record of the transaction
+    UnknownTransaction,
+    /// The transaction has already been charged back. A chargeback is final,
+    /// so no further dispute, resolve, or chargeback applies to it
+    AlreadyChargedBack,
+    /// The same kind of action was already applied to the transaction, and
+    /// the account applies each kind at most once
+    AlreadyApplied,
+    /// A deposit could not be disputed, as the available balance doesn't cover it
+    InsufficientFunds,
+    /// A resolve or chargeback was for more than is held
+    InsufficientHeld,
+    /// The transaction is a withdrawal, and the account doesn't allow withdrawals
+    /// to be disputed
+    WithdrawalsNotDisputable,
+    /// The account is frozen, and `hard_freeze` stops dispute actions on frozen accounts
+    RejectedFrozen,
+    /// The transaction was known, but the action did not apply to it in
+    /// its current state (e.g. resolving a transaction that isn't disputed)
+    Ignored
+}
+
+/// How an account reacts to a broken internal invariant
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum InvariantPolicy {
+    /// Panic, stopping all processing
+    Panic,
+    /// Log the violation as a warning and skip the action. This is the default,
+    /// so that adversarial input can't bring down the process
+    #[default]
+    Warn,
+    /// Silently skip the action
+    Skip
+}
+
+/// Where interest earned on held funds is credited
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum InterestCredit {
+    /// Held interest is paid out to the available balance
+    #[default]
+    Available,
+    /// Held interest stays held alongside the funds that earned it
+    Held
 }
 
 impl Account {
@@ -30,116 +244,483 @@ impl Account {
             id, 
             available_balance: Zero::zero(),
             held_balance: Zero::zero(),
-            transactions: HashMap::new(),
-            is_frozen: false
+            manually_held: Zero::zero(),
+            transactions: BTreeMap::new(),
+            freeze_reason: None,
+            frozen_when_loaded: false,
+            dispute_count: 0,
+            currency: None,
+            freeze_after_disputes: None,
+            hard_freeze: false,
+            last_seq: None,
+            require_open: false,
+            is_open: false,
+            chargeback_requires_full_hold: true,
+            withdrawal_disputes: false,
+            strict_dispute_dedup: false,
+            applied_dispute_actions: HashSet::new(),
+            overdraft_limit: Zero::zero(),
+            withdrawal_fee: Zero::zero(),
+            normalize_on_dispute: None,
+            on_invariant_violation: InvariantPolicy::Warn,
+            trace_tx: None,
+            trace_log: Vec::new()
         }
     }
 
-    /// Register and apply a new transaction
-    pub fn register_transaction(&mut self, transaction: Transaction) {
-        if self.is_frozen {
+    /// Create a new account with zero transaction history, which
+    /// allows withdrawals to be disputed if `withdrawal_disputes` is set
+    pub fn with_withdrawal_disputes(id: u16, withdrawal_disputes: bool) -> Self {
+        Self { withdrawal_disputes, ..Self::new(id) }
+    }
+
+    /// Create a new account with zero transaction history, which allows
+    /// withdrawals to take the available balance down to `-overdraft_limit`
+    pub fn with_overdraft_limit(id: u16, overdraft_limit: Money) -> Self {
+        Self { overdraft_limit, ..Self::new(id) }
+    }
+
+    /// Create a new account with zero transaction history, which takes
+    /// `withdrawal_fee` from the available balance with each withdrawal
+    pub fn with_withdrawal_fee(id: u16, withdrawal_fee: Money) -> Self {
+        Self { withdrawal_fee, ..Self::new(id) }
+    }
+
+    /// Create a new account with zero transaction history, which applies each
+    /// kind of dispute action to a transaction at most once if `strict_dispute_dedup` is set
+    pub fn with_strict_dispute_dedup(id: u16, strict_dispute_dedup: bool) -> Self {
+        Self { strict_dispute_dedup, ..Self::new(id) }
+    }
+
+    /// Create a new account with zero transaction history, which is frozen
+    /// once `freeze_after_disputes` disputes have been opened on it
+    pub fn with_freeze_after_disputes(id: u16, freeze_after_disputes: Option<u32>) -> Self {
+        Self { freeze_after_disputes, ..Self::new(id) }
+    }
+
+    /// Mark the account as opened, allowing it to accept transactions
+    /// if it must be explicitly opened
+    pub fn open(&mut self) {
+        self.is_open = true;
+    }
+
+    /// Manually hold `amount` of the available balance, apart from any dispute.
+    /// Returns whether it was held, which it isn't if less than `amount` is available
+    pub fn hold_funds(&mut self, amount: Money) -> bool {
+        if self.is_hard_frozen() {
+            return false;
+        }
+        match self.available_balance.checked_sub(&amount) {
+            Some(available) => {
+                self.available_balance = available;
+                self.held_balance += &amount;
+                self.manually_held += amount;
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Make `amount` of the manually held funds available again. Returns whether it
+    /// was released, which it isn't if less than `amount` is manually held. Funds
+    /// held by disputes can only be released by resolving them
+    pub fn release_funds(&mut self, amount: Money) -> bool {
+        if self.is_hard_frozen() {
+            return false;
+        }
+        match self.manually_held.checked_sub(&amount) {
+            Some(manually_held) => {
+                self.manually_held = manually_held;
+                self.held_balance -= &amount;
+                self.available_balance += amount;
+                true
+            },
+            None => false
+        }
+    }
+
+    /// The account's net position for risk reporting: its balance less anything it owes.
+    /// Nothing is owed yet, so this is the available and held balances together
+    pub fn net_position(&self) -> Money {
+        &self.available_balance + &self.held_balance
+    }
+
+    /// Check the account's internal invariants without panicking: the held balance
+    /// must not be negative, and must be exactly what the open disputes and manual
+    /// holds have placed on hold. Describes the first broken invariant found
+    pub fn validate_invariants(&self) -> Result<(), String> {
+        if self.held_balance.is_negative() {
+            return Err(format!("Client {} has a negative held balance of {}", self.id, self.held_balance));
+        }
+        let mut expected_held = self.manually_held.clone();
+        for transaction in self.transactions.values().filter(|transaction| transaction.dispute_state == DisputeState::Disputed) {
+            expected_held += &transaction.held_amount;
+        }
+        if self.held_balance != expected_held {
+            return Err(format!("Client {} has a held balance of {}, but its disputes and manual holds total {}",
+                self.id, self.held_balance, expected_held));
+        }
+        Ok(())
+    }
+
+    /// Whether the account has been frozen against new deposits and withdrawals
+    pub fn is_frozen(&self) -> bool {
+        self.freeze_reason.is_some()
+    }
+
+    /// Whether the account is frozen against every change, not just deposits and withdrawals
+    pub fn is_hard_frozen(&self) -> bool {
+        self.hard_freeze && self.is_frozen()
+    }
+
+    /// Freeze the account for the given reason. If the account is already
+    /// frozen, the original reason is kept
+    pub fn freeze(&mut self, reason: FreezeReason) {
+        self.freeze_reason.get_or_insert(reason);
+    }
+
+    /// Register and apply a new transaction, reporting whether it was applied
+    /// or why it was rejected
+    pub fn register_transaction(&mut self, transaction: Transaction) -> TransactionOutcome {
+        let id = transaction.id;
+        if self.is_frozen() {
             // Do not process new transactions if the account is frozen.
-            // Disputes are still allowed.
-            return;
+            // Disputes are still allowed, unless the freeze is hard.
+            trace!(self, id, "rejected: account is frozen");
+            return TransactionOutcome::RejectedFrozen;
         }
-        if self.transactions.contains_key(&transaction.id) {
+        if self.require_open && !self.is_open {
+            // Do not process transactions before the account is opened
+            trace!(self, id, "rejected: account is not open");
+            return TransactionOutcome::RejectedNotOpen;
+        }
+        if self.transactions.contains_key(&id) {
             // Do not process transactions with duplicate IDs
-            return;
+            trace!(self, id, "rejected: duplicate of an existing transaction");
+            return TransactionOutcome::RejectedDuplicate;
+        }
+        trace!(self, id, "duplicate check passed");
+        if let (Some(currency), Some(account_currency)) = (&transaction.currency, &self.currency) {
+            if currency != account_currency {
+                // There is no conversion between currencies
+                trace!(self, id, "rejected: currency {} differs from the account's {}", currency, account_currency);
+                return TransactionOutcome::RejectedCurrency;
+            }
         }
 
         match transaction.transaction_type {
             TransactionType::Deposit => {
+                if self.currency.is_none() {
+                    self.currency = transaction.currency.clone();
+                }
                 self.available_balance += &transaction.amount;
-                self.transactions.insert(transaction.id, transaction);
+                trace!(self, id, "registered deposit of {}, available now {}", transaction.amount, self.available_balance);
+                self.transactions.insert(id, transaction);
+                TransactionOutcome::Applied
             },
             TransactionType::Withdrawal => {
-                if transaction.amount <= self.available_balance {
-                    self.available_balance -= &transaction.amount;
-                    self.transactions.insert(transaction.id, transaction);
+                // the fee is taken along with the withdrawal, so the available balance must cover both
+                let cost = &transaction.amount + &self.withdrawal_fee;
+                if cost <= &self.available_balance + &self.overdraft_limit {
+                    self.available_balance -= &cost;
+                    trace!(self, id, "registered withdrawal of {} with fee {}, available now {}",
+                        transaction.amount, self.withdrawal_fee, self.available_balance);
+                    self.transactions.insert(id, transaction);
+                    TransactionOutcome::Applied
+                } else {
+                    trace!(self, id, "rejected: withdrawal of {} with fee {} exceeds available {} plus overdraft {}",
+                        transaction.amount, self.withdrawal_fee, self.available_balance, self.overdraft_limit);
+                    TransactionOutcome::RejectedInsufficientFunds
                 }
             }
         }
     }
 
+    /// Credit interest to the account, at `available_rate` on the available balance and
+    /// `held_rate` on the held balance. Interest on the available balance is credited to
+    /// the available balance, while interest on the held balance is credited according
+    /// to `held_credit`. Each amount of interest is rounded to `decimals` decimal places.
+    /// Frozen accounts do not accrue interest.
+    pub fn accrue_interest(&mut self, available_rate: &BigDecimal, held_rate: &BigDecimal, held_credit: InterestCredit, decimals: i64) {
+        if self.is_frozen() {
+            return;
+        }
+        let available_interest = self.available_balance.times_rate(available_rate, decimals);
+        let held_interest = self.held_balance.times_rate(held_rate, decimals);
+        self.available_balance += available_interest;
+        match held_credit {
+            InterestCredit::Available => self.available_balance += held_interest,
+            InterestCredit::Held => self.held_balance += held_interest,
+        }
+    }
+
+    /// Credit interest to the account at `rate` on the available balance alone, as a
+    /// savings product pays it. This is `accrue_interest` with held funds earning nothing
+    pub fn apply_interest(&mut self, rate: &BigDecimal, decimals: i64) {
+        self.accrue_interest(rate, &Zero::zero(), InterestCredit::Available, decimals);
+    }
+
+    /// Handle a broken invariant while acting on a transaction according to
+    /// `on_invariant_violation`. Unless it panics, the caller skips the action
+    fn invariant_violated(&self, transaction_id: u32, message: &str) {
+        match self.on_invariant_violation {
+            InvariantPolicy::Panic => panic!("{}", message),
+            InvariantPolicy::Warn => log::warn!("Skipping action on client {} tx {}: {}", self.id, transaction_id, message),
+            InvariantPolicy::Skip => {}
+        }
+    }
+
+    /// Combine another account's state for the same client into this one, as when the
+    /// client's transactions were processed in separate batches. Balances are summed and
+    /// transaction histories combined (keeping this account's copy of any shared ID).
+    /// The merged account is frozen if either was.
+    pub fn merge(&mut self, other: Account) {
+        self.available_balance += other.available_balance;
+        self.held_balance += other.held_balance;
+        self.manually_held += other.manually_held;
+        for (id, transaction) in other.transactions {
+            self.transactions.entry(id).or_insert(transaction);
+        }
+        if let Some(reason) = other.freeze_reason {
+            self.freeze(reason);
+        }
+        self.applied_dispute_actions.extend(other.applied_dispute_actions);
+        self.dispute_count += other.dispute_count;
+        if self.currency.is_none() {
+            self.currency = other.currency;
+        }
+        self.last_seq = self.last_seq.max(other.last_seq);
+        self.is_open |= other.is_open;
+    }
+
+    /// Apply a transaction to a copy of this account, reporting what
+    /// would happen without changing this account's state
+    pub fn dry_apply(&self, transaction: Transaction) -> DryApplyReport {
+        let mut copy = self.clone();
+        let outcome = copy.register_transaction(transaction.clone());
+        DryApplyReport {
+            succeeded: outcome == TransactionOutcome::Applied,
+            transaction,
+            available_before: self.available_balance.clone(),
+            held_before: self.held_balance.clone(),
+            available_after: copy.available_balance,
+            held_after: copy.held_balance,
+        }
+    }
+
+    /// Apply a dispute, resolve, or chargeback to one of the account's transactions,
+    /// reporting whether it had any effect
+    pub fn apply_dispute_action(&mut self, action: &DisputeAction) -> DisputeOutcome {
+        if action.client_id != self.id {
+            return DisputeOutcome::ClientMismatch;
+        }
+        let dispute_state = self.transactions.get(&action.transaction_id).map(|transaction| transaction.dispute_state);
+        if dispute_state == Some(DisputeState::ChargedBack) {
+            trace!(self, action.transaction_id, "{:?} ignored: transaction was already charged back", action.action_type);
+            return DisputeOutcome::AlreadyChargedBack;
+        }
+        let key = (action.transaction_id, action.action_type);
+        if self.strict_dispute_dedup && self.applied_dispute_actions.contains(&key) {
+            trace!(self, action.transaction_id, "{:?} ignored: already applied once", action.action_type);
+            return DisputeOutcome::AlreadyApplied;
+        }
+        let outcome = self.apply_dispute(action.action_type, action.transaction_id);
+        if outcome == DisputeOutcome::Applied && self.strict_dispute_dedup {
+            self.applied_dispute_actions.insert(key);
+        }
+        outcome
+    }
+
     /// Indicate a transaction in dispute
     pub fn dispute_transaction(&mut self, transaction_id: u32) {
-        if let Some(transaction) = self.transactions.get_mut(&transaction_id) {
-            if transaction.dispute_state == DisputeState::Undisputed {
-                match transaction.transaction_type {
-                    TransactionType::Deposit => {
-                        // do not process if there are not enough available funds - this can happen
-                        // if a person deposits money, withdraws some of that money, then disputes
-                        // the original deposit
-                        if transaction.amount <= self.available_balance {
-                            self.available_balance -= &transaction.amount;
-                            self.held_balance += &transaction.amount;
-                            transaction.dispute_state = DisputeState::Disputed;
-                        }
-                    },
-                    TransactionType::Withdrawal => {
-                        // do not dispute a withdrawal - there's really nothing we can do when the
-                        // withdrawal has been processed, since the money is already gone
-                        // NOTE: If we gave the withdrawal a holding period, then we could allow a dispute
-                        // to cancel the withdrawal. This would also let us dispute deposits with not enough
-                        // funds remaining by canceling interfering withdrawals
-                    }
-                }
-            }
-        }
+        self.apply_dispute(DisputeActionType::Dispute, transaction_id);
     }
-    
+
     /// Cancel a dispute on a transaction
     pub fn resolve_disputed_transaction(&mut self, transaction_id: u32) {
-        if let Some(transaction) = self.transactions.get_mut(&transaction_id) {
-            if transaction.dispute_state == DisputeState::Disputed {
-                match transaction.transaction_type {
-                    TransactionType::Deposit => {
-                        if transaction.amount <= self.held_balance {
-                            self.held_balance -= &transaction.amount;
-                            self.available_balance += &transaction.amount;
-                            transaction.dispute_state = DisputeState::Undisputed;
-                        } else {
-                            // Because the held balance is always the exact sum of the deposit balances
-                            // of all transactions currently under dispute, it should never go below zero
-                            panic!("Held balance taken below zero - this should not happen");
-                        }
-                    },
-                    TransactionType::Withdrawal => {
-                        /* withdrawals can't be disputed, so do nothing */
-                    }
-                }
-            }
-        }
+        self.apply_dispute(DisputeActionType::Resolve, transaction_id);
+    }
+
+    /// Cancel part of a dispute on a transaction, making `amount` of its held funds
+    /// available again. The rest of the transaction stays in dispute, until all of it
+    /// has been resolved. Resolutions for more than is held are ignored.
+    pub fn resolve_partial_disputed_transaction(&mut self, transaction_id: u32, amount: Money) {
+        self.apply_dispute_amount(DisputeActionType::Resolve, transaction_id, Some(amount));
     }
 
-    /// Charge back a disputed transaction and freeze the account
+    /// Charge back a disputed transaction and freeze the account. Unless
+    /// `chargeback_requires_full_hold` is unset, the chargeback is ignored if
+    /// part of the dispute has already been resolved.
     pub fn chargeback_disputed_transaction(&mut self, transaction_id: u32) {
-        if let Some(transaction) = self.transactions.get_mut(&transaction_id) {
-            if transaction.dispute_state == DisputeState::Disputed {
-                match transaction.transaction_type {
-                    TransactionType::Deposit => {
-                        if transaction.amount <= self.held_balance {
-                            self.held_balance -= &transaction.amount;
-                            self.is_frozen = true;
-                            transaction.dispute_state = DisputeState::ChargedBack;
-                        } else {
-                            // Because the held balance is always the exact sum of the deposit balances
-                            // of all transactions currently under dispute, it should never go below zero
-                            panic!("Held balance taken below zero - this should not happen");
+        self.apply_dispute(DisputeActionType::Chargeback, transaction_id);
+    }
+
+    /// Charge back `amount` of a disputed transaction's held funds and freeze the
+    /// account. The rest of the transaction stays in dispute, to be resolved or
+    /// charged back later, and the transaction is only charged back once nothing
+    /// of it is held. Chargebacks for more than is held are ignored, as are any
+    /// after part of the dispute was resolved, unless `chargeback_requires_full_hold`
+    /// is unset.
+    pub fn chargeback_partial_disputed_transaction(&mut self, transaction_id: u32, amount: Money) {
+        self.apply_dispute_amount(DisputeActionType::Chargeback, transaction_id, Some(amount));
+    }
+
+    /// Move one of the account's transactions through the dispute state machine,
+    /// reporting whether the action was applied or why it wasn't:
+    ///
+    /// - A `Dispute` holds the funds of an undisputed transaction. A deposit can only
+    ///   be disputed while the available balance covers it, and a withdrawal only if
+    ///   `withdrawal_disputes` is set.
+    /// - A `Resolve` releases a disputed transaction's held funds, returning a
+    ///   deposit's funds to the available balance and leaving the transaction
+    ///   undisputed.
+    /// - A `Chargeback` removes a disputed transaction's held funds, returning a
+    ///   withdrawal's funds to the available balance, and freezes the account. The
+    ///   transaction is final once charged back.
+    ///
+    /// Unlike `apply_dispute_action`, this doesn't check the client or the
+    /// `strict_dispute_dedup` history.
+    pub fn apply_dispute(&mut self, action_type: DisputeActionType, transaction_id: u32) -> DisputeOutcome {
+        self.apply_dispute_amount(action_type, transaction_id, None)
+    }
+
+    /// Apply a dispute action as `apply_dispute` does, resolving or charging back
+    /// only `amount` of the held funds if it is given, or all of them if not.
+    /// The balances are rounded afterwards if `normalize_on_dispute` is set
+    fn apply_dispute_amount(&mut self, action_type: DisputeActionType, transaction_id: u32, amount: Option<Money>) -> DisputeOutcome {
+        let outcome = self.change_dispute_state(action_type, transaction_id, amount);
+        if let (DisputeOutcome::Applied, Some(decimals)) = (outcome, self.normalize_on_dispute) {
+            self.available_balance = Money::round(self.available_balance.as_decimal(), decimals);
+            self.held_balance = Money::round(self.held_balance.as_decimal(), decimals);
+        }
+        outcome
+    }
+
+    /// Move a transaction through the dispute state machine for `apply_dispute_amount`
+    fn change_dispute_state(&mut self, action_type: DisputeActionType, transaction_id: u32, amount: Option<Money>) -> DisputeOutcome {
+        let action_name = match action_type {
+            DisputeActionType::Dispute => "dispute",
+            DisputeActionType::Resolve => "resolve",
+            DisputeActionType::Chargeback => "chargeback",
+        };
+        if self.is_hard_frozen() {
+            trace!(self, transaction_id, "{} rejected: account is frozen", action_name);
+            return DisputeOutcome::RejectedFrozen;
+        }
+        let transaction = match self.transactions.get_mut(&transaction_id) {
+            Some(transaction) => transaction,
+            None => {
+                trace!(self, transaction_id, "{} ignored: transaction not found", action_name);
+                return DisputeOutcome::UnknownTransaction;
+            }
+        };
+        let expected_state = match action_type {
+            DisputeActionType::Dispute => DisputeState::Undisputed,
+            DisputeActionType::Resolve | DisputeActionType::Chargeback => DisputeState::Disputed,
+        };
+        if transaction.dispute_state != expected_state {
+            trace!(self, transaction_id, "{} ignored: transaction is {:?}", action_name, transaction.dispute_state);
+            return DisputeOutcome::Ignored;
+        }
+
+        if action_type == DisputeActionType::Dispute {
+            // any part already charged back can't be disputed again
+            let amount = &transaction.amount - &transaction.charged_back_amount;
+            match transaction.transaction_type {
+                TransactionType::Deposit => {
+                    // do not process if there are not enough available funds - this can happen
+                    // if a person deposits money, withdraws some of that money, then disputes
+                    // the original deposit
+                    match self.available_balance.checked_sub(&amount) {
+                        Some(available) => {
+                            self.available_balance = available;
+                            self.held_balance += &amount;
+                            trace!(self, transaction_id, "dispute applied: {} moved from available to held", amount);
+                        },
+                        None => {
+                            trace!(self, transaction_id, "dispute ignored: amount {} exceeds available {}",
+                                amount, self.available_balance);
+                            return DisputeOutcome::InsufficientFunds;
                         }
-                    },
-                    TransactionType::Withdrawal => {
-                        /* withdrawals can't be disputed, so do nothing */
                     }
+                },
+                // by default, do not dispute a withdrawal - there's really nothing we can do
+                // when the withdrawal has been processed, since the money is already gone.
+                // If withdrawal disputes are enabled, the withdrawn amount is held as owed back
+                // to the client until the dispute is resolved or charged back
+                TransactionType::Withdrawal if self.withdrawal_disputes => {
+                    self.held_balance += &amount;
+                    trace!(self, transaction_id, "dispute applied: withdrawn {} held", amount);
+                },
+                TransactionType::Withdrawal => {
+                    trace!(self, transaction_id, "dispute ignored: withdrawals cannot be disputed");
+                    return DisputeOutcome::WithdrawalsNotDisputable;
                 }
             }
-        }        
+            transaction.held_amount = amount;
+            transaction.dispute_state = DisputeState::Disputed;
+            self.dispute_count += 1;
+            if self.freeze_after_disputes.is_some_and(|limit| self.dispute_count >= limit) && !self.is_frozen() {
+                trace!(self, transaction_id, "account frozen after {} disputes", self.dispute_count);
+                self.freeze(FreezeReason::DisputeLimit { disputes: self.dispute_count });
+            }
+            return DisputeOutcome::Applied;
+        }
+
+        if action_type == DisputeActionType::Chargeback && self.chargeback_requires_full_hold
+            && &transaction.held_amount + &transaction.charged_back_amount != transaction.amount {
+            trace!(self, transaction_id, "chargeback ignored: only {} of {} is still held",
+                transaction.held_amount, transaction.amount);
+            return DisputeOutcome::Ignored;
+        }
+        let amount = amount.unwrap_or_else(|| transaction.held_amount.clone());
+        if amount > transaction.held_amount {
+            trace!(self, transaction_id, "{} ignored: {} is more than the {} held", action_name, amount, transaction.held_amount);
+            return DisputeOutcome::InsufficientHeld;
+        }
+        if amount > self.held_balance {
+            // Because the held balance is always the exact sum of the held amounts
+            // of all transactions currently under dispute, it should never go below zero
+            trace!(self, transaction_id, "{} failed: held balance {} is below {}", action_name, self.held_balance, amount);
+            self.invariant_violated(transaction_id, "Held balance taken below zero - this should not happen");
+            return DisputeOutcome::InsufficientHeld;
+        }
+
+        self.held_balance -= &amount;
+        transaction.held_amount -= &amount;
+        match (action_type, transaction.transaction_type) {
+            // a resolved deposit was legitimate, so its funds are available again
+            (DisputeActionType::Resolve, TransactionType::Deposit) => self.available_balance += &amount,
+            // a charged back withdrawal is returned to the client
+            (DisputeActionType::Chargeback, TransactionType::Withdrawal) => self.available_balance += &amount,
+            // a resolved withdrawal was legitimate, so nothing is owed back,
+            // and a charged back deposit is taken away entirely
+            _ => {}
+        }
+        if action_type == DisputeActionType::Resolve {
+            if transaction.held_amount.is_zero() {
+                transaction.dispute_state = DisputeState::Undisputed;
+            }
+            trace!(self, transaction_id, "resolve applied: {} released, {} still held", amount, transaction.held_amount);
+        } else {
+            transaction.charged_back_amount += &amount;
+            if transaction.held_amount.is_zero() {
+                transaction.dispute_state = DisputeState::ChargedBack;
+                trace!(self, transaction_id, "chargeback applied: {} removed from held, account frozen", amount);
+            } else {
+                trace!(self, transaction_id, "chargeback applied: {} removed from held, {} still held, account frozen",
+                    amount, transaction.held_amount);
+            }
+            self.freeze(FreezeReason::Chargeback { tx: transaction_id });
+        }
+        DisputeOutcome::Applied
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::csv_rows::DEFAULT_DECIMALS;
 
     #[test]
     fn creates_with_zero_balance() {
@@ -148,227 +729,388 @@ mod tests {
         assert!(&(account.available_balance).eq(&Zero::zero()));
         assert!(&(account.held_balance).eq(&Zero::zero()));
         assert!(&account.transactions.is_empty());
-        assert!(!account.is_frozen);
+        assert!(!account.is_frozen());
     }
 
     #[test]
     fn records_deposit() {
         let mut account = Account::new(1);
-        account.register_transaction(Transaction
-            {
-                id: 1,
-                client_id: 1,
-                amount: 10.into(),
-                transaction_type: TransactionType::Deposit,
-                dispute_state: DisputeState::Undisputed,
-            });
+        assert_eq!(account.register_transaction(Transaction::new(1, 1, 10.into(), TransactionType::Deposit)), TransactionOutcome::Applied);
 
         assert_eq!(account.id, 1);
         assert!(&(account.available_balance).eq(&10.into()));
         assert!(&(account.held_balance).eq(&Zero::zero()));
-        assert_eq!(account.transactions.len(), 1 as usize);
-        assert!(!account.is_frozen);
+        assert_eq!(account.transactions.len(), 1);
+        assert!(!account.is_frozen());
     }
 
     #[test]
     fn records_withdrawal() {
         let mut account = Account::new(1);
-        account.register_transaction(Transaction
-            {
-                id: 1,
-                client_id: 1,
-                amount: 10.into(),
-                transaction_type: TransactionType::Deposit,
-                dispute_state: DisputeState::Undisputed,
-            });
-        account.register_transaction(Transaction
-            {
-                id: 2,
-                client_id: 1,
-                amount: 8.into(),
-                transaction_type: TransactionType::Withdrawal,
-                dispute_state: DisputeState::Undisputed,
-            });
+        assert_eq!(account.register_transaction(Transaction::new(1, 1, 10.into(), TransactionType::Deposit)), TransactionOutcome::Applied);
+        assert_eq!(account.register_transaction(Transaction::new(2, 1, 8.into(), TransactionType::Withdrawal)), TransactionOutcome::Applied);
 
         assert_eq!(account.id, 1);
         assert!(&(account.available_balance).eq(&2.into()));
         assert!(&(account.held_balance).eq(&Zero::zero()));
-        assert_eq!(account.transactions.len(), 2 as usize);
-        assert!(!account.is_frozen);
+        assert_eq!(account.transactions.len(), 2);
+        assert!(!account.is_frozen());
     }
 
     #[test]
     fn records_multiple_transactions() {
         let mut account = Account::new(1);
-        account.register_transaction(Transaction
-            {
-                id: 1,
-                client_id: 1,
-                amount: 10.into(),
-                transaction_type: TransactionType::Deposit,
-                dispute_state: DisputeState::Undisputed,
-            });
-        account.register_transaction(Transaction
-            {
-                id: 3,
-                client_id: 1,
-                amount: 15.into(),
-                transaction_type: TransactionType::Deposit,
-                dispute_state: DisputeState::Undisputed,
-            });
-        account.register_transaction(Transaction
-            {
-                id: 2,
-                client_id: 1,
-                amount: 4.into(),
-                transaction_type: TransactionType::Withdrawal,
-                dispute_state: DisputeState::Undisputed,
-            });
+        assert_eq!(account.register_transaction(Transaction::new(1, 1, 10.into(), TransactionType::Deposit)), TransactionOutcome::Applied);
+        assert_eq!(account.register_transaction(Transaction::new(3, 1, 15.into(), TransactionType::Deposit)), TransactionOutcome::Applied);
+        assert_eq!(account.register_transaction(Transaction::new(2, 1, 4.into(), TransactionType::Withdrawal)), TransactionOutcome::Applied);
 
         assert_eq!(account.id, 1);
         assert!(&(account.available_balance).eq(&21.into()));
         assert!(&(account.held_balance).eq(&Zero::zero()));
-        assert_eq!(account.transactions.len(), 3 as usize);
-        assert!(!account.is_frozen);
+        assert_eq!(account.transactions.len(), 3);
+        assert!(!account.is_frozen());
     }
 
     #[test]
     fn ignores_duplicate_transaction_numbers() {
         let mut account = Account::new(1);
-        account.register_transaction(Transaction
-            {
-                id: 1,
-                client_id: 1,
-                amount: 12.into(),
-                transaction_type: TransactionType::Deposit,
-                dispute_state: DisputeState::Undisputed,
-            });
-        account.register_transaction(Transaction
-            {
-                id: 1,
-                client_id: 1,
-                amount: 10.into(),
-                transaction_type: TransactionType::Deposit,
-                dispute_state: DisputeState::Undisputed,
-            });
+        assert_eq!(account.register_transaction(Transaction::new(1, 1, 12.into(), TransactionType::Deposit)), TransactionOutcome::Applied);
+        assert_eq!(account.register_transaction(Transaction::new(1, 1, 10.into(), TransactionType::Deposit)), TransactionOutcome::RejectedDuplicate);
 
         assert_eq!(account.id, 1);
         assert!(&(account.available_balance).eq(&12.into()));
         assert!(&(account.held_balance).eq(&Zero::zero()));
-        assert_eq!(account.transactions.len(), 1 as usize);
-        assert!(!account.is_frozen);
+        assert_eq!(account.transactions.len(), 1);
+        assert!(!account.is_frozen());
+    }
+
+    #[test]
+    fn rejects_withdrawal_over_available_balance() {
+        let mut account = Account::new(1);
+        assert_eq!(account.register_transaction(Transaction::new(1, 1, 10.into(), TransactionType::Withdrawal)), TransactionOutcome::RejectedInsufficientFunds);
+
+        assert!(account.available_balance.is_zero());
+        assert!(account.transactions.is_empty());
+    }
+
+    #[test]
+    fn withdrawal_fee_must_be_covered_too() {
+        let withdrawal = Transaction::new(1, 1, 90.into(), TransactionType::Withdrawal);
+        let mut account = Account { available_balance: 92.into(), ..Account::with_withdrawal_fee(1, 5.into()) };
+        assert_eq!(account.register_transaction(withdrawal.clone()), TransactionOutcome::RejectedInsufficientFunds);
+        assert!(&(account.available_balance).eq(&92.into()));
+
+        let mut account = Account { available_balance: 95.into(), ..Account::with_withdrawal_fee(1, 5.into()) };
+        assert_eq!(account.register_transaction(withdrawal), TransactionOutcome::Applied);
+        assert!(account.available_balance.is_zero());
     }
 
     #[test]
     fn records_dispute() {
         let mut account = Account::new(1);
-        account.register_transaction(Transaction
-            {
-                id: 1,
-                client_id: 1,
-                amount: 10.into(),
-                transaction_type: TransactionType::Deposit,
-                dispute_state: DisputeState::Undisputed,
-            });
+        account.register_transaction(Transaction::new(1, 1, 10.into(), TransactionType::Deposit));
         account.dispute_transaction(1);
 
         assert_eq!(account.id, 1);
         assert!(&(account.available_balance).eq(&0.into()));
         assert!(&(account.held_balance).eq(&10.into()));
-        assert_eq!(account.transactions.len(), 1 as usize);
-        assert!(!account.is_frozen);
+        assert_eq!(account.transactions.len(), 1);
+        assert!(!account.is_frozen());
+    }
+
+    /// An account with withdrawal disputes enabled, after depositing 10 and withdrawing 4
+    fn account_with_disputed_withdrawal() -> Account {
+        let mut account = Account::with_withdrawal_disputes(1, true);
+        account.register_transaction(Transaction::new(1, 1, 10.into(), TransactionType::Deposit));
+        account.register_transaction(Transaction::new(2, 1, 4.into(), TransactionType::Withdrawal));
+        account.dispute_transaction(2);
+        account
+    }
+
+    #[test]
+    fn ignores_withdrawal_dispute_by_default() {
+        let mut account = Account::new(1);
+        account.register_transaction(Transaction::new(1, 1, 10.into(), TransactionType::Deposit));
+        account.register_transaction(Transaction::new(2, 1, 4.into(), TransactionType::Withdrawal));
+        assert_eq!(account.apply_dispute(DisputeActionType::Dispute, 2), DisputeOutcome::WithdrawalsNotDisputable);
+
+        assert_eq!(account.available_balance, 6.into());
+        assert!(account.held_balance.is_zero());
+        assert_eq!(account.transactions[&2].dispute_state, DisputeState::Undisputed);
+    }
+
+    #[test]
+    fn withdrawal_dispute_holds_withdrawn_amount() {
+        let account = account_with_disputed_withdrawal();
+
+        assert_eq!(account.available_balance, 6.into());
+        assert_eq!(account.held_balance, 4.into());
+        assert_eq!(account.transactions[&2].dispute_state, DisputeState::Disputed);
+        assert!(!account.is_frozen());
+    }
+
+    #[test]
+    fn withdrawal_dispute_resolution_cancels_hold() {
+        let mut account = account_with_disputed_withdrawal();
+        account.resolve_disputed_transaction(2);
+
+        assert_eq!(account.available_balance, 6.into());
+        assert!(account.held_balance.is_zero());
+        assert_eq!(account.transactions[&2].dispute_state, DisputeState::Undisputed);
+        assert!(!account.is_frozen());
+    }
+
+    #[test]
+    fn withdrawal_chargeback_returns_funds_and_freezes() {
+        let mut account = account_with_disputed_withdrawal();
+        account.chargeback_disputed_transaction(2);
+
+        assert_eq!(account.available_balance, 10.into());
+        assert!(account.held_balance.is_zero());
+        assert_eq!(account.transactions[&2].dispute_state, DisputeState::ChargedBack);
+        assert_eq!(account.freeze_reason, Some(FreezeReason::Chargeback { tx: 2 }));
+    }
+
+    #[test]
+    fn withdrawal_may_dip_into_overdraft() {
+        let mut account = Account::with_overdraft_limit(1, 5.into());
+        let transaction = |id, amount: i32, transaction_type| Transaction::new(id, 1, amount.into(), transaction_type);
+        account.register_transaction(transaction(1, 10, TransactionType::Deposit));
+        assert_eq!(account.register_transaction(transaction(2, 13, TransactionType::Withdrawal)), TransactionOutcome::Applied);
+        assert_eq!(account.available_balance, (-3).into());
+        assert_eq!(account.register_transaction(transaction(3, 3, TransactionType::Withdrawal)),
+            TransactionOutcome::RejectedInsufficientFunds);
+        assert_eq!(account.register_transaction(transaction(4, 2, TransactionType::Withdrawal)), TransactionOutcome::Applied);
+        assert_eq!(account.available_balance, (-5).into());
+
+        // The deposit's funds are gone, so there is nothing left to hold
+        account.dispute_transaction(1);
+        assert_eq!(account.available_balance, (-5).into());
+        assert!(account.held_balance.is_zero());
+        assert_eq!(account.transactions[&1].dispute_state, DisputeState::Undisputed);
+    }
+
+    #[test]
+    fn traces_decisions_about_one_transaction() {
+        let mut account = Account { trace_tx: Some(1), ..Account::new(1) };
+        for id in [1, 2] {
+            account.register_transaction(Transaction::new(id, 1, 10.into(), TransactionType::Deposit));
+        }
+        account.dispute_transaction(2);
+        account.resolve_disputed_transaction(1);
+        account.dispute_transaction(1);
+        account.dispute_transaction(1);
+        account.chargeback_disputed_transaction(1);
+
+        assert_eq!(account.trace_log, vec![
+            "duplicate check passed",
+            "registered deposit of 10.0000, available now 10.0000",
+            "resolve ignored: transaction is Undisputed",
+            "dispute applied: 10.0000 moved from available to held",
+            "dispute ignored: transaction is Disputed",
+            "chargeback applied: 10.0000 removed from held, account frozen",
+        ]);
+    }
+
+    #[test]
+    fn apply_dispute_action_routes_each_action_type() {
+        let mut account = Account::new(1);
+        account.register_transaction(Transaction::new(1, 1, 10.into(), TransactionType::Deposit));
+        let action = |action_type| DisputeAction { action_type, client_id: 1, transaction_id: 1 };
+
+        assert_eq!(account.apply_dispute_action(&action(DisputeActionType::Dispute)), DisputeOutcome::Applied);
+        assert_eq!(account.held_balance, 10.into());
+        assert_eq!(account.apply_dispute_action(&action(DisputeActionType::Resolve)), DisputeOutcome::Applied);
+        assert_eq!(account.available_balance, 10.into());
+        assert_eq!(account.apply_dispute_action(&action(DisputeActionType::Chargeback)), DisputeOutcome::Ignored);
+        assert_eq!(account.apply_dispute_action(&action(DisputeActionType::Dispute)), DisputeOutcome::Applied);
+        assert_eq!(account.apply_dispute_action(&action(DisputeActionType::Chargeback)), DisputeOutcome::Applied);
+        assert!(account.is_frozen());
+        assert!(account.held_balance.is_zero());
+    }
+
+    #[test]
+    fn charged_back_transaction_ignores_further_dispute_actions() {
+        let mut account = Account::new(1);
+        account.register_transaction(Transaction::new(1, 1, 10.into(), TransactionType::Deposit));
+        account.register_transaction(Transaction::new(2, 1, 5.into(), TransactionType::Deposit));
+        let action = |action_type| DisputeAction { action_type, client_id: 1, transaction_id: 1 };
+        account.apply_dispute_action(&action(DisputeActionType::Dispute));
+        assert_eq!(account.apply_dispute_action(&action(DisputeActionType::Chargeback)), DisputeOutcome::Applied);
+
+        for action_type in [DisputeActionType::Dispute, DisputeActionType::Resolve, DisputeActionType::Chargeback] {
+            assert_eq!(account.apply_dispute_action(&action(action_type)), DisputeOutcome::AlreadyChargedBack);
+            assert_eq!(account.available_balance, 5.into());
+            assert!(account.held_balance.is_zero());
+            assert_eq!(account.transactions[&1].dispute_state, DisputeState::ChargedBack);
+        }
+    }
+
+    #[test]
+    fn apply_dispute_action_checks_client_and_transaction() {
+        let mut account = Account::new(1);
+        let wrong_client = DisputeAction { action_type: DisputeActionType::Dispute, client_id: 2, transaction_id: 1 };
+        let unknown_tx = DisputeAction { client_id: 1, ..wrong_client };
+        assert_eq!(account.apply_dispute_action(&wrong_client), DisputeOutcome::ClientMismatch);
+        assert_eq!(account.apply_dispute_action(&unknown_tx), DisputeOutcome::UnknownTransaction);
+    }
+
+    /// A disputed deposit of 10 whose held balance has been corrupted to 5
+    fn account_with_corrupted_hold(policy: InvariantPolicy) -> Account {
+        let mut account = Account { on_invariant_violation: policy, ..Account::new(1) };
+        account.register_transaction(Transaction::new(1, 1, 10.into(), TransactionType::Deposit));
+        account.dispute_transaction(1);
+        account.held_balance = 5.into();
+        account
+    }
+
+    #[test]
+    fn validates_invariants() {
+        let mut account = account_with_corrupted_hold(InvariantPolicy::default());
+        assert_eq!(account.validate_invariants(),
+            Err("Client 1 has a held balance of 5.0000, but its disputes and manual holds total 10.0000".to_string()));
+
+        account.held_balance = 10.into();
+        assert_eq!(account.validate_invariants(), Ok(()));
+        account.available_balance = 5.into();
+        assert!(account.hold_funds(2.into()));
+        assert_eq!(account.validate_invariants(), Ok(()));
+
+        account.held_balance -= &20.into();
+        assert_eq!(account.validate_invariants(), Err("Client 1 has a negative held balance of -8.0000".to_string()));
+    }
+
+    #[test]
+    fn hard_freeze_rejects_dispute_actions() {
+        let mut account = Account { hard_freeze: true, ..Account::new(1) };
+        for id in [1, 2] {
+            account.register_transaction(Transaction::new(id, 1, 10.into(), TransactionType::Deposit));
+        }
+        assert_eq!(account.apply_dispute(DisputeActionType::Dispute, 1), DisputeOutcome::Applied);
+        assert_eq!(account.apply_dispute(DisputeActionType::Dispute, 2), DisputeOutcome::Applied);
+        assert_eq!(account.apply_dispute(DisputeActionType::Chargeback, 1), DisputeOutcome::Applied);
+        assert!(account.is_hard_frozen());
+
+        for action_type in [DisputeActionType::Resolve, DisputeActionType::Chargeback, DisputeActionType::Dispute] {
+            assert_eq!(account.apply_dispute(action_type, 2), DisputeOutcome::RejectedFrozen);
+        }
+        assert!(!account.release_funds(1.into()));
+        assert_eq!(account.transactions[&2].dispute_state, DisputeState::Disputed);
+        assert_eq!(account.available_balance, 0.into());
+        assert_eq!(account.held_balance, 10.into());
+
+        // A soft freeze still lets the dispute be resolved
+        account.hard_freeze = false;
+        assert_eq!(account.apply_dispute(DisputeActionType::Resolve, 2), DisputeOutcome::Applied);
+        assert_eq!(account.available_balance, 10.into());
+    }
+
+    #[test]
+    fn invariant_violation_is_survived_by_default() {
+        let mut account = account_with_corrupted_hold(InvariantPolicy::default());
+        account.resolve_disputed_transaction(1);
+        assert_eq!(account.held_balance, 5.into());
+        assert!(account.available_balance.is_zero());
+        assert_eq!(account.transactions[&1].dispute_state, DisputeState::Disputed);
+        assert_eq!(account.transactions[&1].held_amount, 10.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "Held balance taken below zero")]
+    fn invariant_violation_panics_when_configured() {
+        let mut account = account_with_corrupted_hold(InvariantPolicy::Panic);
+        account.resolve_disputed_transaction(1);
+    }
+
+    #[test]
+    fn invariant_violation_warns_and_skips() {
+        let mut account = account_with_corrupted_hold(InvariantPolicy::Warn);
+        account.chargeback_disputed_transaction(1);
+        assert_eq!(account.held_balance, 5.into());
+        assert_eq!(account.transactions[&1].dispute_state, DisputeState::Disputed);
+        assert!(!account.is_frozen());
+    }
+
+    #[test]
+    fn invariant_violation_skips_silently() {
+        let mut account = account_with_corrupted_hold(InvariantPolicy::Skip);
+        account.resolve_disputed_transaction(1);
+        assert_eq!(account.held_balance, 5.into());
+        assert!(account.available_balance.is_zero());
+        assert_eq!(account.transactions[&1].dispute_state, DisputeState::Disputed);
     }
 
     #[test]
     fn records_dispute_resolution() {
         let mut account = Account::new(1);
-        account.register_transaction(Transaction
-            {
-                id: 1,
-                client_id: 1,
-                amount: 10.into(),
-                transaction_type: TransactionType::Deposit,
-                dispute_state: DisputeState::Undisputed,
-            });
+        account.register_transaction(Transaction::new(1, 1, 10.into(), TransactionType::Deposit));
         account.dispute_transaction(1);
         account.resolve_disputed_transaction(1);
 
         assert_eq!(account.id, 1);
         assert!(&(account.available_balance).eq(&10.into()));
         assert!(&(account.held_balance).eq(&0.into()));
-        assert_eq!(account.transactions.len(), 1 as usize);
-        assert!(!account.is_frozen);
+        assert_eq!(account.transactions.len(), 1);
+        assert!(!account.is_frozen());
+    }
+
+    #[test]
+    fn strict_dispute_dedup_applies_each_action_once() {
+        let dispute = DisputeAction { action_type: DisputeActionType::Dispute, client_id: 1, transaction_id: 1 };
+        let resolve = DisputeAction { action_type: DisputeActionType::Resolve, ..dispute };
+        for strict in [false, true] {
+            let mut account = Account::with_strict_dispute_dedup(1, strict);
+            account.register_transaction(Transaction::new(1, 1, 10.into(), TransactionType::Deposit));
+            assert_eq!(account.apply_dispute_action(&dispute), DisputeOutcome::Applied);
+            assert_eq!(account.apply_dispute_action(&resolve), DisputeOutcome::Applied);
+
+            // A re-sent dispute only applies again without strict dedup
+            let outcome = account.apply_dispute_action(&dispute);
+            if strict {
+                assert_eq!(outcome, DisputeOutcome::AlreadyApplied);
+                assert!(&(account.available_balance).eq(&10.into()));
+                assert!(&(account.held_balance).eq(&0.into()));
+            } else {
+                assert_eq!(outcome, DisputeOutcome::Applied);
+                assert!(&(account.held_balance).eq(&10.into()));
+            }
+        }
     }
 
     #[test]
     fn records_dispute_chargeback() {
         let mut account = Account::new(1);
-        account.register_transaction(Transaction
-            {
-                id: 1,
-                client_id: 1,
-                amount: 10.into(),
-                transaction_type: TransactionType::Deposit,
-                dispute_state: DisputeState::Undisputed,
-            });
+        account.register_transaction(Transaction::new(1, 1, 10.into(), TransactionType::Deposit));
         account.dispute_transaction(1);
         account.chargeback_disputed_transaction(1);
 
         assert_eq!(account.id, 1);
         assert!(&(account.available_balance).eq(&0.into()));
         assert!(&(account.held_balance).eq(&0.into()));
-        assert_eq!(account.transactions.len(), 1 as usize);
-        assert!(account.is_frozen);
+        assert_eq!(account.transactions.len(), 1);
+        assert!(account.is_frozen());
     }
 
     #[test]
     fn disallows_further_transactions_after_chargeback() {
         let mut account = Account::new(1);
-        account.register_transaction(Transaction
-            {
-                id: 1,
-                client_id: 1,
-                amount: 10.into(),
-                transaction_type: TransactionType::Deposit,
-                dispute_state: DisputeState::Undisputed,
-            });
+        assert_eq!(account.register_transaction(Transaction::new(1, 1, 10.into(), TransactionType::Deposit)), TransactionOutcome::Applied);
         account.dispute_transaction(1);
         account.chargeback_disputed_transaction(1);
-        account.register_transaction(Transaction
-            {
-                id: 2,
-                client_id: 1,
-                amount: 15.into(),
-                transaction_type: TransactionType::Deposit,
-                dispute_state: DisputeState::Undisputed,
-            });
+        assert_eq!(account.register_transaction(Transaction::new(2, 1, 15.into(), TransactionType::Deposit)), TransactionOutcome::RejectedFrozen);
 
         assert_eq!(account.id, 1);
         assert!(&(account.available_balance).eq(&0.into()));
         assert!(&(account.held_balance).eq(&0.into()));
-        assert_eq!(account.transactions.len(), 1 as usize);
-        assert!(account.is_frozen);
+        assert_eq!(account.transactions.len(), 1);
+        assert!(account.is_frozen());
     }
 
     #[test]
     fn allows_further_disputes_after_chargeback() {
         let mut account = Account::new(1);
-        account.register_transaction(Transaction
-            {
-                id: 1,
-                client_id: 1,
-                amount: 10.into(),
-                transaction_type: TransactionType::Deposit,
-                dispute_state: DisputeState::Undisputed,
-            });
-        account.register_transaction(Transaction
-            {
-                id: 2,
-                client_id: 1,
-                amount: 15.into(),
-                transaction_type: TransactionType::Deposit,
-                dispute_state: DisputeState::Undisputed,
-            });
+        account.register_transaction(Transaction::new(1, 1, 10.into(), TransactionType::Deposit));
+        account.register_transaction(Transaction::new(2, 1, 15.into(), TransactionType::Deposit));
         account.dispute_transaction(1);
         account.chargeback_disputed_transaction(1);
         account.dispute_transaction(2);
@@ -376,61 +1118,40 @@ mod tests {
         assert_eq!(account.id, 1);
         assert!(&(account.available_balance).eq(&0.into()));
         assert!(&(account.held_balance).eq(&15.into()));
-        assert_eq!(account.transactions.len(), 2 as usize);
-        assert!(account.is_frozen);
+        assert_eq!(account.transactions.len(), 2);
+        assert!(account.is_frozen());
     }
 
     #[test]
     fn ignores_resolve_on_undisputed() {
         let mut account = Account::new(1);
-        account.register_transaction(Transaction
-            {
-                id: 1,
-                client_id: 1,
-                amount: 10.into(),
-                transaction_type: TransactionType::Deposit,
-                dispute_state: DisputeState::Undisputed,
-            });
+        account.register_transaction(Transaction::new(1, 1, 10.into(), TransactionType::Deposit));
         account.resolve_disputed_transaction(1);
 
         assert_eq!(account.id, 1);
         assert!(&(account.available_balance).eq(&10.into()));
         assert!(&(account.held_balance).eq(&0.into()));
-        assert_eq!(account.transactions.len(), 1 as usize);
-        assert!(!account.is_frozen);
+        assert_eq!(account.transactions.len(), 1);
+        assert!(!account.is_frozen());
     }
 
     #[test]
     fn ignores_chargeback_on_undisputed() {
         let mut account = Account::new(1);
-        account.register_transaction(Transaction
-            {
-                id: 1,
-                client_id: 1,
-                amount: 10.into(),
-                transaction_type: TransactionType::Deposit,
-                dispute_state: DisputeState::Undisputed,
-            });
+        account.register_transaction(Transaction::new(1, 1, 10.into(), TransactionType::Deposit));
         account.chargeback_disputed_transaction(1);
 
         assert_eq!(account.id, 1);
         assert!(&(account.available_balance).eq(&10.into()));
         assert!(&(account.held_balance).eq(&0.into()));
-        assert_eq!(account.transactions.len(), 1 as usize);
-        assert!(!account.is_frozen);
+        assert_eq!(account.transactions.len(), 1);
+        assert!(!account.is_frozen());
     }
 
     #[test]
     fn ignores_disputes_on_unknown_transaction_numbers() {
         let mut account = Account::new(1);
-        account.register_transaction(Transaction
-            {
-                id: 1,
-                client_id: 1,
-                amount: 10.into(),
-                transaction_type: TransactionType::Deposit,
-                dispute_state: DisputeState::Undisputed,
-            });
+        account.register_transaction(Transaction::new(1, 1, 10.into(), TransactionType::Deposit));
         account.dispute_transaction(2);
         account.resolve_disputed_transaction(2);
         account.chargeback_disputed_transaction(2);
@@ -438,7 +1159,392 @@ mod tests {
         assert_eq!(account.id, 1);
         assert!(&(account.available_balance).eq(&10.into()));
         assert!(&(account.held_balance).eq(&Zero::zero()));
-        assert_eq!(account.transactions.len(), 1 as usize);
-        assert!(!account.is_frozen);
+        assert_eq!(account.transactions.len(), 1);
+        assert!(!account.is_frozen());
+    }
+
+    #[test]
+    fn dry_apply_reports_rejected_withdrawal_without_mutating() {
+        let mut account = Account::new(1);
+        account.register_transaction(Transaction::new(1, 1, 10.into(), TransactionType::Deposit));
+        let report = account.dry_apply(Transaction::new(2, 1, 15.into(), TransactionType::Withdrawal));
+
+        assert!(!report.succeeded);
+        assert_eq!(report.available_before, 10.into());
+        assert_eq!(report.available_after, 10.into());
+        assert!(&(account.available_balance).eq(&10.into()));
+        assert_eq!(account.transactions.len(), 1);
+    }
+
+    #[test]
+    fn dry_apply_reports_successful_deposit_without_mutating() {
+        let account = Account::new(1);
+        let report = account.dry_apply(Transaction::new(1, 1, 10.into(), TransactionType::Deposit));
+
+        assert!(report.succeeded);
+        assert_eq!(report.available_before, 0.into());
+        assert_eq!(report.available_after, 10.into());
+        assert!(&(account.available_balance).eq(&Zero::zero()));
+        assert!(account.transactions.is_empty());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn chargeback_records_freeze_reason() {
+        let mut account = Account::new(1);
+        account.register_transaction(Transaction::new(7, 1, 10.into(), TransactionType::Deposit));
+        account.dispute_transaction(7);
+        account.chargeback_disputed_transaction(7);
+
+        assert!(account.is_frozen());
+        assert_eq!(account.freeze_reason, Some(FreezeReason::Chargeback { tx: 7 }));
+    }
+
+    #[test]
+    fn later_chargeback_keeps_original_freeze_reason() {
+        let mut account = Account::new(1);
+        for id in [1, 2] {
+            account.register_transaction(Transaction::new(id, 1, 10.into(), TransactionType::Deposit));
+            account.dispute_transaction(id);
+        }
+        account.chargeback_disputed_transaction(1);
+        account.chargeback_disputed_transaction(2);
+
+        assert_eq!(account.freeze_reason, Some(FreezeReason::Chargeback { tx: 1 }));
+    }
+
+    #[test]
+    fn requires_open_before_transactions() {
+        let mut account = Account { require_open: true, ..Account::new(1) };
+        assert_eq!(account.register_transaction(Transaction::new(1, 1, 10.into(), TransactionType::Deposit)), TransactionOutcome::RejectedNotOpen);
+        assert!(&(account.available_balance).eq(&Zero::zero()));
+        assert!(account.transactions.is_empty());
+
+        account.open();
+        assert_eq!(account.register_transaction(Transaction::new(2, 1, 10.into(), TransactionType::Deposit)), TransactionOutcome::Applied);
+        assert!(&(account.available_balance).eq(&10.into()));
+        assert_eq!(account.transactions.len(), 1);
+    }
+
+    #[test]
+    fn accrues_interest_separately_on_held_funds() {
+        let mut account = Account::new(1);
+        for (id, amount) in [(1, 1000), (2, 200)] {
+            account.register_transaction(Transaction::new(id, 1, amount.into(), TransactionType::Deposit));
+        }
+        account.dispute_transaction(2);
+
+        let available_rate: BigDecimal = "0.015".parse().unwrap();
+        let held_rate: BigDecimal = "0.05".parse().unwrap();
+        account.accrue_interest(&available_rate, &held_rate, InterestCredit::Available, DEFAULT_DECIMALS);
+        assert!(&(account.available_balance).eq(&1025.into()));
+        assert!(&(account.held_balance).eq(&200.into()));
+
+        let mut account = Account { available_balance: 1000.into(), held_balance: 200.into(), ..Account::new(1) };
+        account.accrue_interest(&available_rate, &held_rate, InterestCredit::Held, DEFAULT_DECIMALS);
+        assert!(&(account.available_balance).eq(&1015.into()));
+        assert!(&(account.held_balance).eq(&210.into()));
+    }
+
+    #[test]
+    fn applies_interest_to_available_balance_only() {
+        let mut account = Account { available_balance: 1000.into(), held_balance: 200.into(), ..Account::new(1) };
+        account.apply_interest(&"0.015".parse().unwrap(), DEFAULT_DECIMALS);
+        assert!(&(account.available_balance).eq(&1015.into()));
+        assert!(&(account.held_balance).eq(&200.into()));
+
+        account.freeze(FreezeReason::ManualFreeze);
+        account.apply_interest(&"0.015".parse().unwrap(), DEFAULT_DECIMALS);
+        assert!(&(account.available_balance).eq(&1015.into()));
+    }
+
+    #[test]
+    fn frozen_account_does_not_accrue_interest() {
+        let mut account = Account { available_balance: 1000.into(), ..Account::new(1) };
+        account.freeze(FreezeReason::ManualFreeze);
+        account.accrue_interest(&"0.015".parse().unwrap(), &Zero::zero(), InterestCredit::Available, DEFAULT_DECIMALS);
+        assert!(&(account.available_balance).eq(&1000.into()));
+    }
+
+    #[test]
+    fn manual_hold_and_release_preserve_total() {
+        let mut account = Account { available_balance: 10.into(), ..Account::new(1) };
+        assert!(account.hold_funds(4.into()));
+        assert!(&(account.available_balance).eq(&6.into()));
+        assert!(&(account.held_balance).eq(&4.into()));
+        assert!(&(&account.available_balance + &account.held_balance).eq(&10.into()));
+
+        assert!(account.release_funds(3.into()));
+        assert!(&(account.available_balance).eq(&9.into()));
+        assert!(&(account.held_balance).eq(&1.into()));
+        assert!(&(&account.available_balance + &account.held_balance).eq(&10.into()));
+    }
+
+    #[test]
+    fn cannot_hold_more_than_available_or_release_more_than_held() {
+        let mut account = Account { available_balance: 10.into(), ..Account::new(1) };
+        assert!(!account.hold_funds(11.into()));
+        assert!(&(account.available_balance).eq(&10.into()));
+        assert!(&(account.held_balance).eq(&Zero::zero()));
+
+        // Funds held by a dispute aren't manually held, so can't be released
+        account.register_transaction(Transaction::new(1, 1, 5.into(), TransactionType::Deposit));
+        account.dispute_transaction(1);
+        assert!(account.hold_funds(2.into()));
+        assert!(!account.release_funds(3.into()));
+        assert!(&(account.held_balance).eq(&7.into()));
+        assert!(&(account.available_balance).eq(&8.into()));
+    }
+
+    #[test]
+    fn partial_resolution_keeps_remainder_disputed() {
+        let mut account = Account::new(1);
+        account.register_transaction(Transaction::new(1, 1, 100.into(), TransactionType::Deposit));
+        account.dispute_transaction(1);
+        account.resolve_partial_disputed_transaction(1, 40.into());
+
+        assert!(&(account.available_balance).eq(&40.into()));
+        assert!(&(account.held_balance).eq(&60.into()));
+        assert_eq!(account.transactions[&1].dispute_state, DisputeState::Disputed);
+        assert_eq!(account.transactions[&1].held_amount, 60.into());
+
+        account.resolve_partial_disputed_transaction(1, 60.into());
+        assert!(&(account.available_balance).eq(&100.into()));
+        assert!(&(account.held_balance).eq(&Zero::zero()));
+        assert_eq!(account.transactions[&1].dispute_state, DisputeState::Undisputed);
+    }
+
+    #[test]
+    fn rejects_chargeback_on_partially_resolved_transaction() {
+        let mut account = Account::new(1);
+        account.register_transaction(Transaction::new(1, 1, 100.into(), TransactionType::Deposit));
+        account.dispute_transaction(1);
+        account.resolve_partial_disputed_transaction(1, 40.into());
+        account.chargeback_disputed_transaction(1);
+
+        assert!(&(account.available_balance).eq(&40.into()));
+        assert!(&(account.held_balance).eq(&60.into()));
+        assert_eq!(account.transactions[&1].dispute_state, DisputeState::Disputed);
+        assert!(!account.is_frozen());
+
+        // Without the rule, the remaining held funds are charged back
+        account.chargeback_requires_full_hold = false;
+        account.chargeback_disputed_transaction(1);
+        assert!(&(account.available_balance).eq(&40.into()));
+        assert!(&(account.held_balance).eq(&Zero::zero()));
+        assert_eq!(account.transactions[&1].dispute_state, DisputeState::ChargedBack);
+        assert!(account.is_frozen());
+    }
+
+    #[test]
+    fn partial_chargeback_keeps_remainder_disputed() {
+        let mut account = Account::new(1);
+        account.register_transaction(Transaction::new(1, 1, 100.into(), TransactionType::Deposit));
+        account.dispute_transaction(1);
+        account.chargeback_partial_disputed_transaction(1, 40.into());
+
+        assert!(&(account.available_balance).eq(&Zero::zero()));
+        assert!(&(account.held_balance).eq(&60.into()));
+        assert_eq!(account.transactions[&1].dispute_state, DisputeState::Disputed);
+        assert_eq!(account.transactions[&1].held_amount, 60.into());
+        assert_eq!(account.transactions[&1].charged_back_amount, 40.into());
+        assert!(account.is_frozen());
+
+        // More than is still held is ignored
+        account.chargeback_partial_disputed_transaction(1, 61.into());
+        assert!(&(account.held_balance).eq(&60.into()));
+
+        // A partial chargeback doesn't count as a resolution, so the rest may still be charged back
+        account.chargeback_disputed_transaction(1);
+        assert!(&(account.held_balance).eq(&Zero::zero()));
+        assert_eq!(account.transactions[&1].dispute_state, DisputeState::ChargedBack);
+        assert_eq!(account.transactions[&1].charged_back_amount, 100.into());
+    }
+
+    #[test]
+    fn partial_chargeback_remainder_may_be_resolved() {
+        let mut account = Account::new(1);
+        account.register_transaction(Transaction::new(1, 1, 100.into(), TransactionType::Deposit));
+        account.dispute_transaction(1);
+        account.chargeback_partial_disputed_transaction(1, 40.into());
+        account.resolve_disputed_transaction(1);
+
+        assert!(&(account.available_balance).eq(&60.into()));
+        assert!(&(account.held_balance).eq(&Zero::zero()));
+        assert_eq!(account.transactions[&1].dispute_state, DisputeState::Undisputed);
+        assert!(account.is_frozen());
+
+        // Only the part that wasn't charged back can be disputed again
+        account.dispute_transaction(1);
+        assert!(&(account.available_balance).eq(&Zero::zero()));
+        assert!(&(account.held_balance).eq(&60.into()));
+    }
+
+    #[test]
+    fn normalizes_balances_on_dispute() {
+        let mut account = Account { normalize_on_dispute: Some(4), ..Account::new(1) };
+        account.register_transaction(Transaction::new(1, 1, 20.into(), TransactionType::Deposit));
+        // A fee charged at a finer precision than the currency
+        account.available_balance = &account.available_balance - &"0.00004".parse().unwrap();
+        account.register_transaction(Transaction::new(2, 1, 10.into(), TransactionType::Deposit));
+        account.dispute_transaction(2);
+
+        assert_eq!(account.available_balance.as_decimal().as_bigint_and_exponent().1, 4);
+        assert_eq!(account.held_balance.as_decimal().as_bigint_and_exponent().1, 4);
+        assert_eq!(account.available_balance, 20.into());
+        assert_eq!(account.held_balance, 10.into());
+    }
+
+    #[test]
+    fn merge_sums_balances_and_combines_history() {
+        let mut account = Account::new(1);
+        account.register_transaction(Transaction::new(1, 1, 10.into(), TransactionType::Deposit));
+        let mut other = Account::new(1);
+        other.register_transaction(Transaction::new(2, 1, 5.into(), TransactionType::Deposit));
+        other.dispute_transaction(2);
+        other.chargeback_disputed_transaction(2);
+        account.merge(other);
+
+        assert!(&(account.available_balance).eq(&10.into()));
+        assert!(&(account.held_balance).eq(&Zero::zero()));
+        assert_eq!(account.transactions.len(), 2);
+        assert_eq!(account.freeze_reason, Some(FreezeReason::Chargeback { tx: 2 }));
+    }
+
+    #[test]
+    fn freezes_once_dispute_limit_is_reached() {
+        let mut account = Account::with_freeze_after_disputes(1, Some(3));
+        for id in 1..=3 {
+            account.register_transaction(Transaction::new(id, 1, 10.into(), TransactionType::Deposit));
+        }
+
+        // a resolved dispute still counts towards the limit
+        account.dispute_transaction(1);
+        account.resolve_disputed_transaction(1);
+        account.dispute_transaction(2);
+        // an ignored dispute does not
+        account.dispute_transaction(2);
+        assert_eq!(account.dispute_count, 2);
+        assert!(!account.is_frozen());
+
+        account.dispute_transaction(3);
+        assert_eq!(account.dispute_count, 3);
+        assert_eq!(account.freeze_reason, Some(FreezeReason::DisputeLimit { disputes: 3 }));
+        assert_eq!(account.freeze_reason.unwrap().to_string(), "disputes:3");
+    }
+
+    #[test]
+    fn disputes_do_not_freeze_without_a_limit() {
+        let mut account = Account::new(1);
+        for id in 1..=5 {
+            account.register_transaction(Transaction::new(id, 1, 10.into(), TransactionType::Deposit));
+            account.dispute_transaction(id);
+        }
+        assert_eq!(account.dispute_count, 5);
+        assert!(!account.is_frozen());
+    }
+
+    #[test]
+    fn apply_dispute_reports_each_outcome() {
+        let mut account = Account::new(1);
+        account.on_invariant_violation = InvariantPolicy::Skip;
+        for (id, transaction_type, amount) in [(1, TransactionType::Deposit, 10), (2, TransactionType::Withdrawal, 4), (3, TransactionType::Deposit, 5)] {
+            account.register_transaction(Transaction::new(id, 1, amount.into(), transaction_type));
+        }
+
+        assert_eq!(account.apply_dispute(DisputeActionType::Dispute, 9), DisputeOutcome::UnknownTransaction);
+        assert_eq!(account.apply_dispute(DisputeActionType::Resolve, 1), DisputeOutcome::Ignored);
+        assert_eq!(account.apply_dispute(DisputeActionType::Dispute, 2), DisputeOutcome::WithdrawalsNotDisputable);
+        assert_eq!(account.apply_dispute(DisputeActionType::Dispute, 1), DisputeOutcome::Applied);
+        assert_eq!(account.apply_dispute(DisputeActionType::Dispute, 1), DisputeOutcome::Ignored);
+        // only 1 of the 11 available remains once deposit 1 is held
+        assert_eq!(account.apply_dispute(DisputeActionType::Dispute, 3), DisputeOutcome::InsufficientFunds);
+        assert_eq!(account.apply_dispute_amount(DisputeActionType::Resolve, 1, Some(11.into())), DisputeOutcome::InsufficientHeld);
+        account.held_balance = 3.into();
+        assert_eq!(account.apply_dispute(DisputeActionType::Chargeback, 1), DisputeOutcome::InsufficientHeld);
+        account.held_balance = 10.into();
+        assert_eq!(account.apply_dispute(DisputeActionType::Chargeback, 1), DisputeOutcome::Applied);
+        assert!(account.is_frozen());
+        assert_eq!(account.apply_dispute(DisputeActionType::Resolve, 1), DisputeOutcome::Ignored);
+        assert_eq!(account.available_balance, 1.into());
+        assert!(account.held_balance.is_zero());
+    }
+
+    /// Assert that the held balance is exactly the sum of the amounts held by the
+    /// transactions currently under dispute
+    fn assert_held_matches_disputes(account: &Account) {
+        let held: Money = account.transactions.values()
+            .filter(|transaction| transaction.dispute_state == DisputeState::Disputed)
+            .fold(Money::default(), |mut held, transaction| { held += &transaction.held_amount; held });
+        assert_eq!(account.held_balance, held);
+    }
+
+    #[test]
+    fn resolved_deposit_can_be_disputed_again() {
+        let mut account = Account::new(1);
+        for (id, transaction_type, amount) in [(1, TransactionType::Deposit, 10), (2, TransactionType::Deposit, 5)] {
+            account.register_transaction(Transaction::new(id, 1, amount.into(), transaction_type));
+        }
+
+        for (action_type, available, held) in [
+            (DisputeActionType::Dispute, 5, 10),
+            (DisputeActionType::Resolve, 15, 0),
+            (DisputeActionType::Dispute, 5, 10),
+            (DisputeActionType::Chargeback, 5, 0),
+        ] {
+            assert_eq!(account.apply_dispute(action_type, 1), DisputeOutcome::Applied, "for {:?}", action_type);
+            assert_eq!(account.available_balance, available.into(), "for {:?}", action_type);
+            assert_eq!(account.held_balance, held.into(), "for {:?}", action_type);
+            assert_held_matches_disputes(&account);
+        }
+        assert_eq!(account.transactions[&1].dispute_state, DisputeState::ChargedBack);
+        assert_eq!(account.dispute_count, 2);
+        assert!(account.is_frozen());
+    }
+
+    #[test]
+    fn redispute_after_withdrawal_needs_available_funds() {
+        let mut account = Account::new(1);
+        for (id, transaction_type, amount) in [(1, TransactionType::Deposit, 10), (2, TransactionType::Deposit, 5)] {
+            account.register_transaction(Transaction::new(id, 1, amount.into(), transaction_type));
+        }
+        assert_eq!(account.apply_dispute(DisputeActionType::Dispute, 1), DisputeOutcome::Applied);
+        assert_eq!(account.apply_dispute(DisputeActionType::Resolve, 1), DisputeOutcome::Applied);
+        account.register_transaction(Transaction::new(3, 1, 8.into(), TransactionType::Withdrawal));
+
+        // Only 7 is left available, so the deposit of 10 can't be held again,
+        // and nothing changes
+        assert_eq!(account.apply_dispute(DisputeActionType::Dispute, 1), DisputeOutcome::InsufficientFunds);
+        assert_eq!(account.available_balance, 7.into());
+        assert!(account.held_balance.is_zero());
+        assert_eq!(account.transactions[&1].dispute_state, DisputeState::Undisputed);
+        assert_held_matches_disputes(&account);
+        assert_eq!(account.apply_dispute(DisputeActionType::Chargeback, 1), DisputeOutcome::Ignored);
+        assert!(!account.is_frozen());
+
+        // Once there are enough funds again, the dispute goes ahead
+        account.register_transaction(Transaction::new(4, 1, 3.into(), TransactionType::Deposit));
+        assert_eq!(account.apply_dispute(DisputeActionType::Dispute, 1), DisputeOutcome::Applied);
+        assert_eq!(account.available_balance, 0.into());
+        assert_eq!(account.held_balance, 10.into());
+        assert_held_matches_disputes(&account);
+    }
+
+    #[test]
+    fn net_position_includes_held_funds() {
+        let mut available_balance: Money = 5.into();
+        available_balance -= &8.into();
+        let account = Account { available_balance, held_balance: "2.5".parse().unwrap(), ..Account::new(1) };
+        assert_eq!(account.net_position().rounded(1), "-0.5".parse().unwrap());
+        assert!(Account::new(2).net_position().is_zero());
+    }
+
+    #[test]
+    fn transactions_iterate_in_id_order() {
+        let mut account = Account::new(1);
+        for id in [7, 3, 42, 1, 19] {
+            account.register_transaction(Transaction::new(id, 1, 1.into(), TransactionType::Deposit));
+        }
+        let ids: Vec<u32> = account.transactions.values().map(|transaction| transaction.id).collect();
+        assert_eq!(ids, vec![1, 3, 7, 19, 42]);
+    }
+}
@@ -0,0 +1,628 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+use bigdecimal::BigDecimal;
+use num_traits::Signed;
+
+use crate::account::{ InterestCredit, InvariantPolicy };
+use crate::config::{ EngineConfig, ErrorPolicy, LimitPolicy, RatePolicy };
+use crate::csv_rows::{ InputRow, Locale };
+use crate::engine::AccountOrder;
+use crate::money::{ Money, RoundingMode };
+
+/// The format the final account states are written in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// A CSV file with a header row
+    #[default]
+    Csv,
+    /// A JSON array with one object per account
+    Json,
+    /// An aligned text table, for reading in a terminal
+    Table,
+    /// A bincode-encoded `Vec<OutputRow>`, always including the verbose
+    /// columns so that the layout is fixed
+    Bincode,
+}
+
+/// The format the input transactions are read in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InputFormat {
+    /// A CSV file with a header row
+    #[default]
+    Csv,
+    /// JSON lines, with one object per row
+    Jsonl,
+}
+
+/// Options parsed from the command line
+#[derive(Clone, Debug, Default)]
+pub struct CliOptions {
+    /// The settings for the engine itself
+    pub engine: EngineConfig,
+    /// The path of the input CSV file, or `None` to read from stdin
+    pub filename: Option<String>,
+    /// A hypothetical transaction to apply to a copy of its account's final
+    /// state, reporting the effect without persisting it
+    pub dry_apply: Option<InputRow>,
+    /// Whether to include diagnostic columns (such as `lock_reason`) in the output
+    pub verbose_columns: bool,
+    /// Whether to include the number of transactions stored for each account in the output
+    pub show_tx_count: bool,
+    /// Whether to include the net position of each account in the output
+    pub show_net: bool,
+    /// Whether to flag the accounts with a negative available balance in the output
+    pub show_negative: bool,
+    /// The expected sum of all deposits in the input. The run fails
+    /// if the deposits don't match it exactly
+    pub control_total: Option<BigDecimal>,
+    /// The path to write an export of every stored transaction to, if any
+    pub export_transactions: Option<String>,
+    /// Whether to scan the input for reused transaction IDs before processing
+    pub check_unique_tx: bool,
+    /// Whether to only check that every row of the input is valid, reporting the
+    /// problems found rather than processing the rows and writing the accounts
+    pub check: bool,
+    /// Whether an account ending with a negative available balance fails the run
+    pub fail_on_negative: bool,
+    /// The path of a CSV of the accounts the output is expected to match, if any
+    pub assert_state: Option<String>,
+    /// Whether to check the internal invariants of every account after processing
+    pub self_check: bool,
+    /// Whether failed integrity checks fail the run rather than only being reported
+    pub strict: bool,
+    /// The number of threads to process the files of an input directory on,
+    /// independently of each other. Files are processed in order if not set
+    pub parallel_files: Option<usize>,
+    /// The format to read the input in
+    pub input_format: InputFormat,
+    /// The format to write the output in
+    pub format: OutputFormat,
+    /// The order to list the accounts in
+    pub order: AccountOrder,
+    /// The number of worker threads to spread the accounts across. Rows
+    /// are processed on the reading thread if not set
+    pub threads: Option<usize>,
+    /// Whether to print a summary of the rows processed to stderr
+    pub verbose: bool,
+    /// Whether to write nothing to stderr, other than reports asked for by other options
+    pub quiet: bool,
+    /// Whether the output `total` is only the available balance, rather
+    /// than the available and held balances together
+    pub total_excludes_held: bool,
+    /// The clients to include in the output. All clients are included if empty,
+    /// and every row is processed either way
+    pub client_filter: HashSet<u16>,
+    /// An external store, such as a Redis URL, to also write the output to
+    pub sink: Option<String>,
+    /// The path of a snapshot to load the accounts from before processing
+    pub snapshot_in: Option<String>,
+    /// The path of a CSV of opening balances to seed the accounts with before processing
+    pub seed_accounts: Option<String>,
+    /// The path to write a snapshot of the accounts to after processing
+    pub snapshot_out: Option<String>,
+    /// The path of a snapshot to compare the accounts with, only outputting
+    /// the accounts that differ from it
+    pub diff_against: Option<String>,
+    /// Whether to total the balances of the accounts output, by currency
+    pub summary: bool,
+    /// The path to write the totals to, or `None` to write them after the output
+    pub summary_out: Option<String>,
+    /// The path to write the output to, or `None` to write it to stdout
+    pub output: Option<String>,
+    /// The character separating the fields of the input and CSV output,
+    /// or `None` for a comma
+    pub delimiter: Option<u8>,
+}
+
+/// The largest number of decimal places `--decimals` accepts, as amounts
+/// are never held more precisely than this
+pub const MAX_DECIMALS: u32 = Money::MAX_SCALE as u32;
+
+/// Parse the command line arguments (excluding the program name) into options,
+/// returning a message describing the problem if they are malformed
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<CliOptions, String> {
+    let mut args = args.into_iter();
+    let mut options = CliOptions::default();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-apply" => {
+                let spec = args.next().ok_or("--dry-apply requires a transaction, e.g. withdrawal,1,99,10.0")?;
+                let row = spec.parse::<InputRow>()
+                    .map_err(|err| format!("Invalid --dry-apply transaction '{}': {}", spec, err))?;
+                options.dry_apply = Some(row);
+            },
+            "--max-input-scale" => {
+                let scale: u32 = parse_value(&arg, args.next())?;
+                options.engine.max_input_scale = Some(scale.into());
+            },
+            "--max-amount" => {
+                let max_amount: BigDecimal = parse_value(&arg, args.next())?;
+                if !max_amount.is_positive() {
+                    return Err("--max-amount must be more than 0".to_string());
+                }
+                options.engine.max_amount = Some(max_amount);
+            },
+            "--decimals" => {
+                let decimals: u32 = parse_value(&arg, args.next())?;
+                if decimals > MAX_DECIMALS {
+                    return Err(format!("--decimals must be between 0 and {}", MAX_DECIMALS));
+                }
+                options.engine.decimals = decimals.into();
+            },
+            "--locale" => {
+                options.engine.locale = match args.next().as_deref() {
+                    Some("en") => Locale::En,
+                    Some("de") => Locale::De,
+                    _ => return Err("--locale must be 'en' or 'de'".to_string())
+                };
+            },
+            "--rounding" => {
+                options.engine.rounding = match args.next().as_deref() {
+                    Some("half-up") => RoundingMode::HalfUp,
+                    Some("half-even") => RoundingMode::HalfEven,
+                    Some("truncate") => RoundingMode::Truncate,
+                    _ => return Err("--rounding must be 'half-up', 'half-even', or 'truncate'".to_string())
+                };
+            },
+            "--minor-units" => {
+                let scale: u32 = parse_value(&arg, args.next())?;
+                if scale > MAX_DECIMALS {
+                    return Err(format!("--minor-units must be between 0 and {}", MAX_DECIMALS));
+                }
+                options.engine.minor_units = Some(scale.into());
+            },
+            "--total-excludes-held" => options.total_excludes_held = true,
+            "--verbose" => options.verbose = true,
+            "--quiet" => options.quiet = true,
+            "--verbose-columns" => {
+                options.verbose_columns = true;
+                options.engine.track_last_seq = true;
+            },
+            "--replay-speed" => {
+                let rate: u32 = parse_value(&arg, args.next())?;
+                if rate == 0 {
+                    return Err("--replay-speed must be at least 1 row per second".to_string());
+                }
+                options.engine.replay_speed = Some(rate);
+            },
+            "--progress-every" => {
+                let rows: u64 = parse_value(&arg, args.next())?;
+                if rows == 0 {
+                    return Err("--progress-every must be at least 1 row".to_string());
+                }
+                options.engine.progress_every = Some(rows);
+            },
+            "--client" => { options.client_filter.insert(parse_value(&arg, args.next())?); },
+            "--control-total" => options.control_total = Some(parse_value(&arg, args.next())?),
+            "--since" => options.engine.since = Some(parse_value(&arg, args.next())?),
+            "--until" => options.engine.until = Some(parse_value(&arg, args.next())?),
+            "--max-rows" => options.engine.max_rows = Some(parse_value(&arg, args.next())?),
+            "--on-limit" => {
+                options.engine.on_limit = match args.next().as_deref() {
+                    Some("abort") => LimitPolicy::Abort,
+                    Some("truncate") => LimitPolicy::Truncate,
+                    _ => return Err("--on-limit must be 'abort' or 'truncate'".to_string())
+                };
+            },
+            "--max-client-rate" => {
+                let rate: usize = parse_value(&arg, args.next())?;
+                if rate == 0 {
+                    return Err("--max-client-rate must be at least 1".to_string());
+                }
+                options.engine.max_client_rate = Some(rate);
+            },
+            "--on-client-rate" => {
+                options.engine.on_client_rate = match args.next().as_deref() {
+                    Some("flag") => RatePolicy::Flag,
+                    Some("reject") => RatePolicy::Reject,
+                    _ => return Err("--on-client-rate must be 'flag' or 'reject'".to_string())
+                };
+            },
+            "--max-history-bytes" => options.engine.max_history_bytes = Some(parse_value(&arg, args.next())?),
+            "--audit-precision-loss" => options.engine.audit_precision_loss = true,
+            "--buffer-out-of-order-disputes" => options.engine.buffer_out_of_order_disputes = true,
+            "--enforce-unique-tx" => options.engine.enforce_unique_tx = true,
+            "--dispute-lenient-client" => options.engine.lenient_dispute_client = true,
+            "--export-transactions" | "--ledger" => options.export_transactions = Some(parse_value(&arg, args.next())?),
+            "--require-open" => options.engine.require_open = true,
+            "--held-interest-rate" => options.engine.held_interest_rate = parse_value(&arg, args.next())?,
+            "--held-interest-to" => {
+                options.engine.held_interest_credit = match args.next().as_deref() {
+                    Some("available") => InterestCredit::Available,
+                    Some("held") => InterestCredit::Held,
+                    _ => return Err("--held-interest-to must be 'available' or 'held'".to_string())
+                };
+            },
+            "--check" => options.check = true,
+            "--check-unique-tx" => options.check_unique_tx = true,
+            "--delimiter" => {
+                options.delimiter = match args.next().as_deref() {
+                    Some(",") => Some(b','),
+                    Some(";") => Some(b';'),
+                    Some("\\t" | "\t") => Some(b'\t'),
+                    _ => return Err("--delimiter must be a single ',', ';', or '\\t' character".to_string())
+                };
+            },
+            "--output" => options.output = Some(parse_value(&arg, args.next())?),
+            "--sink" => options.sink = Some(parse_value(&arg, args.next())?),
+            "--snapshot-in" => options.snapshot_in = Some(parse_value(&arg, args.next())?),
+            "--diff-against" => options.diff_against = Some(parse_value(&arg, args.next())?),
+            "--seed-accounts" => options.seed_accounts = Some(parse_value(&arg, args.next())?),
+            "--snapshot-out" => options.snapshot_out = Some(parse_value(&arg, args.next())?),
+            "--show-tx-count" => options.show_tx_count = true,
+            "--show-net" => options.show_net = true,
+            "--show-negative" => options.show_negative = true,
+            "--summary" => options.summary = true,
+            "--summary-out" => {
+                options.summary_out = Some(parse_value(&arg, args.next())?);
+                options.summary = true;
+            },
+            "--fail-on-negative" => options.fail_on_negative = true,
+            "--self-check" => options.self_check = true,
+            "--assert-state" => options.assert_state = Some(parse_value(&arg, args.next())?),
+            "--strict" => options.strict = true,
+            "--strict-disputes" => options.engine.strict_disputes = true,
+            "--normalize-on-dispute" => options.engine.normalize_on_dispute = true,
+            "--parallel-files" => {
+                let threads: usize = parse_value(&arg, args.next())?;
+                if threads == 0 {
+                    return Err("--parallel-files must be at least 1 thread".to_string());
+                }
+                options.parallel_files = Some(threads);
+            },
+            // Dispute-only accounts are always omitted now; the flag is kept for old scripts
+            "--omit-dispute-only-accounts" => {},
+            "--format" => {
+                options.format = match args.next().as_deref() {
+                    Some("csv") => OutputFormat::Csv,
+                    Some("json") => OutputFormat::Json,
+                    Some("table") => OutputFormat::Table,
+                    Some("bincode") => OutputFormat::Bincode,
+                    _ => return Err("--format must be 'csv', 'json', 'table', or 'bincode'".to_string())
+                };
+            },
+            "--input-format" => {
+                options.input_format = match args.next().as_deref() {
+                    Some("csv") => InputFormat::Csv,
+                    Some("jsonl") => InputFormat::Jsonl,
+                    _ => return Err("--input-format must be 'csv' or 'jsonl'".to_string())
+                };
+            },
+            "--on-invariant-violation" => {
+                options.engine.on_invariant_violation = match args.next().as_deref() {
+                    Some("panic") => InvariantPolicy::Panic,
+                    Some("warn") => InvariantPolicy::Warn,
+                    Some("skip") => InvariantPolicy::Skip,
+                    _ => return Err("--on-invariant-violation must be 'panic', 'warn', or 'skip'".to_string())
+                };
+            },
+            "--hard-freeze" => options.engine.hard_freeze = true,
+            "--freeze-after-disputes" => {
+                let disputes: u32 = parse_value(&arg, args.next())?;
+                if disputes == 0 {
+                    return Err("--freeze-after-disputes must be at least 1 dispute".to_string());
+                }
+                options.engine.freeze_after_disputes = Some(disputes);
+            },
+            "--overdraft" => options.engine.overdraft_limit = parse_value(&arg, args.next())?,
+            "--withdrawal-fee" => options.engine.withdrawal_fee = parse_value(&arg, args.next())?,
+            "--order" => {
+                options.order = match args.next().as_deref() {
+                    Some("client") => AccountOrder::Client,
+                    Some("first-seen") => AccountOrder::FirstSeen,
+                    _ => return Err("--order must be 'client' or 'first-seen'".to_string())
+                };
+            },
+            "--on-error" => {
+                options.engine.on_error = match args.next().as_deref() {
+                    Some("skip") => ErrorPolicy::Skip,
+                    Some("warn") => ErrorPolicy::Warn,
+                    Some("abort") => ErrorPolicy::Abort,
+                    _ => return Err("--on-error must be 'skip', 'warn', or 'abort'".to_string())
+                };
+            },
+            "--trace" => options.engine.trace = Some(parse_value(&arg, args.next())?),
+            "--threads" => {
+                let threads: usize = parse_value(&arg, args.next())?;
+                if threads == 0 {
+                    return Err("--threads must be at least 1 thread".to_string());
+                }
+                options.threads = Some(threads);
+            },
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+            _ => {
+                if options.filename.replace(arg).is_some() {
+                    return Err("Only one input file may be given".to_string());
+                }
+            }
+        }
+    }
+
+    // Check the hypothetical transaction now, rather than after processing the whole input
+    if let Some(row) = &options.dry_apply {
+        let engine = &options.engine;
+        row.clone().into_transaction(engine.max_input_scale, engine.max_amount.as_ref(), engine.decimals, engine.rounding)
+            .map_err(|err| format!("Invalid --dry-apply transaction: {}", err))?;
+    }
+    if let (Some(since), Some(until)) = (options.engine.since, options.engine.until) {
+        if since >= until {
+            return Err("--since must be earlier than --until".to_string());
+        }
+    }
+    if options.threads.is_some() && options.parallel_files.is_some() {
+        return Err("--threads and --parallel-files cannot be combined".to_string());
+    }
+    if options.input_format == InputFormat::Jsonl {
+        let csv_only = [
+            ("--threads", options.threads.is_some()),
+            ("--parallel-files", options.parallel_files.is_some()),
+            ("--check", options.check),
+            ("--check-unique-tx", options.check_unique_tx),
+            ("--delimiter", options.delimiter.is_some()),
+        ];
+        if let Some((flag, _)) = csv_only.iter().find(|(_, given)| *given) {
+            return Err(format!("{} cannot be combined with --input-format jsonl", flag));
+        }
+    }
+    if options.summary && options.summary_out.is_none() && options.format != OutputFormat::Csv {
+        return Err("--summary requires --format csv, unless the totals go to --summary-out".to_string());
+    }
+    if options.verbose && options.quiet {
+        return Err("--verbose and --quiet cannot be combined".to_string());
+    }
+    if options.self_check && options.engine.held_interest_credit == InterestCredit::Held {
+        return Err("--self-check cannot be combined with --held-interest-to held".to_string());
+    }
+    if options.engine.lenient_dispute_client && (options.threads.is_some() || options.parallel_files.is_some()) {
+        return Err("--dispute-lenient-client cannot be combined with --threads or --parallel-files".to_string());
+    }
+    if options.engine.enforce_unique_tx && (options.threads.is_some() || options.parallel_files.is_some()) {
+        return Err("--enforce-unique-tx cannot be combined with --threads or --parallel-files".to_string());
+    }
+    if options.engine.max_client_rate.is_some() && options.parallel_files.is_some() {
+        return Err("--max-client-rate and --parallel-files cannot be combined".to_string());
+    }
+    if options.seed_accounts.is_some() && options.parallel_files.is_some() {
+        return Err("--seed-accounts and --parallel-files cannot be combined".to_string());
+    }
+    if options.snapshot_in.is_some() && options.parallel_files.is_some() {
+        return Err("--snapshot-in and --parallel-files cannot be combined".to_string());
+    }
+    Ok(options)
+}
+
+/// Parse the value following a flag, reporting which flag was at fault on failure
+fn parse_value<T: FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} requires a value", flag))?;
+    value.parse().map_err(|_| format!("Invalid value for {}: '{}'", flag, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_filename() {
+        let options = parse_args(args(&["input.csv"])).expect("Parse failed");
+        assert_eq!(options.filename.as_deref(), Some("input.csv"));
+        assert!(options.dry_apply.is_none());
+    }
+
+    #[test]
+    fn parses_dry_apply() {
+        let options = parse_args(args(&["input.csv", "--dry-apply", "withdrawal, 1, 99, 10.5"])).expect("Parse failed");
+        let row = options.dry_apply.expect("Dry apply not parsed");
+        assert_eq!(row.transaction_type, "withdrawal");
+        assert_eq!(row.client, 1);
+        assert_eq!(row.tx, 99);
+        assert_eq!(row.amount, Some("10.5".parse().unwrap()));
+
+        let error = parse_args(args(&["input.csv", "--dry-apply", "deposit,1,99,500.5", "--max-amount", "100"]))
+            .expect_err("Too large an amount was allowed");
+        assert_eq!(error, "Invalid --dry-apply transaction: amount is too large");
+        parse_args(args(&["input.csv", "--max-input-scale", "0", "--dry-apply", "deposit,1,99,500.5"]))
+            .expect_err("Too precise an amount was allowed");
+        parse_args(args(&["input.csv", "--dry-apply", "dispute,1,99,"])).expect_err("A dispute was allowed");
+    }
+
+    #[test]
+    fn reads_stdin_without_filename() {
+        let options = parse_args(args(&["--verbose-columns"])).expect("Parse failed");
+        assert!(options.filename.is_none());
+    }
+
+    #[test]
+    fn rejects_multiple_filenames() {
+        parse_args(args(&["first.csv", "second.csv"])).expect_err("Multiple filenames were allowed");
+    }
+
+    #[test]
+    fn parses_format() {
+        let options = parse_args(args(&["input.csv"])).expect("Parse failed");
+        assert_eq!(options.format, OutputFormat::Csv);
+        let options = parse_args(args(&["input.csv", "--format", "json"])).expect("Parse failed");
+        assert_eq!(options.format, OutputFormat::Json);
+        parse_args(args(&["input.csv", "--format", "xml"])).expect_err("Unknown format was allowed");
+    }
+
+    #[test]
+    fn parses_input_format() {
+        let options = parse_args(args(&["input.csv"])).expect("Parse failed");
+        assert_eq!(options.input_format, InputFormat::Csv);
+        let options = parse_args(args(&["input.jsonl", "--input-format", "jsonl"])).expect("Parse failed");
+        assert_eq!(options.input_format, InputFormat::Jsonl);
+        parse_args(args(&["input.jsonl", "--input-format", "xml"])).expect_err("Unknown format was allowed");
+        parse_args(args(&["input.jsonl", "--input-format", "jsonl", "--threads", "2"])).expect_err("--threads was allowed");
+    }
+
+    #[test]
+    fn parses_summary() {
+        let options = parse_args(args(&["input.csv", "--summary"])).expect("Parse failed");
+        assert!(options.summary);
+        assert_eq!(options.summary_out, None);
+        let options = parse_args(args(&["input.csv", "--summary-out", "totals.csv", "--format", "json"])).expect("Parse failed");
+        assert!(options.summary);
+        assert_eq!(options.summary_out.as_deref(), Some("totals.csv"));
+        parse_args(args(&["input.csv", "--summary", "--format", "json"])).expect_err("--summary was allowed with JSON output");
+    }
+
+    #[test]
+    fn parses_diff_against() {
+        let options = parse_args(args(&["input.csv"])).expect("Parse failed");
+        assert_eq!(options.diff_against, None);
+        let options = parse_args(args(&["input.csv", "--diff-against", "baseline.snapshot"])).expect("Parse failed");
+        assert_eq!(options.diff_against.as_deref(), Some("baseline.snapshot"));
+    }
+
+    #[test]
+    fn parses_repeated_client_filter() {
+        let options = parse_args(args(&["input.csv"])).expect("Parse failed");
+        assert!(options.client_filter.is_empty());
+        let options = parse_args(args(&["--client", "3", "input.csv", "--client", "7"])).expect("Parse failed");
+        assert_eq!(options.client_filter, HashSet::from([3, 7]));
+        parse_args(args(&["input.csv", "--client", "70000"])).expect_err("Out of range client was allowed");
+    }
+
+    #[test]
+    fn parses_decimals() {
+        let options = parse_args(args(&["input.csv"])).expect("Parse failed");
+        assert_eq!(options.engine.decimals, 4);
+        let options = parse_args(args(&["input.csv", "--decimals", "2"])).expect("Parse failed");
+        assert_eq!(options.engine.decimals, 2);
+        let options = parse_args(args(&["input.csv", "--decimals", "10"])).expect("Parse failed");
+        assert_eq!(options.engine.decimals, 10);
+        parse_args(args(&["input.csv", "--decimals", "11"])).expect_err("Too many decimals were allowed");
+        parse_args(args(&["input.csv", "--decimals", "-1"])).expect_err("Negative decimals were allowed");
+    }
+
+    #[test]
+    fn parses_locale() {
+        let options = parse_args(args(&["input.csv"])).expect("Parse failed");
+        assert_eq!(options.engine.locale, Locale::En);
+        let options = parse_args(args(&["input.csv", "--locale", "de"])).expect("Parse failed");
+        assert_eq!(options.engine.locale, Locale::De);
+        parse_args(args(&["input.csv", "--locale", "fr"])).expect_err("Unknown locale was allowed");
+    }
+
+    #[test]
+    fn parses_rounding() {
+        let options = parse_args(args(&["input.csv"])).expect("Parse failed");
+        assert_eq!(options.engine.rounding, RoundingMode::HalfUp);
+        let options = parse_args(args(&["input.csv", "--rounding", "half-even"])).expect("Parse failed");
+        assert_eq!(options.engine.rounding, RoundingMode::HalfEven);
+        let options = parse_args(args(&["input.csv", "--rounding", "truncate"])).expect("Parse failed");
+        assert_eq!(options.engine.rounding, RoundingMode::Truncate);
+        parse_args(args(&["input.csv", "--rounding", "up"])).expect_err("Unknown rounding mode was allowed");
+    }
+
+    #[test]
+    fn parses_max_input_scale() {
+        let options = parse_args(args(&["--max-input-scale", "2", "input.csv"])).expect("Parse failed");
+        assert_eq!(options.engine.max_input_scale, Some(2));
+        parse_args(args(&["--max-input-scale", "-1", "input.csv"])).expect_err("Negative scale was allowed");
+    }
+
+    #[test]
+    fn rejects_verbose_with_quiet() {
+        let options = parse_args(args(&["--quiet", "input.csv"])).expect("Parse failed");
+        assert!(options.quiet);
+        parse_args(args(&["--quiet", "--verbose", "input.csv"])).expect_err("--verbose was allowed with --quiet");
+    }
+
+    #[test]
+    fn parses_max_amount() {
+        let options = parse_args(args(&["--max-amount", "1000.50", "input.csv"])).expect("Parse failed");
+        assert_eq!(options.engine.max_amount, Some("1000.50".parse().unwrap()));
+        parse_args(args(&["--max-amount", "0", "input.csv"])).expect_err("Zero maximum was allowed");
+    }
+
+    #[test]
+    fn parses_freeze_after_disputes() {
+        let options = parse_args(args(&["input.csv"])).expect("Parse failed");
+        assert_eq!(options.engine.freeze_after_disputes, None);
+        let options = parse_args(args(&["input.csv", "--freeze-after-disputes", "3"])).expect("Parse failed");
+        assert_eq!(options.engine.freeze_after_disputes, Some(3));
+        parse_args(args(&["input.csv", "--freeze-after-disputes", "0"])).expect_err("Zero disputes were allowed");
+    }
+
+    #[test]
+    fn parses_normalize_on_dispute() {
+        assert!(!parse_args(args(&["input.csv"])).expect("Parse failed").engine.normalize_on_dispute);
+        assert!(parse_args(args(&["input.csv", "--normalize-on-dispute"])).expect("Parse failed").engine.normalize_on_dispute);
+    }
+
+    #[test]
+    fn parses_hard_freeze() {
+        assert!(!parse_args(args(&["input.csv"])).expect("Parse failed").engine.hard_freeze);
+        assert!(parse_args(args(&["input.csv", "--hard-freeze"])).expect("Parse failed").engine.hard_freeze);
+    }
+
+    #[test]
+    fn parses_enforce_unique_tx() {
+        let options = parse_args(args(&["input.csv", "--enforce-unique-tx"])).expect("Parse failed");
+        assert!(options.engine.enforce_unique_tx);
+        parse_args(args(&["input.csv", "--enforce-unique-tx", "--threads", "2"])).expect_err("--threads was allowed");
+    }
+
+    #[test]
+    fn parses_assert_state() {
+        let options = parse_args(args(&["input.csv", "--assert-state", "expected.csv"])).expect("Parse failed");
+        assert_eq!(options.assert_state.as_deref(), Some("expected.csv"));
+        parse_args(args(&["input.csv", "--assert-state"])).expect_err("Missing path was allowed");
+    }
+
+    #[test]
+    fn parses_self_check() {
+        let options = parse_args(args(&["input.csv", "--self-check"])).expect("Parse failed");
+        assert!(options.self_check);
+        parse_args(args(&["input.csv", "--self-check", "--held-interest-to", "held"])).expect_err("Held interest was allowed");
+    }
+
+    #[test]
+    fn parses_max_client_rate() {
+        let options = parse_args(args(&["input.csv"])).expect("Parse failed");
+        assert_eq!(options.engine.max_client_rate, None);
+        assert_eq!(options.engine.on_client_rate, RatePolicy::Flag);
+        let options = parse_args(args(&["input.csv", "--max-client-rate", "5", "--on-client-rate", "reject"])).expect("Parse failed");
+        assert_eq!(options.engine.max_client_rate, Some(5));
+        assert_eq!(options.engine.on_client_rate, RatePolicy::Reject);
+        parse_args(args(&["input.csv", "--max-client-rate", "0"])).expect_err("Zero rate was allowed");
+        parse_args(args(&["input.csv", "--on-client-rate", "drop"])).expect_err("Unknown policy was allowed");
+        parse_args(args(&["input.csv", "--max-client-rate", "5", "--parallel-files", "2"])).expect_err("--parallel-files was allowed");
+    }
+
+    #[test]
+    fn parses_dispute_lenient_client() {
+        let options = parse_args(args(&["input.csv", "--dispute-lenient-client"])).expect("Parse failed");
+        assert!(options.engine.lenient_dispute_client);
+        parse_args(args(&["input.csv", "--dispute-lenient-client", "--parallel-files", "2"])).expect_err("--parallel-files was allowed");
+    }
+
+    #[test]
+    fn parses_delimiter() {
+        let options = parse_args(args(&["input.csv"])).expect("Parse failed");
+        assert_eq!(options.delimiter, None);
+        let options = parse_args(args(&["input.csv", "--delimiter", ";"])).expect("Parse failed");
+        assert_eq!(options.delimiter, Some(b';'));
+        let options = parse_args(args(&["input.csv", "--delimiter", "\\t"])).expect("Parse failed");
+        assert_eq!(options.delimiter, Some(b'\t'));
+        parse_args(args(&["input.csv", "--delimiter", ";;"])).expect_err("Several characters were allowed");
+        parse_args(args(&["input.csv", "--delimiter", "|"])).expect_err("Unsupported delimiter was allowed");
+    }
+
+    #[test]
+    fn parses_time_window() {
+        let options = parse_args(args(&["input.csv", "--since", "2024-01-01T00:00:00Z", "--until", "2024-02-01T00:00:00+01:00"]))
+            .expect("Parse failed");
+        assert_eq!(options.engine.since, Some("2024-01-01T00:00:00Z".parse().unwrap()));
+        assert_eq!(options.engine.until, Some("2024-01-31T23:00:00Z".parse().unwrap()));
+        parse_args(args(&["input.csv", "--since", "yesterday"])).expect_err("Bad timestamp was allowed");
+        parse_args(args(&["input.csv", "--since", "2024-02-01T00:00:00Z", "--until", "2024-01-01T00:00:00Z"]))
+            .expect_err("Empty window was allowed");
+    }
+}
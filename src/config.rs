@@ -0,0 +1,185 @@
+use bigdecimal::BigDecimal;
+use chrono::{ DateTime, Utc };
+
+use crate::account::{ Account, InterestCredit, InvariantPolicy };
+use crate::csv_rows::{ Locale, DEFAULT_DECIMALS };
+use crate::money::{ Money, RoundingMode };
+
+/// What happens to input rows that are malformed or specify no known action
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Silently drop the row
+    Skip,
+    /// Report the row on stderr and drop it
+    #[default]
+    Warn,
+    /// Stop processing with an error
+    Abort
+}
+
+/// What happens to the rows of an input beyond its row limit
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum LimitPolicy {
+    /// Stop processing with an error
+    #[default]
+    Abort,
+    /// Report the limit on stderr and ignore the rest of the input
+    Truncate
+}
+
+/// What happens to the deposits and withdrawals of a client going faster than its rate limit
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum RatePolicy {
+    /// Apply them, but report the client once processing is done
+    #[default]
+    Flag,
+    /// Ignore them, as well as reporting the client
+    Reject
+}
+
+/// The tunables that control how the engine applies rows to accounts,
+/// independent of where the rows come from or where the output goes
+#[derive(Clone, Debug)]
+pub struct EngineConfig {
+    /// The number of decimal places amounts are rounded to on input
+    /// and written with on output
+    pub decimals: i64,
+    /// The number of decimal places in a minor unit, if input amounts are given as
+    /// whole numbers of minor units (e.g. 2 for cents) rather than as decimals
+    pub minor_units: Option<i64>,
+    /// How amounts with more than `decimals` places are rounded
+    pub rounding: RoundingMode,
+    /// How input amounts group thousands and mark decimals
+    pub locale: Locale,
+    /// The maximum number of decimal places accepted on input amounts.
+    /// Rows with more precise amounts are rejected rather than rounded
+    pub max_input_scale: Option<i64>,
+    /// The largest amount accepted on deposits and withdrawals. Rows with larger
+    /// amounts are rejected. Unbounded if not set
+    pub max_amount: Option<BigDecimal>,
+    /// The maximum number of rows to process per second, for simulating
+    /// realistic traffic. Unthrottled if not set
+    pub replay_speed: Option<u32>,
+    /// Rows timestamped before this time are not applied
+    pub since: Option<DateTime<Utc>>,
+    /// Rows timestamped at or after this time are not applied
+    pub until: Option<DateTime<Utc>>,
+    /// How many rows to process between each report of progress on stderr.
+    /// No progress is reported if not set
+    pub progress_every: Option<u64>,
+    /// The maximum number of rows to read from each input. Unlimited if not set
+    pub max_rows: Option<u64>,
+    /// What to do with the rows of an input beyond `max_rows`
+    pub on_limit: LimitPolicy,
+    /// The most deposits and withdrawals each client may make within any one second,
+    /// going by the rows' timestamps. Unlimited if not set
+    pub max_client_rate: Option<usize>,
+    /// What to do with the transactions of a client over `max_client_rate`
+    pub on_client_rate: RatePolicy,
+    /// The memory budget, in bytes, for the transaction history retained
+    /// across all accounts. Unlimited if not set
+    pub max_history_bytes: Option<usize>,
+    /// Whether to hold resolves and chargebacks that arrive before their
+    /// dispute, applying them once the dispute opens
+    pub buffer_out_of_order_disputes: bool,
+    /// Whether accounts must be explicitly opened with an `open` row
+    /// before they accept deposits and withdrawals
+    pub require_open: bool,
+    /// The interest rate applied to held funds by `accrue` rows
+    pub held_interest_rate: BigDecimal,
+    /// Where interest earned on held funds is credited
+    pub held_interest_credit: InterestCredit,
+    /// Whether a transaction ID may only be used once across all accounts, rather
+    /// than once per account
+    pub enforce_unique_tx: bool,
+    /// Whether dispute actions naming the wrong client are applied to the account that
+    /// holds the transaction, rather than ignored
+    pub lenient_dispute_client: bool,
+    /// Whether each kind of dispute action may only be applied to a transaction once
+    pub strict_disputes: bool,
+    /// The number of disputes after which an account is frozen, if any
+    pub freeze_after_disputes: Option<u32>,
+    /// Whether frozen accounts also reject dispute actions and manual holds and releases
+    pub hard_freeze: bool,
+    /// How far below zero withdrawals may take each account's available balance
+    pub overdraft_limit: Money,
+    /// The flat fee taken from the available balance with each withdrawal
+    pub withdrawal_fee: Money,
+    /// Whether to round balances back to `decimals` places after each dispute action
+    pub normalize_on_dispute: bool,
+    /// What accounts do when a dispute action would break an internal invariant
+    pub on_invariant_violation: InvariantPolicy,
+    /// The transaction ID to log every processing decision about, if any
+    pub trace: Option<u32>,
+    /// Whether to record the last row that changed each account
+    pub track_last_seq: bool,
+    /// Whether to tally the transactions whose amounts lost precision to rounding
+    pub audit_precision_loss: bool,
+    /// What to do with rows that are malformed or specify no known action
+    pub on_error: ErrorPolicy,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig {
+            decimals: DEFAULT_DECIMALS,
+            minor_units: None,
+            rounding: RoundingMode::default(),
+            locale: Locale::default(),
+            max_input_scale: None,
+            max_amount: None,
+            replay_speed: None,
+            since: None,
+            until: None,
+            progress_every: None,
+            max_rows: None,
+            on_limit: LimitPolicy::default(),
+            max_client_rate: None,
+            on_client_rate: RatePolicy::default(),
+            max_history_bytes: None,
+            buffer_out_of_order_disputes: false,
+            require_open: false,
+            held_interest_rate: BigDecimal::default(),
+            held_interest_credit: InterestCredit::default(),
+            enforce_unique_tx: false,
+            lenient_dispute_client: false,
+            strict_disputes: false,
+            freeze_after_disputes: None,
+            hard_freeze: false,
+            overdraft_limit: Money::default(),
+            withdrawal_fee: Money::default(),
+            normalize_on_dispute: false,
+            on_invariant_violation: InvariantPolicy::default(),
+            trace: None,
+            track_last_seq: false,
+            audit_precision_loss: false,
+            on_error: ErrorPolicy::default(),
+        }
+    }
+}
+
+impl EngineConfig {
+    /// Whether a row with the given timestamp falls within the `since` and `until`
+    /// window. Rows without a timestamp always do
+    pub fn in_window(&self, timestamp: Option<DateTime<Utc>>) -> bool {
+        match timestamp {
+            Some(timestamp) => self.since.is_none_or(|since| timestamp >= since)
+                && self.until.is_none_or(|until| timestamp < until),
+            None => true
+        }
+    }
+
+    /// Apply the account-level settings to an account, whether it is
+    /// new or was loaded from a snapshot
+    pub fn configure_account(&self, account: &mut Account) {
+        account.require_open = self.require_open;
+        account.strict_dispute_dedup = self.strict_disputes;
+        account.freeze_after_disputes = self.freeze_after_disputes;
+        account.hard_freeze = self.hard_freeze;
+        account.overdraft_limit = self.overdraft_limit.clone();
+        account.withdrawal_fee = self.withdrawal_fee.clone();
+        account.normalize_on_dispute = self.normalize_on_dispute.then_some(self.decimals);
+        account.on_invariant_violation = self.on_invariant_violation;
+        account.trace_tx = self.trace;
+    }
+}
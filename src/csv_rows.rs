@@ -1,50 +1,302 @@
 use crate::transaction::*;
 use crate::account::*;
+use crate::money::{ Money, RoundingMode };
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
 use bigdecimal::BigDecimal;
-use num_traits::Zero;
-use serde::{ Deserialize, Serialize };
+use chrono::{ DateTime, Utc };
+use csv::Trim;
+use num_traits::{ Signed, Zero };
+use serde::{ de, Deserialize, Deserializer, Serialize, Serializer };
 
 /// Structure representing a raw input row. This could turn
-/// into either a transaction or a dispute action
-#[derive(Clone, Deserialize)]
+/// into either a transaction or a dispute action. Rows serialize
+/// back out with the same columns they are read with
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct InputRow {
     #[serde(rename = "type")]
     pub transaction_type: String,
+    #[serde(deserialize_with = "deserialize_client")]
     pub client: u16,
+    #[serde(deserialize_with = "deserialize_tx")]
     pub tx: u32,
+    #[serde(default, deserialize_with = "deserialize_amount")]
     pub amount: Option<BigDecimal>,
+    #[serde(default, deserialize_with = "deserialize_timestamp")]
+    pub timestamp: Option<DateTime<Utc>>,
+    /// The currency code of the amount, e.g. `USD`. An empty column is no currency
+    #[serde(default, deserialize_with = "deserialize_currency")]
+    pub currency: Option<String>,
 }
 
-#[derive(Debug)]
+/// The columns every input must have. `amount` may be left out, as only some row types
+/// need it, and so may the optional `timestamp` and `currency`
+pub const REQUIRED_COLUMNS: [&str; 3] = ["type", "client", "tx"];
+
+/// The first required column missing from an input's header row, if any.
+/// Columns are matched by name, so they may come in any order
+pub fn missing_column(headers: &csv::StringRecord) -> Option<&'static str> {
+    REQUIRED_COLUMNS.into_iter().find(|column| !headers.iter().any(|header| header == *column))
+}
+
+/// Deserialize a client ID, see `parse_id`
+fn deserialize_client<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    parse_id(&String::deserialize(deserializer)?, "client").map_err(de::Error::custom)
+}
+
+/// Deserialize a transaction ID, see `parse_id`
+fn deserialize_tx<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    parse_id(&String::deserialize(deserializer)?, "tx").map_err(de::Error::custom)
+}
+
+/// Leniently parse a numeric ID column, allowing surrounding whitespace,
+/// a leading `+`, and zero padding. An empty column is reported by name
+/// rather than as a generic number format error
+fn parse_id<T: FromStr>(text: &str, column: &str) -> Result<T, String> where T::Err: fmt::Display {
+    let text = text.trim();
+    if text.is_empty() {
+        return Err(format!("missing {} id", column));
+    }
+    text.strip_prefix('+').unwrap_or(text).parse()
+        .map_err(|err| format!("invalid {} id '{}': {}", column, text, err))
+}
+
+/// The longest amount text accepted, and the largest exponent an amount may have.
+/// Arithmetic on amounts with millions of digits would take unbounded memory and time
+pub const MAX_AMOUNT_DIGITS: usize = 64;
+
+/// Deserialize an optional amount from its exact decimal text. The csv crate
+/// would otherwise hand BigDecimal an f64, losing the amount's written scale
+fn deserialize_amount<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<BigDecimal>, D::Error> {
+    let too_long = || de::Error::custom(format!("amount has more than {} digits", MAX_AMOUNT_DIGITS));
+    match Option::<String>::deserialize(deserializer)? {
+        // Check the length before parsing, which is itself slow for huge amounts
+        Some(text) if text.len() > MAX_AMOUNT_DIGITS => Err(too_long()),
+        Some(text) if !text.is_empty() => {
+            let amount = BigDecimal::from_str(&text).map_err(de::Error::custom)?;
+            // An exponent, as in `1e1000000`, can make a short amount huge once rounded
+            let (_, scale) = amount.as_bigint_and_exponent();
+            if scale.unsigned_abs() > MAX_AMOUNT_DIGITS as u64 {
+                return Err(too_long());
+            }
+            Ok(Some(amount))
+        },
+        _ => Ok(None)
+    }
+}
+
+/// The conventions amounts are written in, for grouping thousands and marking decimals
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Locale {
+    /// `1,234.56`: commas group thousands, and a point marks the decimals
+    #[default]
+    En,
+    /// `1.234,56`: points group thousands, and a comma marks the decimals
+    De,
+}
+
+impl Locale {
+    /// Rewrite an amount written in this locale in the plain form `1234.56`. Amounts
+    /// whose thousands separators are misplaced, such as `1,23.4` in `en`, are rejected
+    /// rather than guessed at, as are amounts with more than one decimal mark
+    pub fn normalize_amount(self, text: &str) -> Result<Cow<'_, str>, String> {
+        let (group, decimal) = match self {
+            Locale::En => (',', '.'),
+            Locale::De => ('.', ','),
+        };
+        let invalid = || format!("invalid amount '{}' for locale {}", text, self);
+        if text.matches(decimal).count() > 1 {
+            return Err(invalid());
+        }
+        if !text.contains(group) {
+            return Ok(if decimal == '.' { Cow::Borrowed(text) } else { Cow::Owned(text.replace(decimal, ".")) });
+        }
+        let digits = text.trim_start_matches(['+', '-']);
+        let sign = &text[..text.len() - digits.len()];
+        let (whole, fraction) = match digits.split_once(decimal) {
+            Some((whole, fraction)) => (whole, Some(fraction)),
+            None => (digits, None)
+        };
+        let is_digits = |part: &str| !part.is_empty() && part.bytes().all(|byte| byte.is_ascii_digit());
+        let groups: Vec<&str> = whole.split(group).collect();
+        let well_grouped = sign.len() <= 1
+            && groups[0].len() <= 3
+            && groups[1..].iter().all(|group| group.len() == 3)
+            && groups.iter().all(|group| is_digits(group))
+            && fraction.is_none_or(is_digits);
+        if !well_grouped {
+            return Err(invalid());
+        }
+        let fraction = fraction.map(|fraction| format!(".{}", fraction)).unwrap_or_default();
+        Ok(Cow::Owned(format!("{}{}{}", sign, groups.concat(), fraction)))
+    }
+}
+
+impl fmt::Display for Locale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Locale::En => write!(f, "en"),
+            Locale::De => write!(f, "de"),
+        }
+    }
+}
+
+/// Rewrite the amount in the `amount_column` of a record, if it has one, from the way
+/// `locale` writes amounts to plain form, so that it can be deserialized
+pub fn localize_amount(record: &mut csv::StringRecord, amount_column: Option<usize>, locale: Locale) -> Result<(), String> {
+    let Some(text) = amount_column.and_then(|column| record.get(column)) else {
+        return Ok(());
+    };
+    if let Cow::Owned(amount) = locale.normalize_amount(text)? {
+        let mut localized: csv::StringRecord = record.iter().enumerate()
+            .map(|(column, field)| if Some(column) == amount_column { amount.as_str() } else { field })
+            .collect();
+        localized.set_position(record.position().cloned());
+        *record = localized;
+    }
+    Ok(())
+}
+
+/// Deserialize an optional currency code. An empty column is no currency
+fn deserialize_currency<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.filter(|currency| !currency.is_empty()))
+}
+
+/// Deserialize an optional RFC 3339 timestamp, e.g. `2024-03-01T12:00:00Z`, converting
+/// it to UTC. An empty column is no timestamp
+fn deserialize_timestamp<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(text) if !text.is_empty() => DateTime::parse_from_rfc3339(&text)
+            .map(|timestamp| Some(timestamp.with_timezone(&Utc)))
+            .map_err(|err| de::Error::custom(format!("invalid timestamp '{}': {}", text, err))),
+        _ => Ok(None)
+    }
+}
+
+impl FromStr for InputRow {
+    type Err = csv::Error;
+    /// Parse a single headerless CSV record in the input column order,
+    /// e.g. `withdrawal, 1, 99, 10.0`
+    fn from_str(record: &str) -> Result<InputRow, csv::Error> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .trim(Trim::All)
+            .from_reader(record.as_bytes());
+        reader.deserialize().next().unwrap_or_else(|| {
+            Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "empty record").into())
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
 /// Simple enum type for parse errors
 pub enum InputRowParseErr {
     UnknownType,
-    BadAmount
+    BadAmount,
+    /// The row's type requires an amount, but it has none
+    MissingAmount,
+    /// A deposit or withdrawal whose amount is zero once rounded
+    ZeroAmount,
+    /// The amount was meant to be a whole number of minor units, but had a fraction
+    FractionalMinorUnits,
+    /// The amount has more decimal places than the configured maximum
+    TooPrecise { scale: i64 },
+    /// The amount of a deposit or withdrawal is more than the configured maximum
+    AmountTooLarge
+}
+
+impl fmt::Display for InputRowParseErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputRowParseErr::UnknownType => write!(f, "unknown transaction type"),
+            InputRowParseErr::BadAmount => write!(f, "amount must not be negative"),
+            InputRowParseErr::MissingAmount => write!(f, "amount is missing"),
+            InputRowParseErr::ZeroAmount => write!(f, "amount must not be zero"),
+            InputRowParseErr::FractionalMinorUnits => write!(f, "amount must be a whole number of minor units"),
+            InputRowParseErr::TooPrecise { scale } => write!(f, "amount has too many decimal places ({})", scale),
+            InputRowParseErr::AmountTooLarge => write!(f, "amount is too large"),
+        }
+    }
+}
+
+/// The number of decimal places amounts are rounded to unless configured otherwise
+pub const DEFAULT_DECIMALS: i64 = Money::DEFAULT_SCALE;
+
+impl InputRow {
+    /// Convert this row to a Transaction as with `try_from`, rounding the amount to
+    /// `decimals` decimal places with `rounding` and rejecting amounts with more than `max_scale`
+    pub fn into_transaction(self, max_scale: Option<i64>, max_amount: Option<&BigDecimal>, decimals: i64, rounding: RoundingMode) -> Result<Transaction, InputRowParseErr> {
+        if let (Some(max_scale), Some(amount)) = (max_scale, &self.amount) {
+            let (_, scale) = amount.as_bigint_and_exponent();
+            if scale > max_scale {
+                return Err(InputRowParseErr::TooPrecise { scale });
+            }
+        }
+        if let (Some(max_amount), Some(amount)) = (max_amount, &self.amount) {
+            if amount > max_amount {
+                return Err(InputRowParseErr::AmountTooLarge);
+            }
+        }
+        let transaction_type = match self.transaction_type.as_str() {
+            "deposit" => TransactionType::Deposit,
+            "withdrawal" => TransactionType::Withdrawal,
+            _ => return Err(InputRowParseErr::UnknownType)
+        };
+        let amount = match self.amount {
+            Some(result) => {
+                if result.is_negative() { return Err(InputRowParseErr::BadAmount); }
+                Money::round_with(&result, decimals, rounding)
+            },
+            None => return Err(InputRowParseErr::MissingAmount)
+        };
+        // A zero transaction would change nothing but still use up its ID
+        if amount.is_zero() {
+            return Err(InputRowParseErr::ZeroAmount);
+        }
+        Ok(Transaction {
+            timestamp: self.timestamp,
+            currency: self.currency,
+            ..Transaction::new(self.tx, self.client, amount, transaction_type)
+        })
+    }
+
+    /// Reinterpret the amount, given as a whole number of minor units (e.g. cents),
+    /// as a decimal with `scale` places, so that `1050` with a scale of 2 is `10.50`.
+    /// Interest rates are left as they are, since they aren't amounts of money
+    pub fn convert_minor_units(&mut self, scale: i64) -> Result<(), InputRowParseErr> {
+        if matches!(self.transaction_type.as_str(), "accrue" | "interest") {
+            return Ok(());
+        }
+        if let Some(amount) = &self.amount {
+            let (units, exponent) = amount.as_bigint_and_exponent();
+            if exponent > 0 {
+                return Err(InputRowParseErr::FractionalMinorUnits);
+            }
+            self.amount = Some(BigDecimal::new(units, exponent + scale));
+        }
+        Ok(())
+    }
+
+    /// The precision that converting this row to a transaction would lose by rounding
+    /// its amount to `decimals` decimal places with `rounding`: the parsed amount minus the
+    /// stored one. `None` if nothing is lost
+    pub fn precision_loss(&self, decimals: i64, rounding: RoundingMode) -> Option<BigDecimal> {
+        let amount = self.amount.as_ref()?;
+        let delta = amount - Money::round_with(amount, decimals, rounding).as_decimal();
+        (!delta.is_zero()).then_some(delta)
+    }
 }
 
 impl TryFrom<InputRow> for Transaction {
     type Error = InputRowParseErr;
-    /// Convert from an input row to a Transaction (withdrawal or deposit).
+    /// Convert from an input row to a Transaction (withdrawal or deposit),
+    /// rounding the amount to the default four decimal places.
     /// The conversion will fail if the amount is negative or if the
     /// row represents a dispute action
     fn try_from(row: InputRow) -> Result<Transaction, InputRowParseErr> {
-        Ok(Transaction {
-            id: row.tx,
-            client_id: row.client,
-            amount: match row.amount {
-                Some(result) => {
-                    if result < BigDecimal::new(Zero::zero(), 0) { return Err(InputRowParseErr::BadAmount); }
-                    result.round(4)
-                },
-                None => return Err(InputRowParseErr::UnknownType)
-            },
-            transaction_type: match row.transaction_type.as_str() {
-                "deposit" => TransactionType::Deposit,
-                "withdrawal" => TransactionType::Withdrawal,
-                _ => return Err(InputRowParseErr::UnknownType)
-            },
-            dispute_state: DisputeState::Undisputed
-        })
+        row.into_transaction(None, None, DEFAULT_DECIMALS, RoundingMode::default())
     }
 }
 
@@ -67,26 +319,284 @@ impl TryFrom<InputRow> for DisputeAction {
     }
 }
 
+impl TryFrom<InputRow> for AccountAction {
+    type Error = InputRowParseErr;
+    /// Convert from an input row to an account action (open, accrue, interest,
+    /// hold, or release). The conversion will fail if the row represents a transaction
+    /// or dispute action, or if an accrual or interest has a missing or negative rate,
+    /// or a hold or release a missing or negative amount.
+    fn try_from(row: InputRow) -> Result<AccountAction, InputRowParseErr> {
+        let action_type = match row.transaction_type.as_str() {
+            "open" => AccountActionType::Open,
+            "accrue" => AccountActionType::Accrue,
+            "interest" => AccountActionType::Interest,
+            "hold" => AccountActionType::Hold,
+            "release" => AccountActionType::Release,
+            _ => return Err(InputRowParseErr::UnknownType)
+        };
+        if action_type != AccountActionType::Open {
+            match &row.amount {
+                Some(rate) if rate.is_negative() => return Err(InputRowParseErr::BadAmount),
+                Some(_) => {},
+                None => return Err(InputRowParseErr::MissingAmount)
+            }
+        }
+        Ok(AccountAction {
+            client_id: row.client,
+            action_type,
+            amount: row.amount,
+        })
+    }
+}
+
+/// A row of opening balances to seed the accounts with before processing
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct OpeningBalanceRow {
+    #[serde(deserialize_with = "deserialize_client")]
+    pub client: u16,
+    #[serde(default, deserialize_with = "deserialize_amount")]
+    pub opening_balance: Option<BigDecimal>,
+}
+
 /// A structure representing an output row.
 /// This is always derived from an account
-#[derive(Clone, Serialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct OutputRow {
     pub client: u16,
+    #[serde(serialize_with = "serialize_plain_decimal", deserialize_with = "deserialize_decimal")]
     pub available: BigDecimal,
+    #[serde(serialize_with = "serialize_plain_decimal", deserialize_with = "deserialize_decimal")]
     pub held: BigDecimal,
+    #[serde(serialize_with = "serialize_plain_decimal", deserialize_with = "deserialize_decimal")]
     pub total: BigDecimal,
     pub locked: bool,
+    /// Why the account is locked (empty if it isn't). Only
+    /// present in verbose output
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lock_reason: Option<String>,
+    /// The input sequence number of the last row that changed the
+    /// account (empty if unknown). Only present in verbose output
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seq: Option<String>,
+    /// The number of transactions stored for the account. Only
+    /// present if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx_count: Option<usize>,
+    /// The account's net position, see `Account::net_position`. Only
+    /// present if requested
+    #[serde(default, skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_plain_decimal_option", deserialize_with = "deserialize_decimal_option")]
+    pub net: Option<BigDecimal>,
+    /// Whether the account's available balance is negative, as an overdraft
+    /// or withdrawal fee can leave it. Only present if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub negative: Option<bool>,
+    /// The account's currency (empty if it has none). Only present
+    /// if any account has a currency
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
 }
 
-impl From<Account> for OutputRow {
-    /// Convert the account state to an output row
-    fn from(account: Account) -> OutputRow {
+/// Write an amount in plain fixed-point notation with all of its decimal places,
+/// e.g. `1000000.0000` or `-2.5000`, rather than in whatever form `BigDecimal` picks
+pub fn plain_decimal(amount: &BigDecimal) -> String {
+    let (digits, scale) = amount.as_bigint_and_exponent();
+    let sign = if digits.is_negative() { "-" } else { "" };
+    let digits = digits.abs().to_string();
+    if scale <= 0 {
+        // A negative scale stands for trailing zeros
+        let zeros = if digits == "0" { 0 } else { scale.unsigned_abs() as usize };
+        return format!("{}{}{}", sign, digits, "0".repeat(zeros));
+    }
+    let scale = scale as usize;
+    let digits = format!("{:0>width$}", digits, width = scale + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - scale);
+    format!("{}{}.{}", sign, whole, fraction)
+}
+
+/// Serialize an amount as a string in plain fixed-point notation, see `plain_decimal`
+pub(crate) fn serialize_plain_decimal<S: Serializer>(amount: &BigDecimal, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&plain_decimal(amount))
+}
+
+/// Serialize an optional amount as a string in plain fixed-point notation, see `plain_decimal`
+fn serialize_plain_decimal_option<S: Serializer>(amount: &Option<BigDecimal>, serializer: S) -> Result<S::Ok, S::Error> {
+    match amount {
+        Some(amount) => serializer.serialize_some(&plain_decimal(amount)),
+        None => serializer.serialize_none()
+    }
+}
+
+/// Deserialize an amount from its exact decimal text, as `deserialize_amount` does
+fn deserialize_decimal<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BigDecimal, D::Error> {
+    BigDecimal::from_str(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+}
+
+/// Deserialize an optional amount from its exact decimal text. An empty column is no amount
+fn deserialize_decimal_option<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<BigDecimal>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(text) if !text.is_empty() => BigDecimal::from_str(&text).map(Some).map_err(de::Error::custom),
+        _ => Ok(None)
+    }
+}
+
+impl OutputRow {
+    /// Convert the account state to an output row, including the diagnostic
+    /// columns if `verbose` is set. Every money field has exactly `decimals` decimal places
+    pub fn from_account(account: Account, verbose: bool, decimals: i64) -> OutputRow {
         OutputRow {
             client: account.id,
-            total: &account.available_balance + &account.held_balance,
-            available: account.available_balance,
-            held: account.held_balance,
-            locked: account.is_frozen,
+            total: (&account.available_balance + &account.held_balance).rounded(decimals),
+            locked: account.is_frozen(),
+            lock_reason: verbose.then(|| account.freeze_reason.map(|reason| reason.to_string()).unwrap_or_default()),
+            last_seq: verbose.then(|| account.last_seq.map(|seq| seq.to_string()).unwrap_or_default()),
+            available: account.available_balance.rounded(decimals),
+            held: account.held_balance.rounded(decimals),
+            tx_count: None,
+            net: None,
+            negative: None,
+            currency: None,
+        }
+    }
+
+    /// Include the account's net position, with `decimals` decimal places
+    pub fn with_net(self, net: Money, decimals: i64) -> OutputRow {
+        OutputRow { net: Some(net.rounded(decimals)), ..self }
+    }
+
+    /// Include whether the account's available balance is negative
+    pub fn with_negative(self) -> OutputRow {
+        OutputRow { negative: Some(self.available.is_negative()), ..self }
+    }
+
+    /// Include the account's currency, which is empty if it has none
+    pub fn with_currency(self, currency: Option<String>) -> OutputRow {
+        OutputRow { currency: Some(currency.unwrap_or_default()), ..self }
+    }
+
+    /// Include the number of transactions stored for the account
+    pub fn with_tx_count(self, tx_count: usize) -> OutputRow {
+        OutputRow { tx_count: Some(tx_count), ..self }
+    }
+
+    /// Report only the available balance as the total, for consumers that
+    /// don't count held funds as part of the account's total
+    pub fn with_total_excluding_held(self) -> OutputRow {
+        OutputRow { total: self.available.clone(), ..self }
+    }
+}
+
+/// Which columns the output rows made from accounts have, and how their amounts are written
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputColumns {
+    /// Whether to include the diagnostic columns (`lock_reason` and `last_seq`)
+    pub verbose: bool,
+    /// Whether to include the number of transactions stored for each account
+    pub tx_count: bool,
+    /// Whether to include each account's net position
+    pub net: bool,
+    /// Whether to flag each account whose available balance is negative
+    pub negative: bool,
+    /// Whether to include each account's currency
+    pub currency: bool,
+    /// Whether the `total` is only the available balance, rather than the
+    /// available and held balances together
+    pub total_excludes_held: bool,
+    /// The number of decimal places every amount is written with
+    pub decimals: i64,
+}
+
+impl Default for OutputColumns {
+    fn default() -> Self {
+        OutputColumns { verbose: false, tx_count: false, net: false, negative: false, currency: false, total_excludes_held: false, decimals: DEFAULT_DECIMALS }
+    }
+}
+
+impl OutputColumns {
+    /// Convert the account state to an output row with these columns
+    pub fn row(&self, account: Account) -> OutputRow {
+        let tx_count = account.transactions.len();
+        let currency = account.currency.clone();
+        let net = account.net_position();
+        let output_row = OutputRow::from_account(account, self.verbose, self.decimals);
+        let output_row = if self.tx_count { output_row.with_tx_count(tx_count) } else { output_row };
+        let output_row = if self.net { output_row.with_net(net, self.decimals) } else { output_row };
+        let output_row = if self.negative { output_row.with_negative() } else { output_row };
+        let output_row = if self.currency { output_row.with_currency(currency) } else { output_row };
+        if self.total_excludes_held { output_row.with_total_excluding_held() } else { output_row }
+    }
+}
+
+impl From<Account> for OutputRow {
+    /// Convert the account state to an output row at the default four decimal places
+    fn from(account: Account) -> OutputRow {
+        OutputRow::from_account(account, false, DEFAULT_DECIMALS)
+    }
+}
+
+/// A structure representing one stored transaction in a
+/// transaction export, with its final dispute state
+#[derive(Clone, Serialize)]
+pub struct TransactionRow {
+    pub client: u16,
+    pub tx: u32,
+    #[serde(rename = "type")]
+    pub transaction_type: &'static str,
+    pub amount: Money,
+    pub dispute_state: &'static str,
+}
+
+impl From<&Transaction> for TransactionRow {
+    /// Convert a stored transaction to an export row
+    fn from(transaction: &Transaction) -> TransactionRow {
+        TransactionRow {
+            client: transaction.client_id,
+            tx: transaction.id,
+            transaction_type: match transaction.transaction_type {
+                TransactionType::Deposit => "deposit",
+                TransactionType::Withdrawal => "withdrawal",
+            },
+            amount: transaction.amount.clone(),
+            dispute_state: match transaction.dispute_state {
+                DisputeState::Undisputed => "undisputed",
+                DisputeState::Disputed => "disputed",
+                DisputeState::ChargedBack => "charged_back",
+            },
+        }
+    }
+}
+
+impl From<&Transaction> for InputRow {
+    /// Convert a transaction back to the input row it could have been read from
+    fn from(transaction: &Transaction) -> InputRow {
+        InputRow {
+            transaction_type: match transaction.transaction_type {
+                TransactionType::Deposit => "deposit",
+                TransactionType::Withdrawal => "withdrawal",
+            }.to_string(),
+            client: transaction.client_id,
+            tx: transaction.id,
+            amount: Some(transaction.amount.as_decimal().clone()),
+            timestamp: transaction.timestamp,
+            currency: transaction.currency.clone(),
+        }
+    }
+}
+
+impl From<&DisputeAction> for InputRow {
+    /// Convert a dispute action back to the input row it could have been read from
+    fn from(dispute_action: &DisputeAction) -> InputRow {
+        InputRow {
+            transaction_type: match dispute_action.action_type {
+                DisputeActionType::Dispute => "dispute",
+                DisputeActionType::Resolve => "resolve",
+                DisputeActionType::Chargeback => "chargeback",
+            }.to_string(),
+            client: dispute_action.client_id,
+            tx: dispute_action.transaction_id,
+            amount: None,
+            timestamp: None,
+            currency: None,
         }
     }
 }
@@ -95,6 +605,29 @@ impl From<Account> for OutputRow {
 mod tests {
     use super::*;
 
+    #[test]
+    fn normalizes_amounts_for_each_locale() {
+        assert_eq!(Locale::De.normalize_amount("1.234,56").as_deref(), Ok("1234.56"));
+        assert_eq!(Locale::De.normalize_amount("1.234.567").as_deref(), Ok("1234567"));
+        assert_eq!(Locale::De.normalize_amount("0,5").as_deref(), Ok("0.5"));
+        assert_eq!(Locale::En.normalize_amount("1,234.56").as_deref(), Ok("1234.56"));
+        assert_eq!(Locale::En.normalize_amount("-12,345").as_deref(), Ok("-12345"));
+        assert_eq!(Locale::En.normalize_amount("1234.56").as_deref(), Ok("1234.56"));
+        for (locale, text) in [(Locale::En, "1,23.4"), (Locale::En, "1,234,5"), (Locale::En, "1.2.3"), (Locale::En, ",123"),
+            (Locale::De, "1.5"), (Locale::De, "1,2,3"), (Locale::De, "1.234,"), (Locale::De, "1.234,5e3")] {
+            assert!(locale.normalize_amount(text).is_err(), "{:?} accepted {}", locale, text);
+        }
+    }
+
+    #[test]
+    fn localizes_the_amount_column() {
+        let mut record = csv::StringRecord::from(vec!["deposit", "1", "1", "1.234,56"]);
+        localize_amount(&mut record, Some(3), Locale::De).expect("Localizing failed");
+        assert_eq!(record, csv::StringRecord::from(vec!["deposit", "1", "1", "1234.56"]));
+        localize_amount(&mut record, None, Locale::De).expect("Localizing failed");
+        assert_eq!(record, csv::StringRecord::from(vec!["deposit", "1", "1", "1234.56"]));
+    }
+
     #[test]
     fn transaction_row_converts_to_transaction() {
         let input_row = InputRow {
@@ -102,6 +635,8 @@ mod tests {
             client: 1,
             tx: 1,
             amount: Some(12.into()),
+            timestamp: None,
+            currency: None,
         };
         let transaction: Transaction = input_row.try_into().expect("Parse failed");
         assert_eq!(transaction.transaction_type, TransactionType::Deposit);
@@ -118,6 +653,8 @@ mod tests {
             client: 1,
             tx: 1,
             amount: None,
+            timestamp: None,
+            currency: None,
         };
         let dispute_action: DisputeAction = input_row.try_into().expect("Parse failed");
         assert_eq!(dispute_action.action_type, DisputeActionType::Dispute);
@@ -132,11 +669,29 @@ mod tests {
             client: 1,
             tx: 1,
             amount: Some(12.into()),
+            timestamp: None,
+            currency: None,
         };
         let dispute_result: Result<DisputeAction, InputRowParseErr> = input_row.try_into();
         dispute_result.expect_err("Parse from transaction into dispute was allowed");
     }
 
+    #[test]
+    fn deposit_without_amount_is_missing_amount() {
+        let input_row: InputRow = "deposit, 1, 1,".parse().expect("Parse failed");
+        let transaction_result: Result<Transaction, InputRowParseErr> = input_row.try_into();
+        assert_eq!(transaction_result.expect_err("Deposit without an amount was allowed"), InputRowParseErr::MissingAmount);
+    }
+
+    #[test]
+    fn zero_deposits_and_withdrawals_are_rejected() {
+        for row in ["deposit, 1, 1, 0.0000", "withdrawal, 1, 2, 0", "deposit, 1, 3, 0.00001"] {
+            let input_row: InputRow = row.parse().expect("Parse failed");
+            let transaction_result: Result<Transaction, InputRowParseErr> = input_row.try_into();
+            assert_eq!(transaction_result.expect_err("Zero amount was allowed"), InputRowParseErr::ZeroAmount, "for row {}", row);
+        }
+    }
+
     #[test]
     fn dispute_row_does_not_convert_to_transaction() {
         let input_row = InputRow {
@@ -144,6 +699,8 @@ mod tests {
             client: 1,
             tx: 1,
             amount: None,
+            timestamp: None,
+            currency: None,
         };
         let transaction_result: Result<Transaction, InputRowParseErr> = input_row.try_into();
         transaction_result.expect_err("Parse from dispute into transaction was allowed");
@@ -154,16 +711,109 @@ mod tests {
         let account = Account {
             available_balance: 100.into(),
             held_balance: 10.into(),
-            id: 1,
-            is_frozen: false,
-            transactions: std::collections::HashMap::new()
+            ..Account::new(1)
         };
         let output_row: OutputRow = account.into();
         assert_eq!(output_row.client, 1);
         assert_eq!(output_row.available, 100.into());
         assert_eq!(output_row.held, 10.into());
         assert_eq!(output_row.total, 110.into());
-        assert_eq!(output_row.locked, false);
+        assert!(!output_row.locked);
+    }
+
+    #[test]
+    fn fractional_amount_round_trips_to_output_row() {
+        let input_row = InputRow {
+            transaction_type: "deposit".to_string(),
+            client: 1,
+            tx: 1,
+            amount: Some("1.2345".parse().unwrap()),
+            timestamp: None,
+            currency: None,
+        };
+        let transaction: Transaction = input_row.try_into().expect("Parse failed");
+        let mut account = Account::new(1);
+        account.register_transaction(transaction);
+        let output_row: OutputRow = account.into();
+        assert_eq!(output_row.available, "1.2345".parse().unwrap());
+        assert_eq!(output_row.total, "1.2345".parse().unwrap());
+    }
+
+    #[test]
+    fn output_row_serializes_amounts_as_json_strings() {
+        let account = Account { available_balance: "1.5000".parse().unwrap(), ..Account::new(1) };
+        let json = serde_json::to_string(&OutputRow::from(account)).expect("Serialize failed");
+        assert_eq!(json, r#"{"client":1,"available":"1.5000","held":"0.0000","total":"1.5000","locked":false}"#);
+    }
+
+    #[test]
+    fn output_row_amounts_always_have_four_decimal_places() {
+        for amount in ["5", "5.0", "5.00000"] {
+            let account = Account { available_balance: amount.parse().unwrap(), ..Account::new(1) };
+            let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(vec![]);
+            writer.serialize(OutputRow::from(account)).expect("Serialize failed");
+            let csv = String::from_utf8(writer.into_inner().expect("Flush failed")).unwrap();
+            assert_eq!(csv, "1,5.0000,0.0000,5.0000,false\n", "for amount {}", amount);
+        }
+    }
+
+    #[test]
+    fn writes_amounts_in_plain_decimal_notation() {
+        assert_eq!(plain_decimal(&"1E+6".parse().unwrap()), "1000000");
+        assert_eq!(plain_decimal(&"0E+3".parse().unwrap()), "0");
+        assert_eq!(plain_decimal(&"1.5E-6".parse().unwrap()), "0.0000015");
+        assert_eq!(plain_decimal(&"-0.05".parse().unwrap()), "-0.05");
+
+        // An overdrawn account has a negative available balance
+        let mut available_balance: Money = "1.5".parse().unwrap();
+        available_balance -= &"3".parse().unwrap();
+        let account = Account { available_balance, held_balance: "1e6".parse().unwrap(), ..Account::new(1) };
+        let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(vec![]);
+        writer.serialize(OutputRow::from(account)).expect("Serialize failed");
+        let csv = String::from_utf8(writer.into_inner().expect("Flush failed")).unwrap();
+        assert_eq!(csv, "1,-1.5000,1000000.0000,999998.5000,false\n");
+    }
+
+    #[test]
+    fn rounds_to_configured_decimals() {
+        let input_row: InputRow = "deposit, 1, 1, 1.2349".parse().expect("Parse failed");
+        assert_eq!(input_row.precision_loss(2, RoundingMode::default()), Some("0.0049".parse().unwrap()));
+        let transaction = input_row.into_transaction(None, None, 2, RoundingMode::default()).expect("Parse failed");
+        assert_eq!(transaction.amount.as_decimal().to_string(), "1.23");
+
+        let account = Account { available_balance: "1.2349".parse().unwrap(), ..Account::new(1) };
+        let output_row = OutputRow::from_account(account, false, 2);
+        assert_eq!(output_row.available.to_string(), "1.23");
+        assert_eq!(output_row.held.to_string(), "0.00");
+        assert_eq!(output_row.total.to_string(), "1.23");
+    }
+
+    #[test]
+    fn output_rows_round_trip_through_bincode() {
+        let accounts = [
+            Account { available_balance: "1.2345".parse().unwrap(), ..Account::new(1) },
+            Account { freeze_reason: Some(FreezeReason::Chargeback { tx: 3 }), ..Account::new(2) },
+        ];
+        let rows: Vec<OutputRow> = accounts.into_iter()
+            .map(|account| OutputRow::from_account(account, true, DEFAULT_DECIMALS).with_tx_count(2).with_net(Money::default(), DEFAULT_DECIMALS).with_negative().with_currency(None))
+            .collect();
+        let encoded = bincode::serialize(&rows).expect("Serialize failed");
+        let decoded: Vec<OutputRow> = bincode::deserialize(&encoded).expect("Deserialize failed");
+        assert_eq!(decoded, rows);
+    }
+
+    #[test]
+    fn total_can_exclude_held_funds() {
+        let account = Account {
+            available_balance: 100.into(),
+            held_balance: 10.into(),
+            ..Account::new(1)
+        };
+        let output_row: OutputRow = account.into();
+        assert_eq!(output_row.total, 110.into());
+        let output_row = output_row.with_total_excluding_held();
+        assert_eq!(output_row.total, 100.into());
+        assert_eq!(output_row.held, 10.into());
     }
 
     #[test]
@@ -171,12 +821,212 @@ mod tests {
         let account = Account {
             available_balance: 100.into(),
             held_balance: 10.into(),
-            id: 1,
-            is_frozen: true,
-            transactions: std::collections::HashMap::new()
+            freeze_reason: Some(FreezeReason::Chargeback { tx: 3 }),
+            ..Account::new(1)
         };
         let output_row: OutputRow = account.into();
         assert_eq!(output_row.client, 1);
-        assert_eq!(output_row.locked, true);
+        assert!(output_row.locked);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn reports_precision_lost_to_rounding() {
+        let row: InputRow = "deposit,1,1,1.23456".parse().expect("Parse failed");
+        assert_eq!(row.precision_loss(4, RoundingMode::default()), Some("-0.00004".parse().unwrap()));
+        let row: InputRow = "deposit,1,1,1.2340".parse().expect("Parse failed");
+        assert_eq!(row.precision_loss(4, RoundingMode::default()), None);
+    }
+
+    #[test]
+    fn amount_at_max_input_scale_is_accepted() {
+        let input_row = InputRow {
+            transaction_type: "deposit".to_string(),
+            client: 1,
+            tx: 1,
+            amount: Some("1.2345".parse().unwrap()),
+            timestamp: None,
+            currency: None,
+        };
+        let transaction = input_row.into_transaction(Some(4), None, DEFAULT_DECIMALS, RoundingMode::default()).expect("Parse failed");
+        assert_eq!(transaction.amount, "1.2345".parse().unwrap());
+    }
+
+    #[test]
+    fn amount_above_max_input_scale_is_rejected() {
+        let input_row = InputRow {
+            transaction_type: "deposit".to_string(),
+            client: 1,
+            tx: 1,
+            amount: Some("1.23456".parse().unwrap()),
+            timestamp: None,
+            currency: None,
+        };
+        let err = input_row.into_transaction(Some(4), None, DEFAULT_DECIMALS, RoundingMode::default()).expect_err("Too precise amount was allowed");
+        assert_eq!(err, InputRowParseErr::TooPrecise { scale: 5 });
+    }
+
+    #[test]
+    fn amount_above_max_amount_is_rejected() {
+        let max_amount: BigDecimal = "1000".parse().unwrap();
+        let row: InputRow = "withdrawal,1,1,1000.0001".parse().expect("Parse failed");
+        let err = row.into_transaction(None, Some(&max_amount), DEFAULT_DECIMALS, RoundingMode::default()).expect_err("Too large amount was allowed");
+        assert_eq!(err, InputRowParseErr::AmountTooLarge);
+        let row: InputRow = "withdrawal,1,1,1000".parse().expect("Parse failed");
+        let transaction = row.into_transaction(None, Some(&max_amount), DEFAULT_DECIMALS, RoundingMode::default()).expect("Parse failed");
+        assert_eq!(transaction.amount, 1000.into());
+    }
+
+    #[test]
+    fn amount_with_too_many_digits_is_malformed() {
+        let digits = "9".repeat(MAX_AMOUNT_DIGITS);
+        let row: InputRow = format!("deposit,1,1,{}", digits).parse().expect("Parse failed");
+        assert_eq!(row.amount, Some(digits.parse().unwrap()));
+        format!("deposit,1,1,{}9", digits).parse::<InputRow>().expect_err("Too long amount was allowed");
+        "deposit,1,1,1e1000000".parse::<InputRow>().expect_err("Huge exponent was allowed");
+    }
+
+    #[test]
+    fn verbose_output_row_reports_lock_reason() {
+        let mut account = Account::new(1);
+        let output_row = OutputRow::from_account(account.clone(), true, DEFAULT_DECIMALS);
+        assert_eq!(output_row.lock_reason, Some(String::new()));
+
+        account.freeze(FreezeReason::Chargeback { tx: 3 });
+        let output_row = OutputRow::from_account(account.clone(), true, DEFAULT_DECIMALS);
+        assert!(output_row.locked);
+        assert_eq!(output_row.lock_reason, Some("chargeback:3".to_string()));
+
+        let output_row: OutputRow = account.into();
+        assert_eq!(output_row.lock_reason, None);
+    }
+
+    #[test]
+    fn tx_count_column_is_only_written_when_requested() {
+        let serialize = |output_row: OutputRow| {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            writer.serialize(output_row).expect("Serialize failed");
+            String::from_utf8(writer.into_inner().unwrap()).unwrap()
+        };
+        let output_row: OutputRow = Account::new(1).into();
+        assert_eq!(serialize(output_row.clone()), "client,available,held,total,locked\n1,0.0000,0.0000,0.0000,false\n");
+        assert_eq!(serialize(output_row.with_tx_count(3)),
+            "client,available,held,total,locked,tx_count\n1,0.0000,0.0000,0.0000,false,3\n");
+    }
+
+    #[test]
+    fn ids_parse_leniently() {
+        let row: InputRow = "deposit, +42, 00042, 1.0".parse().expect("Parse failed");
+        assert_eq!(row.client, 42);
+        assert_eq!(row.tx, 42);
+        let row: InputRow = "deposit, 00042, +7, 1.0".parse().expect("Parse failed");
+        assert_eq!(row.client, 42);
+        assert_eq!(row.tx, 7);
+    }
+
+    #[test]
+    fn empty_ids_report_missing_column() {
+        let err = "deposit, , 1, 1.0".parse::<InputRow>().expect_err("Empty client was allowed");
+        assert!(err.to_string().contains("missing client id"), "unexpected error: {}", err);
+        let err = "deposit, 1, , 1.0".parse::<InputRow>().expect_err("Empty tx was allowed");
+        assert!(err.to_string().contains("missing tx id"), "unexpected error: {}", err);
+    }
+
+    #[test]
+    fn open_row_converts_to_account_action() {
+        let input_row: InputRow = "open, 3, 0,".parse().expect("Parse failed");
+        let account_action: AccountAction = input_row.clone().try_into().expect("Parse failed");
+        assert_eq!(account_action.action_type, AccountActionType::Open);
+        assert_eq!(account_action.client_id, 3);
+        let dispute_result: Result<DisputeAction, InputRowParseErr> = input_row.try_into();
+        dispute_result.expect_err("Parse from open into dispute was allowed");
+    }
+
+    #[test]
+    fn accrue_row_converts_to_account_action() {
+        let input_row: InputRow = "accrue, 3, 0, 0.015".parse().expect("Parse failed");
+        let account_action: AccountAction = input_row.try_into().expect("Parse failed");
+        assert_eq!(account_action.action_type, AccountActionType::Accrue);
+        assert_eq!(account_action.amount, Some("0.015".parse().unwrap()));
+
+        let input_row: InputRow = "accrue, 3, 0,".parse().expect("Parse failed");
+        let account_result: Result<AccountAction, InputRowParseErr> = input_row.try_into();
+        assert_eq!(account_result.expect_err("Accrual without a rate was allowed"), InputRowParseErr::MissingAmount);
+    }
+
+    #[test]
+    fn interest_row_converts_to_account_action() {
+        let input_row: InputRow = "interest, 3, 0, 0.015".parse().expect("Parse failed");
+        let account_action: AccountAction = input_row.try_into().expect("Parse failed");
+        assert_eq!(account_action.action_type, AccountActionType::Interest);
+        assert_eq!(account_action.amount, Some("0.015".parse().unwrap()));
+    }
+
+    #[test]
+    fn hold_and_release_rows_convert_to_account_actions() {
+        let input_row: InputRow = "hold, 3, 0, 2.5".parse().expect("Parse failed");
+        let account_action: AccountAction = input_row.try_into().expect("Parse failed");
+        assert_eq!(account_action.action_type, AccountActionType::Hold);
+        assert_eq!(account_action.amount, Some("2.5".parse().unwrap()));
+
+        let input_row: InputRow = "release, 3, 0, 2.5".parse().expect("Parse failed");
+        let account_action: AccountAction = input_row.try_into().expect("Parse failed");
+        assert_eq!(account_action.action_type, AccountActionType::Release);
+
+        for row in ["hold, 3, 0,", "release, 3, 0, -1"] {
+            let input_row: InputRow = row.parse().expect("Parse failed");
+            let account_result: Result<AccountAction, InputRowParseErr> = input_row.try_into();
+            account_result.expect_err("Hold or release without a valid amount was allowed");
+        }
+    }
+
+    #[test]
+    fn converts_minor_units_to_decimals() {
+        let mut input_row: InputRow = "deposit, 1, 1, 1050".parse().expect("Parse failed");
+        input_row.convert_minor_units(2).expect("Conversion failed");
+        let transaction: Transaction = input_row.try_into().expect("Parse failed");
+        assert_eq!(transaction.amount.to_string(), "10.5000");
+
+        let mut input_row: InputRow = "deposit, 1, 1, 10.50".parse().expect("Parse failed");
+        assert_eq!(input_row.convert_minor_units(2), Err(InputRowParseErr::FractionalMinorUnits));
+
+        // Rates are not amounts, so are left alone
+        let mut input_row: InputRow = "accrue, 1, 0, 0.015".parse().expect("Parse failed");
+        input_row.convert_minor_units(2).expect("Conversion failed");
+        assert_eq!(input_row.amount, Some("0.015".parse().unwrap()));
+    }
+
+    #[test]
+    fn transactions_round_trip_through_input_rows() {
+        for record in ["deposit, 1, 7, 12.5", "withdrawal, 65535, 4294967295, 0.0001, 2024-03-01T12:00:00Z"] {
+            let input_row: InputRow = record.parse().expect("Parse failed");
+            let transaction = Transaction::try_from(input_row.clone()).expect("Conversion failed");
+            // The amount is stored at four decimal places, but is still equal
+            assert_eq!(InputRow::from(&transaction), input_row);
+        }
+    }
+
+    #[test]
+    fn dispute_actions_round_trip_through_input_rows() {
+        for record in ["dispute, 1, 7", "resolve, 2, 8,", "chargeback, 3, 9"] {
+            let input_row: InputRow = record.parse().expect("Parse failed");
+            let dispute_action = DisputeAction::try_from(input_row.clone()).expect("Conversion failed");
+            assert_eq!(InputRow::from(&dispute_action), input_row);
+        }
+    }
+
+    #[test]
+    fn input_rows_serialize_with_input_columns() {
+        let transaction: Transaction = "deposit, 1, 7, 12.5".parse::<InputRow>().unwrap().try_into().unwrap();
+        let dispute_action: DisputeAction = "dispute, 1, 7".parse::<InputRow>().unwrap().try_into().unwrap();
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.serialize(InputRow::from(&transaction)).expect("Error when serializing record");
+        writer.serialize(InputRow::from(&dispute_action)).expect("Error when serializing record");
+        let written = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(written, "type,client,tx,amount,timestamp,currency\ndeposit,1,7,12.5000,,\ndispute,1,7,,,\n");
+
+        // and read back in as the same rows
+        let mut reader = crate::engine::csv_reader(written.as_bytes());
+        let rows: Vec<InputRow> = reader.deserialize().collect::<Result<_, _>>().expect("Parse failed");
+        assert_eq!(rows, vec![InputRow::from(&transaction), InputRow::from(&dispute_action)]);
+    }
+}
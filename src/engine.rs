@@ -0,0 +1,1678 @@
+use std::cell::Cell;
+use std::collections::{ BTreeMap, BTreeSet, HashMap, HashSet, VecDeque };
+use std::fmt;
+use std::io::{ BufRead, BufReader, Read, Write };
+use std::sync::{ mpsc, Mutex };
+
+use bigdecimal::BigDecimal;
+use chrono::{ DateTime, Duration, Utc };
+use csv::Trim;
+use num_traits::Signed;
+
+use crate::account::{ Account, DisputeOutcome, FreezeReason, TransactionOutcome };
+use crate::config::{ EngineConfig, ErrorPolicy, LimitPolicy, RatePolicy };
+use crate::error::AppError;
+use crate::csv_rows::{ localize_amount, missing_column, InputRow, Locale, InputRowParseErr, OpeningBalanceRow, OutputColumns, OutputRow, TransactionRow };
+use crate::history::HistoryBudget;
+use crate::money::{ Money, RoundingMode };
+use crate::throttle::Throttle;
+use crate::transaction::{ Transaction, TransactionType, DisputeAction, DisputeActionType, DisputeState, AccountAction, AccountActionType };
+
+/// The state accumulated by processing a list of transactions
+#[derive(Debug, Default)]
+pub struct Ledger {
+    /// The dictionary of accounts by account number
+    pub accounts: BTreeMap<u16, Account>,
+    /// The sum of the amounts of every deposit read, whether or not
+    /// it was applied, for checking against a control total
+    pub deposit_total: BigDecimal,
+    /// Resolves and chargebacks that arrived before the dispute they close,
+    /// by client and transaction ID, in arrival order
+    pub pending_disputes: HashMap<(u16, u32), VecDeque<DisputeActionType>>,
+    /// Counts of what happened to the rows read
+    pub stats: ProcessingStats,
+    /// The transactions whose amounts lost precision to rounding, if audited
+    pub precision_loss: PrecisionLoss,
+    /// The sum of the fees taken with the withdrawals applied
+    pub fees_collected: Money,
+    /// The IDs of the transactions applied to any account, if they must be unique
+    /// across accounts. Includes the transactions of accounts loaded from a snapshot
+    pub tx_ids: HashSet<u32>,
+    /// The timestamps of each client's deposits and withdrawals within the last second
+    /// of its latest one, if client rates are limited
+    pub client_rate_windows: HashMap<u16, VecDeque<DateTime<Utc>>>,
+    /// The clients that went over the rate limit at least once
+    pub over_rate_clients: BTreeSet<u16>,
+    /// The clients whose accounts hold each stored transaction, by transaction ID
+    pub tx_owners: TxOwners,
+    /// The memory budget for the transaction history of every account, if limited.
+    /// Kept across inputs, and created from `max_history_bytes` when the first
+    /// input is processed
+    pub history_budget: Option<HistoryBudget>,
+    /// Clients in the order they first appeared in the input. Clients may
+    /// appear here without an account, if none of their rows created one
+    pub first_seen: Vec<u16>,
+}
+
+/// An index of the clients whose accounts hold each stored transaction, so that the
+/// account a dispute action refers to can be found without searching every account.
+/// Clients may reuse each other's transaction IDs, so an ID may have several owners
+#[derive(Debug, Default)]
+pub struct TxOwners(HashMap<u32, Vec<u16>>);
+
+impl TxOwners {
+    /// Index the transactions held by `accounts`
+    pub fn from_accounts(accounts: &BTreeMap<u16, Account>) -> TxOwners {
+        let mut owners = TxOwners::default();
+        for account in accounts.values() {
+            for &tx in account.transactions.keys() {
+                owners.insert(tx, account.id);
+            }
+        }
+        owners
+    }
+
+    /// Note that the client's account now holds the transaction
+    pub fn insert(&mut self, tx: u32, client: u16) {
+        let owners = self.0.entry(tx).or_default();
+        if !owners.contains(&client) {
+            owners.push(client);
+        }
+    }
+
+    /// Note that the client's account no longer holds the transaction
+    pub fn remove(&mut self, tx: u32, client: u16) {
+        if let Some(owners) = self.0.get_mut(&tx) {
+            owners.retain(|&owner| owner != client);
+            if owners.is_empty() {
+                self.0.remove(&tx);
+            }
+        }
+    }
+
+    /// The client whose account holds the transaction, preferring `client`,
+    /// and otherwise the lowest client ID
+    pub fn owner(&self, client: u16, tx: u32) -> Option<u16> {
+        let owners = self.0.get(&tx)?;
+        if owners.contains(&client) { Some(client) } else { owners.iter().copied().min() }
+    }
+
+    /// Add the transactions indexed by another ledger's index
+    pub fn merge(&mut self, other: TxOwners) {
+        for (tx, clients) in other.0 {
+            for client in clients {
+                self.insert(tx, client);
+            }
+        }
+    }
+}
+
+/// The order accounts are listed in the output
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AccountOrder {
+    /// By client ID, ascending
+    #[default]
+    Client,
+    /// By the first row naming each client in the input. Accounts that were
+    /// loaded from a snapshot rather than seen in the input come last, by client ID
+    FirstSeen,
+}
+
+/// A tally of the precision lost by rounding input amounts to the configured decimal places
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PrecisionLoss {
+    /// The number of transactions whose amount was changed by rounding
+    pub count: u64,
+    /// The sum of the parsed amounts minus the stored amounts
+    pub total_delta: BigDecimal,
+}
+
+impl fmt::Display for PrecisionLoss {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} transactions lost precision to rounding, with a total delta of {}", self.count, self.total_delta)
+    }
+}
+
+/// Counts of the rows read and what was done with them
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProcessingStats {
+    /// Every data row read, whether or not it was valid
+    pub rows_read: u64,
+    /// Deposits and withdrawals applied to an account
+    pub transactions_applied: u64,
+    /// Disputes, resolves, and chargebacks that changed a transaction's dispute state
+    pub dispute_actions_applied: u64,
+    /// Opens and accruals applied to an account
+    pub account_actions_applied: u64,
+    /// Rows that could not be parsed, or had an invalid amount
+    pub ignored_malformed: u64,
+    /// Rows with a type that isn't recognized
+    pub ignored_unknown_type: u64,
+    /// Deposits and withdrawals reusing a transaction ID
+    pub ignored_duplicate_tx: u64,
+    /// Withdrawals for more than the available balance
+    pub ignored_insufficient_funds: u64,
+    /// Rows ignored for any other reason, such as a frozen account or
+    /// a dispute action that didn't apply
+    pub ignored_other: u64,
+}
+
+impl ProcessingStats {
+    /// Add the counts from another set of stats to these
+    pub fn merge(&mut self, other: &ProcessingStats) {
+        self.rows_read += other.rows_read;
+        self.transactions_applied += other.transactions_applied;
+        self.dispute_actions_applied += other.dispute_actions_applied;
+        self.account_actions_applied += other.account_actions_applied;
+        self.ignored_malformed += other.ignored_malformed;
+        self.ignored_unknown_type += other.ignored_unknown_type;
+        self.ignored_duplicate_tx += other.ignored_duplicate_tx;
+        self.ignored_insufficient_funds += other.ignored_insufficient_funds;
+        self.ignored_other += other.ignored_other;
+    }
+}
+
+impl fmt::Display for ProcessingStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Read {} rows: applied {} transactions, {} dispute actions, and {} account actions; \
+            ignored {} malformed, {} unknown type, {} duplicate tx, {} insufficient funds, and {} other",
+            self.rows_read, self.transactions_applied, self.dispute_actions_applied, self.account_actions_applied,
+            self.ignored_malformed, self.ignored_unknown_type, self.ignored_duplicate_tx,
+            self.ignored_insufficient_funds, self.ignored_other)
+    }
+}
+
+impl Ledger {
+    /// Combine the results of processing another input into this ledger. Accounts
+    /// appearing in both are merged with `Account::merge`
+    pub fn merge(&mut self, other: Ledger) {
+        for (client, account) in other.accounts {
+            match self.accounts.get_mut(&client) {
+                Some(existing) => existing.merge(account),
+                None => { self.accounts.insert(client, account); }
+            }
+        }
+        self.deposit_total += other.deposit_total;
+        self.stats.merge(&other.stats);
+        self.precision_loss.count += other.precision_loss.count;
+        self.precision_loss.total_delta += other.precision_loss.total_delta;
+        self.fees_collected += other.fees_collected;
+        self.tx_ids.extend(other.tx_ids);
+        self.tx_owners.merge(other.tx_owners);
+        match (&mut self.history_budget, other.history_budget) {
+            (Some(budget), Some(other_budget)) => budget.merge(other_budget),
+            (budget @ None, other_budget) => *budget = other_budget,
+            (Some(_), None) => {}
+        }
+        self.client_rate_windows.extend(other.client_rate_windows);
+        self.over_rate_clients.extend(other.over_rate_clients);
+        for (key, actions) in other.pending_disputes {
+            self.pending_disputes.entry(key).or_default().extend(actions);
+        }
+        let mut seen: HashSet<u16> = self.first_seen.iter().copied().collect();
+        for client in other.first_seen {
+            if seen.insert(client) {
+                self.first_seen.push(client);
+            }
+        }
+    }
+
+    /// Give the client's account an opening balance carried over from elsewhere, on top
+    /// of anything it already has, creating and opening the account if need be. The
+    /// balance is not a transaction, so it can't be disputed
+    pub fn seed_account(&mut self, client: u16, opening_balance: &Money, config: &EngineConfig) {
+        let account = self.accounts.entry(client).or_insert_with(|| {
+            let mut account = Account::new(client);
+            config.configure_account(&mut account);
+            account
+        });
+        account.available_balance += opening_balance;
+        account.is_open = true;
+    }
+
+    /// Take the accounts out of the ledger, listed in the given order
+    pub fn into_accounts(mut self, order: AccountOrder) -> Vec<Account> {
+        let mut ordered = Vec::with_capacity(self.accounts.len());
+        if order == AccountOrder::FirstSeen {
+            ordered.extend(self.first_seen.iter().filter_map(|client| self.accounts.remove(client)));
+        }
+        ordered.extend(self.accounts.into_values());
+        ordered
+    }
+
+    /// Take the accounts out of the ledger as output rows with the given columns,
+    /// listed in the given order. Each row is only made as it is reached, so that
+    /// the rows can be streamed to their destination
+    pub fn into_output_rows(self, order: AccountOrder, columns: OutputColumns) -> impl Iterator<Item = OutputRow> {
+        self.into_accounts(order).into_iter().map(move |account| columns.row(account))
+    }
+}
+
+/// Read and apply every transaction row from the reader
+pub fn process<R: Read>(reader: &mut csv::Reader<R>, config: &EngineConfig) -> Result<Ledger, AppError> {
+    let mut ledger = Ledger::default();
+    process_into(reader, config, &mut ledger)?;
+    Ok(ledger)
+}
+
+/// Read and apply every transaction row from raw CSV input with the standard
+/// settings (see `csv_reader`), returning the final state of each account
+pub fn process_reader(config: &EngineConfig, reader: impl Read) -> Result<BTreeMap<u16, Account>, AppError> {
+    Ok(process(&mut csv_reader(reader), config)?.accounts)
+}
+
+/// Apply every row in turn, exactly as if they had been read from CSV input, without
+/// needing a CSV reader. Returns the final state of each account
+pub fn process_rows(rows: impl IntoIterator<Item = InputRow>, config: &EngineConfig) -> Result<BTreeMap<u16, Account>, AppError> {
+    let mut ledger = Ledger::default();
+    process_rows_into(rows.into_iter().map(Ok), config, &mut ledger)?;
+    Ok(ledger.accounts)
+}
+
+/// Read each client's opening balance from rows of `client,opening_balance`, rounded to
+/// `decimals` places with `rounding`. Fails on the first row that is malformed or has a
+/// negative balance
+pub fn read_opening_balances<R: Read>(reader: &mut csv::Reader<R>, decimals: i64, rounding: RoundingMode) -> Result<Vec<(u16, Money)>, AppError> {
+    let mut balances = Vec::new();
+    for (seq, result) in (1..).zip(reader.deserialize::<OpeningBalanceRow>()) {
+        let row = result.map_err(AppError::ReadRow)?;
+        match row.opening_balance {
+            Some(balance) if balance.is_negative() => {
+                return Err(AppError::BadRow(seq, format!("opening balance of client {} must not be negative", row.client)));
+            },
+            Some(balance) => balances.push((row.client, Money::round_with(&balance, decimals, rounding))),
+            None => return Err(AppError::BadRow(seq, format!("opening balance of client {} is missing", row.client)))
+        }
+    }
+    Ok(balances)
+}
+
+/// The field delimiter of CSV input and output, unless another is given
+pub const DEFAULT_DELIMITER: u8 = b',';
+
+/// Open an input file (or URL) with the standard CSV settings
+pub fn open_reader(location: &str) -> Result<csv::Reader<Box<dyn Read>>, AppError> {
+    open_reader_with_delimiter(location, DEFAULT_DELIMITER)
+}
+
+/// Open an input file (or URL) with the standard CSV settings, other than
+/// separating fields with `delimiter`
+pub fn open_reader_with_delimiter(location: &str, delimiter: u8) -> Result<csv::Reader<Box<dyn Read>>, AppError> {
+    let input = crate::input::open_input(location)
+        .map_err(|err| AppError::OpenFile(location.to_string(), err))?;
+    Ok(csv_reader_with_delimiter(input, delimiter))
+}
+
+/// Read CSV input with the standard settings: a header row, trimmed fields,
+/// and rows that may omit the trailing `amount` column
+pub fn csv_reader<R: Read>(input: R) -> csv::Reader<R> {
+    csv_reader_with_delimiter(input, DEFAULT_DELIMITER)
+}
+
+/// Read CSV input with the standard settings, other than separating fields with `delimiter`
+pub fn csv_reader_with_delimiter<R: Read>(input: R, delimiter: u8) -> csv::Reader<R> {
+    csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .flexible(true)
+        .trim(Trim::All)
+        .from_reader(input)
+}
+
+/// Process several input files, each on its own, using up to `threads` worker
+/// threads, and merge the results. This is intended for files with disjoint sets
+/// of clients; accounts that do appear in several files are merged with
+/// `Account::merge`, so the result may differ from processing the files in order.
+/// Any history budget is split evenly between the files. Fields are separated by `delimiter`.
+pub fn process_files_parallel(locations: &[String], config: &EngineConfig, threads: usize, delimiter: u8) -> Result<Ledger, AppError> {
+    let next_file = Mutex::new(locations.iter());
+    let ledgers: Vec<Result<Ledger, AppError>> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.max(1).min(locations.len())).map(|_| scope.spawn(|| {
+            let mut ledger = Ledger::default();
+            loop {
+                let location = match next_file.lock().expect("Worker panicked").next() {
+                    Some(location) => location,
+                    None => return Ok(ledger)
+                };
+                let mut file_ledger = Ledger {
+                    history_budget: config.max_history_bytes.map(|bytes| HistoryBudget::new(bytes / locations.len())),
+                    ..Ledger::default()
+                };
+                process_into(&mut open_reader_with_delimiter(location, delimiter)?, config, &mut file_ledger)?;
+                ledger.merge(file_ledger);
+            }
+        })).collect();
+        workers.into_iter().map(|worker| worker.join().expect("Worker panicked")).collect()
+    });
+
+    let mut merged = Ledger::default();
+    for ledger in ledgers {
+        merged.merge(ledger?);
+    }
+    Ok(merged)
+}
+
+/// Read and apply every transaction row from the reader to an existing ledger
+pub fn process_into<R: Read>(reader: &mut csv::Reader<R>, config: &EngineConfig, ledger: &mut Ledger) -> Result<(), AppError> {
+    check_headers(reader)?;
+    process_rows_into(csv_rows(reader, config.locale), config, ledger)
+}
+
+/// Read and apply every transaction row from JSON lines input, one object per line
+/// with the same fields as a CSV row, to an existing ledger. Blank lines are skipped
+pub fn process_jsonl_into(input: impl Read, config: &EngineConfig, ledger: &mut Ledger) -> Result<(), AppError> {
+    process_rows_into(jsonl_rows(BufReader::new(input), config.locale), config, ledger)
+}
+
+/// Why a row couldn't be read
+pub(crate) enum RowError {
+    /// The row itself is malformed, and is handled according to the error policy
+    Malformed(String),
+    /// The input can't be read any further
+    Fatal(AppError),
+}
+
+impl From<csv::Error> for RowError {
+    /// Malformed fields only make that row malformed, but any other failure is fatal
+    fn from(err: csv::Error) -> RowError {
+        match err.kind() {
+            csv::ErrorKind::Deserialize { .. } => RowError::Malformed(err.to_string()),
+            _ => RowError::Fatal(AppError::ReadRow(err))
+        }
+    }
+}
+
+/// The rows of CSV input, once its header row has been read, with amounts written in `locale`
+fn csv_rows<R: Read>(reader: &mut csv::Reader<R>, locale: Locale) -> impl Iterator<Item = Result<InputRow, RowError>> + '_ {
+    let headers = reader.headers().cloned().unwrap_or_default();
+    let amount_column = headers.iter().position(|header| header == "amount");
+    reader.records().map(move |record| {
+        let mut record = record?;
+        localize_amount(&mut record, amount_column, locale).map_err(RowError::Malformed)?;
+        Ok(record.deserialize(Some(&headers))?)
+    })
+}
+
+/// The rows of JSON lines input. Numbers are read as their decimal text, as CSV fields are,
+/// but an amount written as a JSON number only keeps the precision of an f64, so amounts are
+/// best written as strings. Amounts written as strings are read as `locale` writes them
+fn jsonl_rows(input: impl BufRead, locale: Locale) -> impl Iterator<Item = Result<InputRow, RowError>> {
+    input.lines()
+        .filter(|line| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+        .map(move |line| {
+            let line = line.map_err(|err| RowError::Fatal(AppError::ReadRow(err.into())))?;
+            let mut row: serde_json::Value = serde_json::from_str(&line)
+                .map_err(|err| RowError::Malformed(err.to_string()))?;
+            if let serde_json::Value::Object(fields) = &mut row {
+                // Only amounts written as strings are in the locale; JSON numbers always use a point
+                if let Some(serde_json::Value::String(amount)) = fields.get_mut("amount") {
+                    *amount = locale.normalize_amount(amount).map_err(RowError::Malformed)?.into_owned();
+                }
+                for value in fields.values_mut() {
+                    if let serde_json::Value::Number(number) = value {
+                        *value = serde_json::Value::String(number.to_string());
+                    }
+                }
+            }
+            serde_json::from_value::<InputRow>(row).map_err(|err| RowError::Malformed(err.to_string()))
+        })
+}
+
+/// Apply every row, or the error reading it, in turn to an existing ledger
+fn process_rows_into(rows: impl IntoIterator<Item = Result<InputRow, RowError>>, config: &EngineConfig, ledger: &mut Ledger) -> Result<(), AppError> {
+    if ledger.history_budget.is_none() {
+        ledger.history_budget = config.max_history_bytes.map(|bytes| budget_for(bytes, ledger));
+    }
+    let mut seen: HashSet<u16> = ledger.first_seen.iter().copied().collect();
+    let clients_seen = Cell::new(seen.len());
+    let progress = Progress { rows_before: ledger.stats.rows_read, clients_seen: &clients_seen };
+    let read_stats = dispatch_rows(rows, config, &progress, |seq, input_row, action| {
+        if seen.insert(input_row.client) {
+            ledger.first_seen.push(input_row.client);
+            clients_seen.set(seen.len());
+        }
+        apply_row(ledger, input_row, action, seq, config)
+    })?;
+    ledger.stats.merge(&read_stats);
+    Ok(())
+}
+
+/// A history budget of `max_bytes` that tracks the existing history of the ledger's
+/// accounts, pruning it straight away if it is already over budget
+fn budget_for(max_bytes: usize, ledger: &mut Ledger) -> HistoryBudget {
+    let mut budget = HistoryBudget::with_history(max_bytes, &ledger.accounts);
+    for (client, tx) in budget.prune(&mut ledger.accounts) {
+        ledger.tx_owners.remove(tx, client);
+    }
+    budget
+}
+
+/// Read and apply every transaction row from each reader in turn to an existing
+/// ledger, spreading the accounts across `threads` worker threads. Rows are read on
+/// the calling thread and sent to the worker for `client % threads`, so each account
+/// is owned by exactly one worker and sees its rows in input order. Any history budget
+/// is split evenly between the workers.
+pub fn process_sharded<R: Read>(
+    readers: impl IntoIterator<Item = Result<csv::Reader<R>, AppError>>,
+    config: &EngineConfig,
+    threads: usize,
+    ledger: Ledger
+) -> Result<Ledger, AppError> {
+    let threads = threads.max(1);
+    // Hand each worker the existing accounts it will own
+    let mut shards: Vec<Ledger> = (0..threads).map(|_| Ledger::default()).collect();
+    for (client, account) in ledger.accounts {
+        shards[usize::from(client) % threads].accounts.insert(client, account);
+    }
+    for (key, actions) in ledger.pending_disputes {
+        shards[usize::from(key.0) % threads].pending_disputes.insert(key, actions);
+    }
+    // Each worker tracks the history of its own accounts, with an equal share of the budget
+    for shard in &mut shards {
+        shard.tx_owners = TxOwners::from_accounts(&shard.accounts);
+        shard.history_budget = config.max_history_bytes.map(|bytes| budget_for(bytes / threads, shard));
+    }
+    let mut merged = Ledger {
+        accounts: BTreeMap::new(),
+        pending_disputes: HashMap::new(),
+        tx_owners: TxOwners::default(),
+        history_budget: None,
+        ..ledger
+    };
+    // The workers each only see some of the clients, so track the order they appear in here
+    let mut seen: HashSet<u16> = merged.first_seen.iter().copied().collect();
+    let clients_seen = Cell::new(seen.len());
+
+    std::thread::scope(|scope| {
+        let (senders, workers): (Vec<_>, Vec<_>) = shards.into_iter().map(|mut ledger| {
+            let (sender, receiver) = mpsc::sync_channel::<(u64, InputRow, RowAction)>(SHARD_QUEUE_LEN);
+            let worker = scope.spawn(move || {
+                for (seq, input_row, action) in receiver {
+                    apply_row(&mut ledger, input_row, action, seq, config);
+                }
+                ledger
+            });
+            (sender, worker)
+        }).unzip();
+
+        let mut read_stats = ProcessingStats::default();
+        let result = readers.into_iter().try_for_each(|reader| {
+            let progress = Progress { rows_before: merged.stats.rows_read + read_stats.rows_read, clients_seen: &clients_seen };
+            read_stats.merge(&read_rows(&mut reader?, config, &progress, |seq, input_row, action| {
+                if seen.insert(input_row.client) {
+                    merged.first_seen.push(input_row.client);
+                    clients_seen.set(seen.len());
+                }
+                let shard = usize::from(input_row.client) % threads;
+                senders[shard].send((seq, input_row, action)).expect("Worker panicked");
+            })?);
+            Ok(())
+        });
+        // Close the channels so the workers finish once they've drained them
+        drop(senders);
+
+        for worker in workers {
+            merged.merge(worker.join().expect("Worker panicked"));
+        }
+        merged.stats.merge(&read_stats);
+        result.map(|_| merged)
+    })
+}
+
+/// The number of rows that may be waiting for each worker of `process_sharded`
+const SHARD_QUEUE_LEN: usize = 1024;
+
+/// An action recognized in an input row
+pub(crate) enum RowAction {
+    Transaction(Transaction),
+    Dispute(DisputeAction),
+    Account(AccountAction),
+}
+
+/// Work out which action a row specifies: a transaction, then a dispute
+/// action, then an account action. Fails if the row specifies none of them.
+/// An amount given in minor units is first converted to a decimal in place
+pub(crate) fn parse_row(input_row: &mut InputRow, config: &EngineConfig) -> Result<RowAction, InputRowParseErr> {
+    if let Some(scale) = config.minor_units {
+        input_row.convert_minor_units(scale)?;
+    }
+    let transaction_err = match input_row.clone().into_transaction(config.max_input_scale, config.max_amount.as_ref(), config.decimals, config.rounding) {
+        Ok(transaction) => return Ok(RowAction::Transaction(transaction)),
+        // The row is a transaction, just not a valid one
+        Err(err @ (InputRowParseErr::TooPrecise { .. } | InputRowParseErr::AmountTooLarge | InputRowParseErr::MissingAmount | InputRowParseErr::ZeroAmount)) => return Err(err),
+        Err(err) => err
+    };
+    if let Ok(dispute_action) = DisputeAction::try_from(input_row.clone()) {
+        return Ok(RowAction::Dispute(dispute_action));
+    }
+    match AccountAction::try_from(input_row.clone()) {
+        Ok(account_action) => Ok(RowAction::Account(account_action)),
+        Err(InputRowParseErr::UnknownType) => Err(transaction_err),
+        Err(err) => Err(err)
+    }
+}
+
+/// Describe why a row was rejected, naming the row by its type and transaction ID
+pub(crate) fn describe_row_problem(input_row: &InputRow, err: InputRowParseErr, config: &EngineConfig) -> String {
+    let problem = match err {
+        InputRowParseErr::TooPrecise { scale } => format!("amount has {} decimal places, more than the maximum of {}",
+            scale, config.max_input_scale.unwrap_or_default()),
+        InputRowParseErr::AmountTooLarge => format!("amount {} is more than the maximum of {}",
+            input_row.amount.clone().unwrap_or_default(), config.max_amount.clone().unwrap_or_default()),
+        err => err.to_string()
+    };
+    format!("{} {}: {}", input_row.transaction_type, input_row.tx, problem)
+}
+
+/// Deal with a row that can't be applied as the error policy says: drop it,
+/// log a warning and drop it, or stop processing
+fn reject_row(policy: ErrorPolicy, seq: u64, problem: impl fmt::Display) -> Result<(), AppError> {
+    match policy {
+        ErrorPolicy::Skip => Ok(()),
+        ErrorPolicy::Warn => {
+            log::warn!("Ignoring row {}: {}", seq, problem);
+            Ok(())
+        },
+        ErrorPolicy::Abort => Err(AppError::BadRow(seq, problem.to_string()))
+    }
+}
+
+/// Check that the input's header row names every required column, so that a
+/// missing column is reported once rather than as a failure of every row.
+/// Empty input, without even a header row, passes
+pub fn check_headers<R: Read>(reader: &mut csv::Reader<R>) -> Result<(), AppError> {
+    let headers = reader.headers().map_err(AppError::ReadRow)?;
+    match missing_column(headers) {
+        Some(column) if !headers.is_empty() => Err(AppError::MissingColumn(column.to_string())),
+        _ => Ok(())
+    }
+}
+
+/// Read each transaction row from the reader, numbering the rows from 1, and pass it
+/// with its action to `apply`. Rows that are malformed or specify no action are handled
+/// according to the error policy. Returns the count of rows read and of rows rejected
+fn read_rows<R: Read>(
+    reader: &mut csv::Reader<R>,
+    config: &EngineConfig,
+    progress: &Progress,
+    apply: impl FnMut(u64, InputRow, RowAction)
+) -> Result<ProcessingStats, AppError> {
+    check_headers(reader)?;
+    dispatch_rows(csv_rows(reader, config.locale), config, progress, apply)
+}
+
+/// How far processing has got before the current input, for reporting progress
+struct Progress<'a> {
+    /// The rows read from earlier inputs
+    rows_before: u64,
+    /// The number of clients seen so far, kept up to date as rows are applied
+    clients_seen: &'a Cell<usize>,
+}
+
+/// Pass each row, numbering the rows from 1, with its action to `apply`, as `read_rows` does
+/// with the rows it reads. `rows` gives either each row or the error reading it.
+/// Every `progress_every` rows, if set, the rows read and clients seen so far are reported
+fn dispatch_rows(
+    rows: impl IntoIterator<Item = Result<InputRow, RowError>>,
+    config: &EngineConfig,
+    progress: &Progress,
+    mut apply: impl FnMut(u64, InputRow, RowAction)
+) -> Result<ProcessingStats, AppError> {
+    let mut throttle = config.replay_speed.map(Throttle::new);
+    let mut stats = ProcessingStats::default();
+    for (seq, result) in (1..).zip(rows) {
+        // The limit covers every input, not just this one
+        if let Some(max_rows) = config.max_rows.filter(|&max_rows| progress.rows_before + seq > max_rows) {
+            match config.on_limit {
+                LimitPolicy::Abort => return Err(AppError::TooManyRows(max_rows)),
+                LimitPolicy::Truncate => {
+                    log::warn!("Ignoring the rest of the input: it has more than the limit of {} rows", max_rows);
+                    break;
+                }
+            }
+        }
+        if let Some(throttle) = &mut throttle {
+            throttle.wait();
+        }
+        stats.rows_read += 1;
+        match result {
+            // Rows outside the time window are left out, as if they weren't in the input
+            Ok(input_row) if !config.in_window(input_row.timestamp) => {
+                stats.ignored_other += 1;
+                log::debug!("Ignoring row {}: outside the time window", seq);
+            },
+            Ok(mut input_row) => match parse_row(&mut input_row, config) {
+                Ok(action) => apply(seq, input_row, action),
+                Err(err) => {
+                    match err {
+                        InputRowParseErr::UnknownType => stats.ignored_unknown_type += 1,
+                        InputRowParseErr::BadAmount | InputRowParseErr::MissingAmount | InputRowParseErr::ZeroAmount
+                            | InputRowParseErr::FractionalMinorUnits => stats.ignored_malformed += 1,
+                        InputRowParseErr::TooPrecise { .. } | InputRowParseErr::AmountTooLarge => stats.ignored_other += 1,
+                    }
+                    reject_row(config.on_error, seq, describe_row_problem(&input_row, err, config))?;
+                }
+            },
+            // Reject rows with malformed fields, but always treat I/O failures as fatal
+            Err(RowError::Malformed(problem)) => {
+                stats.ignored_malformed += 1;
+                reject_row(config.on_error, seq, problem)?;
+            },
+            Err(RowError::Fatal(err)) => return Err(err)
+        }
+        let rows_read = progress.rows_before + stats.rows_read;
+        if config.progress_every.is_some_and(|every| rows_read.is_multiple_of(every)) {
+            eprintln!("Processed {} rows, {} clients seen so far", rows_read, progress.clients_seen.get());
+        }
+    }
+    Ok(stats)
+}
+
+/// Apply the action of a single transaction row, the `seq`th of its input, to the ledger
+fn apply_row(ledger: &mut Ledger, input_row: InputRow, mut action: RowAction, seq: u64, config: &EngineConfig) {
+    let mut client = input_row.client;
+
+    // Redirect or ignore dispute actions that name a different client than the transaction belongs to
+    if let RowAction::Dispute(dispute_action) = &mut action {
+        if let Some(owner) = ledger.tx_owners.owner(client, dispute_action.transaction_id) {
+            if owner != client && config.lenient_dispute_client {
+                log::warn!("Applying {} of tx {} for client {} to client {}, which the transaction belongs to",
+                    input_row.transaction_type, dispute_action.transaction_id, client, owner);
+                client = owner;
+                dispute_action.client_id = owner;
+            } else if owner != client {
+                log::warn!("Ignoring {} of tx {} for client {}: the transaction belongs to client {}",
+                    input_row.transaction_type, dispute_action.transaction_id, client, owner);
+                ledger.stats.ignored_other += 1;
+                return;
+            }
+        }
+    }
+
+    let is_new_account = !ledger.accounts.contains_key(&client);
+    let mut keeps_account = false;
+    
+    // Load the account, creating it if it does not exist
+    let account: &mut Account = ledger.accounts.entry(client)
+        .or_insert_with(|| {
+            let mut account = Account::new(client);
+            config.configure_account(&mut account);
+            account
+        });
+    let tracing = config.trace == Some(input_row.tx);
+    if tracing {
+        account.trace_log.push(format!("considering {} row for client {}", input_row.transaction_type, client));
+    }
+    let marker = config.track_last_seq.then(|| change_marker(account));
+
+    match action {
+        RowAction::Transaction(transaction) => {
+            if let Some(delta) = input_row.precision_loss(config.decimals, config.rounding) {
+                // Rounding away significant digits changes the amount, which may surprise
+                if let Some(original) = &input_row.amount {
+                    log::info!("Rounding amount {} of tx {} to {} decimal places: {}", original, transaction.id, config.decimals, transaction.amount);
+                }
+                if config.audit_precision_loss {
+                    ledger.precision_loss.count += 1;
+                    ledger.precision_loss.total_delta += delta;
+                }
+            }
+            if transaction.transaction_type == TransactionType::Deposit {
+                ledger.deposit_total += transaction.amount.as_decimal();
+            }
+            let history_len = account.transactions.len();
+            let recorded = ledger.history_budget.as_ref().map(|_| transaction.clone());
+            let attempted = transaction.amount.clone();
+            let transaction_type = transaction.transaction_type;
+            let id = transaction.id;
+            let over_rate = match (config.max_client_rate, transaction.timestamp) {
+                (Some(max_rate), Some(timestamp)) => {
+                    let window = ledger.client_rate_windows.entry(client).or_default();
+                    exceeds_rate(window, timestamp, max_rate)
+                },
+                _ => false
+            };
+            if over_rate && ledger.over_rate_clients.insert(client) {
+                log::info!("Client {} went over the limit of {} transactions per second at row {}",
+                    client, config.max_client_rate.unwrap_or_default(), seq);
+            }
+            let outcome = if config.enforce_unique_tx && ledger.tx_ids.contains(&id) {
+                log::warn!("Ignoring row {}: tx {} was already used by another transaction", seq, id);
+                TransactionOutcome::RejectedDuplicate
+            } else if over_rate && config.on_client_rate == RatePolicy::Reject {
+                TransactionOutcome::RejectedRate
+            } else {
+                account.register_transaction(transaction)
+            };
+            match outcome {
+                TransactionOutcome::Applied => {
+                    ledger.stats.transactions_applied += 1;
+                    ledger.tx_owners.insert(id, client);
+                    if config.enforce_unique_tx {
+                        ledger.tx_ids.insert(id);
+                    }
+                    if transaction_type == TransactionType::Withdrawal {
+                        ledger.fees_collected += &config.withdrawal_fee;
+                    }
+                    keeps_account = true;
+                },
+                TransactionOutcome::RejectedDuplicate => {
+                    ledger.stats.ignored_duplicate_tx += 1;
+                    log::debug!("Ignoring row {}: tx {} was already used", seq, input_row.tx);
+                },
+                TransactionOutcome::RejectedInsufficientFunds => {
+                    ledger.stats.ignored_insufficient_funds += 1;
+                    log::info!("Withdrawal {} for client {} rejected: {} exceeds the available balance of {}",
+                        input_row.tx, client, attempted, account.available_balance);
+                },
+                TransactionOutcome::RejectedCurrency => {
+                    ledger.stats.ignored_other += 1;
+                    log::warn!("Ignoring row {}: {} {} is not in the currency of client {}, {}", seq, input_row.transaction_type,
+                        input_row.tx, client, account.currency.as_deref().unwrap_or_default());
+                },
+                TransactionOutcome::RejectedRate => {
+                    ledger.stats.ignored_other += 1;
+                    log::debug!("Ignoring row {}: client {} is over the rate limit", seq, client);
+                },
+                // A freeze carried over from a previous run is easy to forget about
+                TransactionOutcome::RejectedFrozen if account.frozen_when_loaded => {
+                    ledger.stats.ignored_other += 1;
+                    log::warn!("Ignoring row {}: client {} was already frozen in the snapshot it was resumed from", seq, client);
+                },
+                outcome @ (TransactionOutcome::RejectedFrozen | TransactionOutcome::RejectedNotOpen) => {
+                    ledger.stats.ignored_other += 1;
+                    log::debug!("Ignoring row {}: {:?} for client {}", seq, outcome, client);
+                },
+            }
+            if let (Some(budget), Some(transaction)) = (ledger.history_budget.as_mut(), recorded) {
+                if account.transactions.len() > history_len {
+                    budget.record(&transaction);
+                    for (owner, tx) in budget.prune(&mut ledger.accounts) {
+                        ledger.tx_owners.remove(tx, owner);
+                    }
+                }
+            }
+        },
+        RowAction::Dispute(dispute_action) => {
+            let key = (client, dispute_action.transaction_id);
+            let is_disputed = |account: &Account| account.transactions.get(&dispute_action.transaction_id)
+                .map(|transaction| transaction.dispute_state == DisputeState::Disputed)
+                .unwrap_or(false);
+            if config.buffer_out_of_order_disputes
+                && dispute_action.action_type != DisputeActionType::Dispute
+                && !is_disputed(account) {
+                // The dispute this closes may not have arrived yet - hold on to it
+                if tracing {
+                    account.trace_log.push(format!("{:?} buffered until a dispute opens", dispute_action.action_type));
+                }
+                ledger.pending_disputes.entry(key).or_default().push_back(dispute_action.action_type);
+            } else {
+                match account.apply_dispute_action(&dispute_action) {
+                    DisputeOutcome::Applied => ledger.stats.dispute_actions_applied += 1,
+                    // Like a withdrawal, a dispute can't take more than is available
+                    DisputeOutcome::InsufficientFunds => {
+                        ledger.stats.ignored_other += 1;
+                        log::info!("Dispute of tx {} for client {} rejected: its amount exceeds the available balance of {}",
+                            dispute_action.transaction_id, client, account.available_balance);
+                    },
+                    outcome => {
+                        ledger.stats.ignored_other += 1;
+                        log::debug!("Ignoring row {}: {} of tx {} for client {} had outcome {:?}",
+                            seq, input_row.transaction_type, dispute_action.transaction_id, client, outcome);
+                    }
+                }
+                if dispute_action.action_type == DisputeActionType::Dispute && is_disputed(account) {
+                    // Close the newly opened dispute with the earliest action that arrived before it
+                    if let Some(action_type) = ledger.pending_disputes.get_mut(&key).and_then(VecDeque::pop_front) {
+                        if tracing {
+                            account.trace_log.push(format!("applying buffered {:?}", action_type));
+                        }
+                        if account.apply_dispute_action(&DisputeAction { action_type, ..dispute_action }) == DisputeOutcome::Applied {
+                            ledger.stats.dispute_actions_applied += 1;
+                        }
+                    }
+                }
+            }
+        },
+        RowAction::Account(account_action) => {
+            let applied = match (account_action.action_type, &account_action.amount) {
+                (AccountActionType::Open, _) => {
+                    account.open();
+                    keeps_account = true;
+                    true
+                },
+                (AccountActionType::Accrue, Some(rate)) => {
+                    account.accrue_interest(rate, &config.held_interest_rate, config.held_interest_credit, config.decimals);
+                    true
+                },
+                (AccountActionType::Interest, Some(rate)) => {
+                    account.apply_interest(rate, config.decimals);
+                    true
+                },
+                (AccountActionType::Hold, Some(amount)) => account.hold_funds(Money::round_with(amount, config.decimals, config.rounding)),
+                (AccountActionType::Release, Some(amount)) => account.release_funds(Money::round_with(amount, config.decimals, config.rounding)),
+                // Rows without a required amount are rejected when they are parsed
+                (_, None) => false
+            };
+            if applied {
+                ledger.stats.account_actions_applied += 1;
+            } else {
+                ledger.stats.ignored_other += 1;
+                log::debug!("Ignoring row {}: {} for client {} did not apply", seq, input_row.transaction_type, client);
+            }
+        }
+    }
+
+    // Report what was decided about the traced transaction
+    if let (Some(tx), Some(account)) = (config.trace, ledger.accounts.get_mut(&client)) {
+        for line in account.trace_log.drain(..) {
+            eprintln!("trace tx {}: row {}: {}", tx, seq, line);
+        }
+    }
+
+    // Drop the account again unless the row opened it or moved funds into or out of it
+    if is_new_account && !keeps_account {
+        ledger.accounts.remove(&client);
+    }
+
+    // Note the row's position if it changed the account
+    if let (Some(marker), Some(account)) = (marker, ledger.accounts.get_mut(&client)) {
+        if change_marker(account) != marker {
+            account.last_seq = Some(seq);
+        }
+    }
+}
+
+/// Add a transaction at `timestamp` to a client's window of recent transactions,
+/// dropping those more than a second older, and report whether the window now
+/// holds more than `max_rate` transactions
+fn exceeds_rate(window: &mut VecDeque<DateTime<Utc>>, timestamp: DateTime<Utc>, max_rate: usize) -> bool {
+    let window_start = timestamp - Duration::seconds(1);
+    while window.front().is_some_and(|&earliest| earliest <= window_start) {
+        window.pop_front();
+    }
+    window.push_back(timestamp);
+    window.len() > max_rate
+}
+
+/// Write every stored transaction as a CSV, ordered by client and then transaction ID
+pub fn write_transactions<W: Write>(accounts: &BTreeMap<u16, Account>, output: W) -> csv::Result<()> {
+    let mut writer = csv::Writer::from_writer(output);
+    for transaction in accounts.values().flat_map(|account| account.transactions.values()) {
+        writer.serialize(TransactionRow::from(transaction))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// A summary of an account's state that changes whenever a row is applied to it
+fn change_marker(account: &Account) -> (usize, Money, Money, Option<FreezeReason>, bool) {
+    (account.transactions.len(), account.available_balance.clone(), account.held_balance.clone(), account.freeze_reason, account.is_open)
+}
+
+/// Check that the deposits read sum to the expected control total,
+/// describing the discrepancy if they don't
+pub fn check_control_total(expected: &BigDecimal, deposit_total: &BigDecimal) -> Result<(), String> {
+    if expected == deposit_total {
+        Ok(())
+    } else {
+        Err(format!("Deposits total {} but the control total is {} (discrepancy of {})",
+            deposit_total, expected, deposit_total - expected))
+    }
+}
+
+/// Describe each account that ended with a negative available balance, which
+/// can happen when withdrawals are allowed to overdraw the account
+pub fn find_negative_balances(accounts: &BTreeMap<u16, Account>) -> Vec<String> {
+    accounts.values()
+        .filter(|account| account.available_balance.is_negative())
+        .map(|account| format!("Client {} ends with a negative available balance of {}", account.id, account.available_balance))
+        .collect()
+}
+
+/// The clients whose accounts' balances or locked state differ from the `baseline`
+/// accounts, including clients with no account in the baseline
+pub fn changed_clients(accounts: &BTreeMap<u16, Account>, baseline: &BTreeMap<u16, Account>) -> HashSet<u16> {
+    accounts.values()
+        .filter(|account| baseline.get(&account.id).is_none_or(|before| {
+            (&account.available_balance, &account.held_balance, account.is_frozen())
+                != (&before.available_balance, &before.held_balance, before.is_frozen())
+        }))
+        .map(|account| account.id)
+        .collect()
+}
+
+/// Describe each account whose internal invariants are broken
+pub fn find_invariant_violations(accounts: &BTreeMap<u16, Account>) -> Vec<String> {
+    accounts.values()
+        .filter_map(|account| account.validate_invariants().err())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Process CSV text with the given config
+    fn process_str(input: &str, config: &EngineConfig) -> Ledger {
+        let mut reader = csv::ReaderBuilder::new()
+            .flexible(true)
+            .trim(Trim::All)
+            .from_reader(input.as_bytes());
+        process(&mut reader, config).expect("Processing failed")
+    }
+
+    #[test]
+    fn processes_reader_with_config() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.2349\n";
+        let accounts = process_reader(&EngineConfig::default(), input.as_bytes()).expect("Processing failed");
+        assert_eq!(accounts[&1].available_balance.to_string(), "1.2349");
+        let config = EngineConfig { decimals: 2, ..EngineConfig::default() };
+        let accounts = process_reader(&config, input.as_bytes()).expect("Processing failed");
+        assert_eq!(accounts[&1].available_balance.as_decimal().to_string(), "1.23");
+
+        // Whole units only, as for currencies without subunits
+        let input = "type,client,tx,amount\ndeposit,1,1,10.7\n";
+        let config = EngineConfig { decimals: 0, ..EngineConfig::default() };
+        let accounts = process_reader(&config, input.as_bytes()).expect("Processing failed");
+        let output_row = OutputRow::from_account(accounts[&1].clone(), false, config.decimals);
+        assert_eq!(output_row.available.to_string(), "11");
+
+        // Eight decimal places, as for cryptocurrencies
+        let input = "type,client,tx,amount\ndeposit,1,1,0.123456789\n";
+        let config = EngineConfig { decimals: 8, ..EngineConfig::default() };
+        let accounts = process_reader(&config, input.as_bytes()).expect("Processing failed");
+        let output_row = OutputRow::from_account(accounts[&1].clone(), false, config.decimals);
+        assert_eq!(output_row.available.to_string(), "0.12345679");
+    }
+
+    const CONTROL_TOTAL_INPUT: &str = "type, client, tx, amount
+deposit, 1, 1, 1.5
+deposit, 2, 2, 2.25
+withdrawal, 1, 3, 1.0
+dispute, 2, 2,
+";
+
+    #[test]
+    fn matching_control_total_passes() {
+        let ledger = process_str(CONTROL_TOTAL_INPUT, &EngineConfig::default());
+        check_control_total(&"3.75".parse().unwrap(), &ledger.deposit_total).expect("Control total should match");
+    }
+
+    #[test]
+    fn mismatched_control_total_reports_discrepancy() {
+        let ledger = process_str(CONTROL_TOTAL_INPUT, &EngineConfig::default());
+        let message = check_control_total(&"4".parse().unwrap(), &ledger.deposit_total)
+            .expect_err("Control total should not match");
+        assert!(message.contains("-0.25"), "discrepancy missing from: {}", message);
+    }
+
+    const FIVE_ROW_INPUT: &str = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, 2.0
+deposit, 1, 3, 3.0
+deposit, 1, 4, 4.0
+deposit, 1, 5, 5.0
+";
+
+    #[test]
+    fn enforces_unique_tx_across_accounts() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 2, 1, 3.0
+deposit, 2, 2, 1.0
+";
+        let ledger = process_str(input, &EngineConfig::default());
+        assert_eq!(ledger.accounts[&2].available_balance, 4.into());
+        assert_eq!(ledger.stats.ignored_duplicate_tx, 0);
+
+        let config = EngineConfig { enforce_unique_tx: true, ..EngineConfig::default() };
+        let ledger = process_str(input, &config);
+        assert_eq!(ledger.accounts[&1].available_balance, 5.into());
+        assert_eq!(ledger.accounts[&2].available_balance, 1.into());
+        assert_eq!(ledger.stats.ignored_duplicate_tx, 1);
+    }
+
+    #[test]
+    fn processes_rows_without_csv() {
+        let row = |transaction_type: &str, client, tx, amount: Option<&str>| InputRow {
+            transaction_type: transaction_type.to_string(),
+            client,
+            tx,
+            amount: amount.map(|amount| amount.parse().unwrap()),
+            timestamp: None,
+            currency: None,
+        };
+        let rows = vec![
+            row("deposit", 1, 1, Some("10.0")),
+            row("deposit", 2, 2, Some("3.123456")),
+            row("withdrawal", 1, 3, Some("4.0")),
+            row("dispute", 2, 2, None),
+            row("bogus", 1, 4, Some("1.0")),
+        ];
+        let accounts = process_rows(rows, &EngineConfig::default()).expect("Processing failed");
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[&1].available_balance, 6.into());
+        assert_eq!(accounts[&2].available_balance, 0.into());
+        assert_eq!(accounts[&2].held_balance, "3.1235".parse().unwrap());
+    }
+
+    #[test]
+    fn rejects_transactions_in_another_currency() {
+        let input = "type, client, tx, amount, timestamp, currency
+deposit, 1, 1, 10.0, , USD
+withdrawal, 1, 2, 4.0, , USD
+deposit, 1, 3, 1.0, ,
+deposit, 2, 4, 5.0
+deposit, 1, 5, 7.0, , EUR
+withdrawal, 1, 6, 2.0, , EUR
+";
+        let ledger = process_str(input, &EngineConfig::default());
+        let account = &ledger.accounts[&1];
+        assert_eq!(account.currency.as_deref(), Some("USD"));
+        assert_eq!(account.available_balance, 7.into());
+        assert_eq!(account.transactions.len(), 3);
+        assert_eq!(ledger.accounts[&2].currency, None);
+        assert_eq!(ledger.stats.transactions_applied, 4);
+        assert_eq!(ledger.stats.ignored_other, 2);
+    }
+
+    #[test]
+    fn seeded_balances_apply_before_transactions() {
+        let config = EngineConfig::default();
+        let mut reader = csv_reader("client, opening_balance\n1, 500\n2, 0.123456\n".as_bytes());
+        let balances = read_opening_balances(&mut reader, config.decimals, config.rounding).expect("Reading failed");
+        assert_eq!(balances, [(1, 500.into()), (2, "0.1235".parse().unwrap())]);
+
+        let mut ledger = Ledger::default();
+        for (client, balance) in &balances {
+            ledger.seed_account(*client, balance, &config);
+        }
+        let input = "type, client, tx, amount
+deposit, 1, 1, 100.0
+dispute, 1, 0,
+";
+        process_into(&mut csv_reader(input.as_bytes()), &config, &mut ledger).expect("Processing failed");
+        let account = &ledger.accounts[&1];
+        assert_eq!(account.available_balance, 600.into());
+        assert_eq!(account.held_balance, 0.into());
+        assert_eq!(account.transactions.len(), 1);
+
+        let mut reader = csv_reader("client, opening_balance\n1, -5\n".as_bytes());
+        read_opening_balances(&mut reader, config.decimals, config.rounding).expect_err("Negative balance was allowed");
+    }
+
+    #[test]
+    fn reads_json_lines() {
+        let input = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "2.50"}
+
+{"type": "deposit", "client": 2, "tx": 2, "amount": 3}
+{"type": "deposit", "client": 1
+{"type": "withdrawal", "client": 1, "tx": 3, "amount": 0.5, "timestamp": null}
+"#;
+        let mut ledger = Ledger::default();
+        process_jsonl_into(input.as_bytes(), &EngineConfig::default(), &mut ledger).expect("Processing failed");
+        assert_eq!(ledger.accounts[&1].available_balance, 2.into());
+        assert_eq!(ledger.accounts[&2].available_balance, 3.into());
+        assert_eq!(ledger.stats.rows_read, 4);
+        assert_eq!(ledger.stats.ignored_malformed, 1);
+    }
+
+    #[test]
+    fn reads_only_json_string_amounts_in_the_locale() {
+        let input = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1.234,5"}
+{"type": "deposit", "client": 2, "tx": 2, "amount": 1.234}
+{"type": "deposit", "client": 3, "tx": 3, "amount": 0.5}
+"#;
+        let config = EngineConfig { locale: Locale::De, ..EngineConfig::default() };
+        let mut ledger = Ledger::default();
+        process_jsonl_into(input.as_bytes(), &config, &mut ledger).expect("Processing failed");
+        assert_eq!(ledger.accounts[&1].available_balance, "1234.5".parse().unwrap());
+        assert_eq!(ledger.accounts[&2].available_balance, "1.234".parse().unwrap());
+        assert_eq!(ledger.accounts[&3].available_balance, "0.5".parse().unwrap());
+        assert_eq!(ledger.stats.ignored_malformed, 0);
+    }
+
+    #[test]
+    fn rounds_amounts_with_the_configured_mode() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.23455\ndeposit,2,2,1.23445\n";
+        for (rounding, expected) in [(RoundingMode::HalfUp, ["1.2346", "1.2345"]), (RoundingMode::HalfEven, ["1.2346", "1.2344"]), (RoundingMode::Truncate, ["1.2345", "1.2344"])] {
+            let ledger = process_str(input, &EngineConfig { rounding, ..EngineConfig::default() });
+            assert_eq!([1, 2].map(|client| ledger.accounts[&client].available_balance.to_string()), expected, "{:?}", rounding);
+        }
+    }
+
+    #[test]
+    fn reads_amounts_in_the_configured_locale() {
+        let ledger = process_str("type,client,tx,amount\ndeposit,1,1,\"1,234.56\"\n", &EngineConfig::default());
+        assert_eq!(ledger.accounts[&1].available_balance, "1234.56".parse().unwrap());
+
+        let config = EngineConfig { locale: Locale::De, ..EngineConfig::default() };
+        let ledger = process_str("type,client,tx,amount\ndeposit,1,1,\"1.234,56\"\ndeposit,1,2,1.5\n", &config);
+        assert_eq!(ledger.accounts[&1].available_balance, "1234.56".parse().unwrap());
+        assert_eq!(ledger.stats.ignored_malformed, 1);
+    }
+
+    #[test]
+    fn reads_amounts_in_minor_units() {
+        let config = EngineConfig { minor_units: Some(2), ..EngineConfig::default() };
+        let ledger = process_str("type, client, tx, amount
+deposit, 1, 1, 1050
+withdrawal, 1, 2, 25
+hold, 1, 0, 100
+interest, 1, 0, 0.1
+deposit, 1, 3, 10.50
+", &config);
+        let account = &ledger.accounts[&1];
+        assert_eq!(account.available_balance.to_string(), "10.1750");
+        assert_eq!(account.held_balance.to_string(), "1.0000");
+        assert_eq!(ledger.stats.ignored_malformed, 1);
+        assert!(!account.transactions.contains_key(&3));
+    }
+
+    #[test]
+    fn applies_only_rows_within_time_window() {
+        let input = "type, client, tx, amount, timestamp
+deposit, 1, 1, 5.0, 2024-01-01T09:00:00Z
+deposit, 1, 2, 3.0, 2024-02-01T09:00:00Z
+deposit, 1, 3, 2.0,
+dispute, 1, 1,, 2024-02-02T09:00:00Z
+dispute, 1, 2,, 2024-02-02T09:00:00+01:00
+withdrawal, 1, 4, 1.0, 2024-03-01T00:00:00Z
+";
+        let since = "2024-02-01T00:00:00Z".parse().unwrap();
+        let until = "2024-03-01T00:00:00Z".parse().unwrap();
+        let config = EngineConfig { since: Some(since), until: Some(until), ..EngineConfig::default() };
+        let ledger = process_str(input, &config);
+        let account = &ledger.accounts[&1];
+        // The early deposit is left out, so its dispute finds nothing to hold
+        assert!(!account.transactions.contains_key(&1));
+        assert_eq!(account.held_balance, 3.into());
+        assert_eq!(account.available_balance, 2.into());
+        assert_eq!(account.transactions[&2].timestamp, Some("2024-02-01T09:00:00Z".parse().unwrap()));
+        assert_eq!(ledger.stats.ignored_other, 3);
+
+        // Without a window, timestamps change nothing
+        let ledger = process_str(input, &EngineConfig::default());
+        assert_eq!(ledger.accounts[&1].available_balance, 1.into());
+        assert_eq!(ledger.accounts[&1].held_balance, 8.into());
+    }
+
+    #[test]
+    fn flags_or_rejects_clients_over_the_rate_limit() {
+        let input = "type, client, tx, amount, timestamp
+deposit, 1, 1, 1.0, 2024-01-01T00:00:00Z
+deposit, 2, 2, 1.0, 2024-01-01T00:00:00Z
+deposit, 1, 3, 1.0, 2024-01-01T00:00:00.400Z
+deposit, 2, 4, 1.0, 2024-01-01T00:00:01Z
+deposit, 1, 5, 1.0, 2024-01-01T00:00:00.800Z
+deposit, 2, 6, 1.0, 2024-01-01T00:00:02Z
+deposit, 1, 7, 1.0, 2024-01-01T00:00:05Z
+";
+        let config = EngineConfig { max_client_rate: Some(2), ..EngineConfig::default() };
+        let ledger = process_str(input, &config);
+        assert_eq!(ledger.over_rate_clients, BTreeSet::from([1]));
+        assert_eq!(ledger.accounts[&1].available_balance, 4.into());
+        assert_eq!(ledger.accounts[&2].available_balance, 3.into());
+
+        let config = EngineConfig { max_client_rate: Some(2), on_client_rate: RatePolicy::Reject, ..EngineConfig::default() };
+        let ledger = process_str(input, &config);
+        assert_eq!(ledger.over_rate_clients, BTreeSet::from([1]));
+        assert!(!ledger.accounts[&1].transactions.contains_key(&5));
+        assert_eq!(ledger.accounts[&1].available_balance, 3.into());
+        assert_eq!(ledger.accounts[&2].available_balance, 3.into());
+    }
+
+    #[test]
+    fn history_budget_covers_every_input_and_loaded_history() {
+        // Sized like the transactions read from the input, at four decimal places
+        let amount = Money::round(&BigDecimal::from(1), 4);
+        let size = Transaction::new(1, 1, amount.clone(), TransactionType::Deposit).estimated_size();
+        let config = EngineConfig { max_history_bytes: Some(size * 3), ..EngineConfig::default() };
+        let mut ledger = Ledger::default();
+        let mut account = Account::new(1);
+        account.register_transaction(Transaction::new(1, 1, amount.clone(), TransactionType::Deposit));
+        account.register_transaction(Transaction::new(2, 1, amount, TransactionType::Deposit));
+        ledger.accounts.insert(1, account);
+
+        process_into(&mut csv_reader("type,client,tx,amount\ndeposit,1,3,1\n".as_bytes()), &config, &mut ledger).expect("Processing failed");
+        process_into(&mut csv_reader("type,client,tx,amount\ndeposit,1,4,1\ndeposit,2,5,1\n".as_bytes()), &config, &mut ledger).expect("Processing failed");
+        let retained: usize = ledger.accounts.values().map(|account| account.transactions.len()).sum();
+        assert_eq!(retained, 3);
+        // The loaded transactions were the oldest, so they went first
+        assert_eq!(ledger.accounts[&1].transactions.keys().copied().collect::<Vec<_>>(), vec![3, 4]);
+    }
+
+    #[test]
+    fn indexes_the_owners_of_stored_transactions() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 1, 1.0
+deposit, 2, 2, 1.0
+deposit, 2, 3, 1.0
+";
+        let size = Transaction::new(1, 1, Money::round(&BigDecimal::from(1), 4), TransactionType::Deposit).estimated_size();
+        let config = EngineConfig { max_history_bytes: Some(size * 3), ..EngineConfig::default() };
+        let ledger = process_str(input, &config);
+        // Client 1's tx 1 was forgotten first
+        assert_eq!(ledger.tx_owners.owner(1, 1), Some(2));
+        assert_eq!(ledger.tx_owners.owner(2, 1), Some(2));
+        assert_eq!(ledger.tx_owners.owner(1, 3), Some(2));
+        assert_eq!(ledger.tx_owners.owner(1, 9), None);
+    }
+
+    #[test]
+    fn truncates_input_at_row_limit() {
+        let config = EngineConfig { max_rows: Some(3), on_limit: LimitPolicy::Truncate, ..EngineConfig::default() };
+        let ledger = process_str(FIVE_ROW_INPUT, &config);
+        assert_eq!(ledger.stats.rows_read, 3);
+        assert_eq!(ledger.accounts[&1].available_balance, 6.into());
+
+        let config = EngineConfig { max_rows: Some(5), on_limit: LimitPolicy::Truncate, ..EngineConfig::default() };
+        assert_eq!(process_str(FIVE_ROW_INPUT, &config).stats.rows_read, 5);
+    }
+
+    #[test]
+    fn row_limit_covers_every_input() {
+        let config = EngineConfig { max_rows: Some(7), on_limit: LimitPolicy::Truncate, ..EngineConfig::default() };
+        let mut ledger = Ledger::default();
+        for _ in 0..3 {
+            process_into(&mut csv_reader(FIVE_ROW_INPUT.as_bytes()), &config, &mut ledger).expect("Processing failed");
+        }
+        assert_eq!(ledger.stats.rows_read, 7);
+
+        let config = EngineConfig { max_rows: Some(7), ..EngineConfig::default() };
+        let mut ledger = Ledger::default();
+        process_into(&mut csv_reader(FIVE_ROW_INPUT.as_bytes()), &config, &mut ledger).expect("Processing failed");
+        let err = process_into(&mut csv_reader(FIVE_ROW_INPUT.as_bytes()), &config, &mut ledger).expect_err("Row limit was not enforced");
+        assert!(matches!(err, AppError::TooManyRows(7)));
+    }
+
+    #[test]
+    fn aborts_input_past_row_limit() {
+        let config = EngineConfig { max_rows: Some(3), ..EngineConfig::default() };
+        let err = process(&mut csv_reader(FIVE_ROW_INPUT.as_bytes()), &config).expect_err("Row limit was not enforced");
+        assert!(matches!(err, AppError::TooManyRows(3)));
+        assert_eq!(err.exit_code(), 2);
+    }
+
+    #[test]
+    fn finds_clients_changed_since_baseline() {
+        let baseline = process_str("type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,3.0\ndeposit,3,3,1.0\n", &EngineConfig::default());
+        let mut ledger = process_str("type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,3.0\ndeposit,3,3,1.0\n", &EngineConfig::default());
+        assert!(changed_clients(&ledger.accounts, &baseline.accounts).is_empty());
+
+        let mut reader = csv_reader("type,client,tx,amount\ndeposit,2,4,1.0\ndispute,3,3,\ndeposit,4,5,2.0\n".as_bytes());
+        process_into(&mut reader, &EngineConfig::default(), &mut ledger).expect("Processing failed");
+        assert_eq!(changed_clients(&ledger.accounts, &baseline.accounts), HashSet::from([2, 3, 4]));
+    }
+
+    #[test]
+    fn reports_negative_available_balances() {
+        let config = EngineConfig { overdraft_limit: 5.into(), ..EngineConfig::default() };
+        let ledger = process_str("type, client, tx, amount
+deposit, 1, 1, 1.0
+withdrawal, 1, 2, 3.0
+deposit, 2, 3, 4.0
+withdrawal, 2, 4, 4.0
+", &config);
+        assert_eq!(find_negative_balances(&ledger.accounts), vec!["Client 1 ends with a negative available balance of -2.0000"]);
+    }
+
+    #[test]
+    fn last_seq_tracks_last_applied_row() {
+        let config = EngineConfig { track_last_seq: true, ..EngineConfig::default() };
+        let ledger = process_str("type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 2, 2, 5.0
+dispute, 1, 1,
+withdrawal, 2, 3, 10.0
+resolve, 2, 2,
+", &config);
+        assert_eq!(ledger.accounts[&1].last_seq, Some(3));
+        // The rejected withdrawal and stray resolve don't count as changes
+        assert_eq!(ledger.accounts[&2].last_seq, Some(2));
+    }
+
+    #[test]
+    fn buffers_chargeback_until_dispute_opens() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 1, 2, 3.0
+chargeback, 1, 1,
+dispute, 1, 1,
+";
+        let config = EngineConfig { buffer_out_of_order_disputes: true, ..EngineConfig::default() };
+        let ledger = process_str(input, &config);
+        let account = &ledger.accounts[&1];
+        assert_eq!(account.available_balance, 3.into());
+        assert_eq!(account.held_balance, 0.into());
+        assert_eq!(account.transactions[&1].dispute_state, DisputeState::ChargedBack);
+        assert!(account.is_frozen());
+
+        // Without buffering, the early chargeback is dropped and the dispute stays open
+        let ledger = process_str(input, &EngineConfig::default());
+        let account = &ledger.accounts[&1];
+        assert_eq!(account.held_balance, 5.into());
+        assert_eq!(account.transactions[&1].dispute_state, DisputeState::Disputed);
+        assert!(!account.is_frozen());
+    }
+
+    #[test]
+    fn exports_final_dispute_states() {
+        let ledger = process_str("type, client, tx, amount
+deposit, 2, 4, 1.0
+deposit, 1, 3, 2.0
+deposit, 1, 1, 5.0
+deposit, 1, 5, 10.0
+withdrawal, 1, 2, 1.0
+dispute, 1, 1,
+dispute, 1, 3,
+chargeback, 1, 3,
+", &EngineConfig::default());
+        let mut output = Vec::new();
+        write_transactions(&ledger.accounts, &mut output).expect("Export failed");
+        assert_eq!(String::from_utf8(output).unwrap(), "client,tx,type,amount,dispute_state
+1,1,deposit,5.0000,disputed
+1,2,withdrawal,1.0000,undisputed
+1,3,deposit,2.0000,charged_back
+1,5,deposit,10.0000,undisputed
+2,4,deposit,1.0000,undisputed
+");
+    }
+
+    #[test]
+    fn counts_what_happened_to_each_row() {
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,1,5.0
+withdrawal,1,2,9.0
+deposit,1,3,1.0
+deposit,1,4,-1.0
+transfer,1,5,1.0
+deposit,1,x,1.0
+deposit,1,6,
+dispute,1,3,
+resolve,1,9,
+";
+        let ledger = process_str(input, &EngineConfig::default());
+        assert_eq!(ledger.stats, ProcessingStats {
+            rows_read: 10,
+            transactions_applied: 2,
+            dispute_actions_applied: 1,
+            account_actions_applied: 0,
+            ignored_malformed: 3,
+            ignored_unknown_type: 1,
+            ignored_duplicate_tx: 1,
+            ignored_insufficient_funds: 1,
+            ignored_other: 1,
+        });
+    }
+
+    #[test]
+    fn error_policy_decides_what_happens_to_bad_rows() {
+        let input = "type,client,tx,amount\ndeposit,1,1,5.0\ntransfer,1,2,1.0\ndeposit,x,3,1.0\ndeposit,1,4,1.0\n";
+        let config = EngineConfig { on_error: ErrorPolicy::Skip, ..EngineConfig::default() };
+        let ledger = process_str(input, &config);
+        assert_eq!(ledger.accounts[&1].available_balance, 6.into());
+        assert_eq!(ledger.stats.ignored_unknown_type, 1);
+        assert_eq!(ledger.stats.ignored_malformed, 1);
+
+        let config = EngineConfig { on_error: ErrorPolicy::Abort, ..EngineConfig::default() };
+        let mut reader = csv_reader(input.as_bytes());
+        match process(&mut reader, &config) {
+            Err(AppError::BadRow(seq, problem)) => {
+                assert_eq!(seq, 2);
+                assert!(problem.contains("unknown transaction type"), "unexpected problem: {}", problem);
+            },
+            result => panic!("Bad row did not abort: {:?}", result.map(|ledger| ledger.accounts))
+        }
+        let mut reader = csv_reader("type,client,tx,amount\ndeposit,x,1,1.0\n".as_bytes());
+        match process(&mut reader, &config) {
+            Err(AppError::BadRow(seq, _)) => assert_eq!(seq, 1),
+            result => panic!("Malformed row did not abort: {:?}", result.map(|ledger| ledger.accounts))
+        }
+    }
+
+    #[test]
+    fn audits_precision_lost_to_rounding() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.23456\ndeposit,1,2,2.5\nwithdrawal,1,3,0.10001\n";
+        let config = EngineConfig { audit_precision_loss: true, ..EngineConfig::default() };
+        let ledger = process_str(input, &config);
+        assert_eq!(ledger.precision_loss, PrecisionLoss {
+            count: 2,
+            total_delta: "-0.00003".parse().unwrap(),
+        });
+
+        let ledger = process_str(input, &EngineConfig::default());
+        assert_eq!(ledger.precision_loss.count, 0);
+    }
+
+    #[test]
+    fn ignores_disputes_naming_another_client() {
+        let input = "type,client,tx,amount\ndeposit,7,1,4.0\ndispute,5,1,\nchargeback,5,1,\n";
+        let ledger = process_str(input, &EngineConfig::default());
+        assert_eq!(ledger.accounts.keys().copied().collect::<Vec<_>>(), vec![7]);
+        let account = &ledger.accounts[&7];
+        assert_eq!(account.available_balance, 4.into());
+        assert_eq!(account.held_balance, 0.into());
+        assert!(!account.is_frozen());
+    }
+
+    #[test]
+    fn lenient_disputes_find_the_owning_client() {
+        let input = "type,client,tx,amount\ndeposit,7,1,4.0\ndispute,5,1,\nchargeback,5,1,\n";
+        let config = EngineConfig { lenient_dispute_client: true, ..EngineConfig::default() };
+        let ledger = process_str(input, &config);
+        assert_eq!(ledger.accounts.keys().copied().collect::<Vec<_>>(), vec![7]);
+        let account = &ledger.accounts[&7];
+        assert_eq!(account.available_balance, 0.into());
+        assert_eq!(account.held_balance, 0.into());
+        assert!(account.is_frozen());
+        assert_eq!(ledger.stats.dispute_actions_applied, 2);
+    }
+
+    #[test]
+    fn lenient_disputes_find_owners_through_the_index() {
+        let config = EngineConfig { lenient_dispute_client: true, ..EngineConfig::default() };
+        let mut bytes = Vec::new();
+        crate::snapshot::write_snapshot(&process_str("type,client,tx,amount\ndeposit,7,1,4.0\n", &config), &mut bytes)
+            .expect("Write failed");
+        // The index of a resumed ledger covers the transactions loaded with it
+        let mut ledger = crate::snapshot::read_snapshot(bytes.as_slice()).expect("Read failed");
+        process_into(&mut csv_reader("type,client,tx,amount\ndeposit,5,2,1.0\ndispute,5,1,\n".as_bytes()), &config, &mut ledger)
+            .expect("Processing failed");
+        assert_eq!(ledger.accounts[&7].held_balance, 4.into());
+        assert_eq!(ledger.accounts[&5].held_balance, 0.into());
+
+        // A transaction forgotten by the history budget has no owner to redirect to
+        let size = Transaction::new(1, 1, Money::round(&BigDecimal::from(1), 4), TransactionType::Deposit).estimated_size();
+        let config = EngineConfig { max_history_bytes: Some(size), ..config };
+        let ledger = process_str("type,client,tx,amount\ndeposit,7,1,1.0\ndeposit,7,2,1.0\ndispute,5,1,\n", &config);
+        assert_eq!(ledger.accounts[&7].held_balance, 0.into());
+        assert_eq!(ledger.stats.dispute_actions_applied, 0);
+    }
+
+    #[test]
+    fn hard_freeze_ignores_disputes_on_frozen_accounts() {
+        let input = "type,client,tx,amount\ndeposit,1,1,4.0\ndeposit,1,2,6.0\ndispute,1,1,\nchargeback,1,1,\ndispute,1,2,\n";
+        let ledger = process_str(input, &EngineConfig::default());
+        assert_eq!(ledger.accounts[&1].held_balance, 6.into());
+
+        let config = EngineConfig { hard_freeze: true, ..EngineConfig::default() };
+        let ledger = process_str(input, &config);
+        let account = &ledger.accounts[&1];
+        assert!(account.is_frozen());
+        assert_eq!(account.available_balance, 6.into());
+        assert_eq!(account.held_balance, 0.into());
+        assert_eq!(ledger.stats.ignored_other, 1);
+    }
+
+    #[test]
+    fn omits_accounts_referenced_only_by_disputes() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndispute,2,9,\nchargeback,3,7,\n";
+        let ledger = process_str(input, &EngineConfig::default());
+        assert_eq!(ledger.accounts.keys().copied().collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[test]
+    fn lone_dispute_creates_no_account() {
+        let ledger = process_str("type,client,tx,amount\ndispute,1,1,\n", &EngineConfig::default());
+        assert!(ledger.accounts.is_empty());
+    }
+
+    #[test]
+    fn insufficient_funds_warning_leaves_balance_unchanged() {
+        let config = EngineConfig::default();
+        let ledger = process_str("type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,7.5\n", &config);
+        assert_eq!(ledger.stats.ignored_insufficient_funds, 1);
+        assert_eq!(ledger.accounts[&1].available_balance, 5.into());
+        assert!(!ledger.accounts[&1].transactions.contains_key(&2));
+    }
+
+    #[test]
+    fn columns_may_come_in_any_order() {
+        let ledger = process_str("client,type,amount,tx\n1,deposit,5.0,1\n", &EngineConfig::default());
+        assert_eq!(ledger.accounts[&1].available_balance, 5.into());
+    }
+
+    #[test]
+    fn missing_tx_column_is_reported_before_any_row() {
+        let mut reader = csv_reader("type,client,amount\ndeposit,1,5.0\n".as_bytes());
+        let err = process(&mut reader, &EngineConfig::default()).expect_err("Missing column was allowed");
+        assert_eq!(err.to_string(), "Input has no `tx` column in its header row");
+        assert_eq!(err.exit_code(), 2);
+    }
+
+    #[test]
+    fn collects_a_fee_with_each_withdrawal() {
+        let config = EngineConfig { withdrawal_fee: "0.5".parse().unwrap(), ..EngineConfig::default() };
+        let ledger = process_str("type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,2,4.0
+withdrawal,1,3,5.5
+withdrawal,1,4,1.0
+", &config);
+        assert_eq!(ledger.stats.ignored_insufficient_funds, 1);
+        assert_eq!(ledger.accounts[&1].available_balance, 4.into());
+        assert_eq!(ledger.fees_collected, 1.into());
+    }
+
+    #[test]
+    fn zero_transactions_do_not_use_up_their_id() {
+        let ledger = process_str("type,client,tx,amount\ndeposit,1,1,0.0\nwithdrawal,1,2,0\ndeposit,1,1,5.0\n", &EngineConfig::default());
+        assert_eq!(ledger.stats.ignored_malformed, 2);
+        assert_eq!(ledger.accounts[&1].available_balance, 5.into());
+    }
+
+    #[test]
+    fn require_open_rejects_transactions_before_open() {
+        let config = EngineConfig { require_open: true, ..EngineConfig::default() };
+        let ledger = process_str("type, client, tx, amount
+deposit, 1, 1, 5.0
+open, 1, 0,
+deposit, 1, 2, 3.0
+", &config);
+        assert_eq!(ledger.accounts[&1].available_balance, 3.into());
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn processes_csv_over_http() {
+        use std::io::{ BufRead, BufReader };
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Could not bind mock server");
+        let url = format!("http://{}/input.csv", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().expect("No connection");
+            // Read the request headers, up to the blank line
+            let mut request = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while request.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                line.clear();
+            }
+            let body = "type, client, tx, amount\ndeposit, 1, 1, 5.0\nwithdrawal, 1, 2, 1.5\n";
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: text/csv\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(), body).unwrap();
+        });
+
+        let mut reader = csv::ReaderBuilder::new()
+            .flexible(true)
+            .trim(Trim::All)
+            .from_reader(crate::input::open_input(&url).expect("Fetch failed"));
+        let ledger = process(&mut reader, &EngineConfig::default()).expect("Processing failed");
+        server.join().unwrap();
+        assert_eq!(ledger.accounts[&1].available_balance, "3.5".parse().unwrap());
+    }
+
+    #[test]
+    fn parallel_files_match_sequential_processing() {
+        let directory = tempfile::tempdir().expect("Could not create temp dir");
+        let first = directory.path().join("first.csv");
+        let second = directory.path().join("second.csv");
+        std::fs::write(&first, "type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 2, 2, 7.0
+dispute, 1, 1,
+").unwrap();
+        std::fs::write(&second, "type, client, tx, amount
+deposit, 3, 3, 2.0
+withdrawal, 3, 4, 0.5
+deposit, 4, 5, 1.0
+").unwrap();
+        let locations: Vec<String> = [first, second].iter().map(|path| path.to_string_lossy().into_owned()).collect();
+
+        let config = EngineConfig::default();
+        let mut sequential = Ledger::default();
+        for location in &locations {
+            process_into(&mut open_reader(location).unwrap(), &config, &mut sequential).expect("Processing failed");
+        }
+        let parallel = process_files_parallel(&locations, &config, 2, DEFAULT_DELIMITER).expect("Processing failed");
+
+        let summarize = |ledger: &Ledger| ledger.accounts.values()
+            .map(|account| (account.id, account.available_balance.clone(), account.held_balance.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(summarize(&parallel), summarize(&sequential));
+        assert_eq!(parallel.accounts.len(), 4);
+        assert_eq!(parallel.deposit_total, sequential.deposit_total);
+    }
+
+    #[test]
+    fn orders_accounts_by_client_or_first_seen() {
+        let input = "type,client,tx,amount\ndeposit,3,1,1.0\ndispute,9,7,\ndeposit,1,2,1.0\ndeposit,2,3,1.0\ndeposit,3,4,1.0\n";
+        let ids = |accounts: Vec<Account>| accounts.iter().map(|account| account.id).collect::<Vec<_>>();
+        assert_eq!(ids(process_str(input, &EngineConfig::default()).into_accounts(AccountOrder::Client)), vec![1, 2, 3]);
+        assert_eq!(ids(process_str(input, &EngineConfig::default()).into_accounts(AccountOrder::FirstSeen)), vec![3, 1, 2]);
+
+        let reader = csv::ReaderBuilder::new().from_reader(input.as_bytes());
+        let sharded = process_sharded([Ok(reader)], &EngineConfig::default(), 2, Ledger::default()).expect("Processing failed");
+        assert_eq!(ids(sharded.into_accounts(AccountOrder::FirstSeen)), vec![3, 1, 2]);
+    }
+
+    #[test]
+    fn sharded_processing_matches_sequential_processing() {
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,7.0
+deposit,3,3,2.0
+withdrawal,2,4,1.5
+dispute,1,1,
+chargeback,1,1,
+deposit,1,5,3.0
+dispute,3,3,
+resolve,3,3,
+deposit,4,6,1.0
+";
+        let config = EngineConfig { track_last_seq: true, ..EngineConfig::default() };
+        let sequential = process_str(input, &config);
+        let reader = csv::ReaderBuilder::new().from_reader(input.as_bytes());
+        let sharded = process_sharded([Ok(reader)], &config, 3, Ledger::default()).expect("Processing failed");
+
+        let summarize = |ledger: &Ledger| ledger.accounts.values()
+            .map(|account| (account.id, account.available_balance.clone(), account.held_balance.clone(),
+                account.freeze_reason, account.last_seq))
+            .collect::<Vec<_>>();
+        assert_eq!(summarize(&sharded), summarize(&sequential));
+        assert_eq!(sharded.deposit_total, sequential.deposit_total);
+    }
+}
@@ -0,0 +1,88 @@
+use std::fmt;
+use std::io;
+
+/// An error that stops the application, each kind mapping to its own exit code
+#[derive(Debug)]
+pub enum AppError {
+    /// The command line arguments were malformed
+    Usage(String),
+    /// A file could not be opened, with its path
+    OpenFile(String, io::Error),
+    /// A row of the input could not be read
+    ReadRow(csv::Error),
+    /// The input's header row lacks a required column, with its name
+    MissingColumn(String),
+    /// A row of the input was malformed or specified no known action, with its
+    /// row number, and the error policy is to abort
+    BadRow(u64, String),
+    /// An input had more rows than the row limit, with the limit, and the
+    /// limit policy is to abort
+    TooManyRows(u64),
+    /// An output row could not be written
+    WriteOutput(csv::Error),
+    /// The output could not be written to a file or external sink, with its location
+    WriteSink(String, io::Error),
+    /// The ledger snapshot could not be written, with its path
+    WriteSnapshot(String, io::Error),
+    /// An integrity check on the input failed
+    CheckFailed(String),
+}
+
+impl AppError {
+    /// The process exit code for this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AppError::Usage(_) => 1,
+            AppError::OpenFile(_, _) | AppError::ReadRow(_) | AppError::MissingColumn(_) | AppError::BadRow(_, _) | AppError::TooManyRows(_)
+                | AppError::WriteOutput(_) | AppError::WriteSink(_, _) | AppError::WriteSnapshot(_, _) => 2,
+            AppError::CheckFailed(_) => 3,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Usage(message) => write!(f, "{}", message),
+            AppError::OpenFile(path, err) => write!(f, "Could not open {}: {}", path, err),
+            AppError::ReadRow(err) => match err.position() {
+                Some(position) => write!(f, "Error reading line {}: {}", position.line(), err),
+                None => write!(f, "Error reading input: {}", err),
+            },
+            AppError::MissingColumn(column) => write!(f, "Input has no `{}` column in its header row", column),
+            AppError::BadRow(seq, problem) => write!(f, "Bad input row {}: {}", seq, problem),
+            AppError::TooManyRows(max_rows) => write!(f, "Input has more than the limit of {} rows", max_rows),
+            AppError::WriteOutput(err) => write!(f, "Error writing output: {}", err),
+            AppError::WriteSink(location, err) => write!(f, "Could not write to {}: {}", location, err),
+            AppError::WriteSnapshot(path, err) => write!(f, "Could not write snapshot {}: {}", path, err),
+            AppError::CheckFailed(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_errors_to_exit_codes() {
+        assert_eq!(AppError::Usage("No input file given".to_string()).exit_code(), 1);
+        let not_found = io::Error::new(io::ErrorKind::NotFound, "not found");
+        assert_eq!(AppError::OpenFile("input.csv".to_string(), not_found).exit_code(), 2);
+        let disk_full = io::Error::new(io::ErrorKind::StorageFull, "disk full");
+        let snapshot_error = AppError::WriteSnapshot("ledger.snap".to_string(), disk_full);
+        assert_eq!(snapshot_error.exit_code(), 2);
+        assert_eq!(snapshot_error.to_string(), "Could not write snapshot ledger.snap: disk full");
+        assert_eq!(AppError::CheckFailed("Deposits don't match".to_string()).exit_code(), 3);
+    }
+
+    #[test]
+    fn read_errors_report_line_number() {
+        let mut reader = csv::ReaderBuilder::new().from_reader("a,b\n1,2\n3\n".as_bytes());
+        let err = reader.records().find_map(Result::err).expect("Short row was allowed");
+        let message = AppError::ReadRow(err).to_string();
+        assert!(message.starts_with("Error reading line 3"), "unexpected message: {}", message);
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+
+use crate::csv_rows::OutputRow;
+
+/// Read the expected output rows from a CSV of accounts written by this tool
+pub fn read_expected<R: Read>(reader: &mut csv::Reader<R>) -> csv::Result<Vec<OutputRow>> {
+    reader.deserialize().collect()
+}
+
+/// Describe how the actual output rows differ from the expected ones, matching rows by
+/// client, as lines of a diff: `- ` for an expected row and `+ ` for the row found in its
+/// place. Empty if the rows match, in whatever order
+pub fn diff_rows(expected: &[OutputRow], actual: &[OutputRow]) -> Vec<String> {
+    let by_client = |rows: &[OutputRow]| -> BTreeMap<u16, OutputRow> {
+        rows.iter().map(|row| (row.client, comparable(row))).collect()
+    };
+    let (mut expected, actual) = (by_client(expected), by_client(actual));
+    let mut diff = Vec::new();
+    for (client, actual_row) in actual {
+        match expected.remove(&client) {
+            Some(expected_row) if expected_row == actual_row => {},
+            Some(expected_row) => {
+                diff.push(format!("- {}", record(&expected_row)));
+                diff.push(format!("+ {}", record(&actual_row)));
+            },
+            None => diff.push(format!("+ {}", record(&actual_row)))
+        }
+    }
+    // Whatever wasn't matched is missing from the output
+    diff.extend(expected.values().map(|expected_row| format!("- {}", record(expected_row))));
+    diff
+}
+
+/// The row as it reads back from CSV, where empty optional columns are none
+fn comparable(row: &OutputRow) -> OutputRow {
+    let present = |text: &Option<String>| text.clone().filter(|text| !text.is_empty());
+    OutputRow {
+        lock_reason: present(&row.lock_reason),
+        last_seq: present(&row.last_seq),
+        currency: present(&row.currency),
+        ..row.clone()
+    }
+}
+
+/// The row written as a CSV record, without a trailing newline
+fn record(row: &OutputRow) -> String {
+    let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(Vec::new());
+    writer.serialize(row).expect("Writing to memory failed");
+    let record = String::from_utf8(writer.into_inner().expect("Writing to memory failed")).expect("CSV is UTF-8");
+    record.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(csv: &str) -> Vec<OutputRow> {
+        read_expected(&mut csv::Reader::from_reader(csv.as_bytes())).expect("Parse failed")
+    }
+
+    #[test]
+    fn matching_rows_have_no_diff() {
+        let expected = rows("client,available,held,total,locked\n2,2.0,0,2.0,false\n1,1.5,0,1.5,false\n");
+        let actual = rows("client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n2,2.0000,0.0000,2.0000,false\n");
+        assert_eq!(diff_rows(&expected, &actual), Vec::<String>::new());
+    }
+
+    #[test]
+    fn lists_changed_extra_and_missing_rows() {
+        let expected = rows("client,available,held,total,locked\n1,1.5,0,1.5,false\n2,2.0,0,2.0,false\n");
+        let actual = rows("client,available,held,total,locked\n1,1.0,0.5,1.5,false\n3,1,0,1,true\n");
+        assert_eq!(diff_rows(&expected, &actual), [
+            "- 1,1.5,0,1.5,false",
+            "+ 1,1.0,0.5,1.5,false",
+            "+ 3,1,0,1,true",
+            "- 2,2.0,0,2.0,false",
+        ]);
+    }
+}
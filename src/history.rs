@@ -0,0 +1,140 @@
+use std::collections::{ BTreeMap, VecDeque };
+
+use crate::account::Account;
+use crate::transaction::{ Transaction, DisputeState };
+
+/// A memory budget for the transaction history retained across all accounts.
+/// Transactions are tracked in the order they were recorded, and once the
+/// estimated size of the history exceeds the budget, the oldest transactions
+/// that are not under dispute are forgotten.
+///
+/// Forgotten transactions can no longer be disputed, and their IDs are no
+/// longer recognized as duplicates.
+#[derive(Debug)]
+pub struct HistoryBudget {
+    /// The maximum estimated size of the retained history, in bytes
+    max_bytes: usize,
+    /// The estimated size of the retained history, in bytes
+    used_bytes: usize,
+    /// The client and transaction ID of every retained transaction, oldest first
+    order: VecDeque<(u16, u32)>,
+}
+
+impl HistoryBudget {
+    /// Create a budget allowing `max_bytes` of retained history
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            used_bytes: 0,
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Create a budget allowing `max_bytes` of retained history that already tracks the
+    /// history of `accounts`, such as accounts loaded from a snapshot. Their transactions
+    /// are taken to be oldest first in client and then transaction ID order
+    pub fn with_history(max_bytes: usize, accounts: &BTreeMap<u16, Account>) -> Self {
+        let mut budget = Self::new(max_bytes);
+        for transaction in accounts.values().flat_map(|account| account.transactions.values()) {
+            budget.record(transaction);
+        }
+        budget
+    }
+
+    /// Take over the history tracked by another budget, whose transactions are in
+    /// other accounts, along with its allowance, as when their ledgers are merged
+    pub fn merge(&mut self, other: HistoryBudget) {
+        self.max_bytes += other.max_bytes;
+        self.used_bytes += other.used_bytes;
+        self.order.extend(other.order);
+    }
+
+    /// Track a transaction that has just been recorded in an account's history
+    pub fn record(&mut self, transaction: &Transaction) {
+        self.used_bytes += transaction.estimated_size();
+        self.order.push_back((transaction.client_id, transaction.id));
+    }
+
+    /// Forget the oldest undisputed transactions until the history fits the budget,
+    /// returning the client and transaction ID of each transaction forgotten
+    pub fn prune(&mut self, accounts: &mut BTreeMap<u16, Account>) -> Vec<(u16, u32)> {
+        let mut pruned = Vec::new();
+        // Disputed transactions are skipped over and put back in their original place
+        let mut kept: Vec<(u16, u32)> = Vec::new();
+        while self.used_bytes > self.max_bytes {
+            let Some((client, tx)) = self.order.pop_front() else { break };
+            let Some(account) = accounts.get_mut(&client) else { continue };
+            match account.transactions.get(&tx) {
+                Some(transaction) if transaction.dispute_state == DisputeState::Disputed => kept.push((client, tx)),
+                Some(_) => {
+                    let transaction = account.transactions.remove(&tx).expect("Transaction was just found");
+                    self.used_bytes -= transaction.estimated_size();
+                    pruned.push((client, tx));
+                },
+                None => {}
+            }
+        }
+        for entry in kept.into_iter().rev() {
+            self.order.push_front(entry);
+        }
+        pruned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::TransactionType;
+
+    fn deposit(id: u32) -> Transaction {
+        Transaction::new(id, 1, 10.into(), TransactionType::Deposit)
+    }
+
+    #[test]
+    fn prunes_oldest_transactions_but_keeps_disputed() {
+        let mut accounts = BTreeMap::new();
+        let account = accounts.entry(1).or_insert_with(|| Account::new(1));
+        let mut budget = HistoryBudget::new(deposit(1).estimated_size() * 2);
+        for id in 1..=3 {
+            account.register_transaction(deposit(id));
+            budget.record(&deposit(id));
+        }
+        account.dispute_transaction(1);
+
+        assert_eq!(budget.prune(&mut accounts), vec![(1, 2)]);
+        let account = &accounts[&1];
+        assert!(account.transactions.contains_key(&1));
+        assert!(!account.transactions.contains_key(&2));
+        assert!(account.transactions.contains_key(&3));
+        // Pruning only forgets history; balances are untouched
+        assert_eq!(account.available_balance, 20.into());
+        assert_eq!(account.held_balance, 10.into());
+    }
+
+    #[test]
+    fn tracks_existing_history() {
+        let mut accounts = BTreeMap::new();
+        let account = accounts.entry(1).or_insert_with(|| Account::new(1));
+        for id in 1..=3 {
+            account.register_transaction(deposit(id));
+        }
+        let mut budget = HistoryBudget::with_history(deposit(1).estimated_size() * 2, &accounts);
+
+        assert_eq!(budget.prune(&mut accounts), vec![(1, 1)]);
+        assert!(!accounts[&1].transactions.contains_key(&1));
+    }
+
+    #[test]
+    fn does_not_prune_within_budget() {
+        let mut accounts = BTreeMap::new();
+        let account = accounts.entry(1).or_insert_with(|| Account::new(1));
+        let mut budget = HistoryBudget::new(deposit(1).estimated_size() * 2);
+        for id in 1..=2 {
+            account.register_transaction(deposit(id));
+            budget.record(&deposit(id));
+        }
+
+        assert!(budget.prune(&mut accounts).is_empty());
+        assert_eq!(accounts[&1].transactions.len(), 2);
+    }
+}
@@ -0,0 +1,66 @@
+use std::fs::File;
+use std::io::{ self, BufReader, Read };
+use flate2::read::MultiGzDecoder;
+
+/// Open the input at `location` for reading. This is normally a file path,
+/// but with the `http` feature enabled it may also be an HTTP(S) URL, whose
+/// body is streamed rather than downloaded up front. Inputs ending in `.gz`
+/// are decompressed as they are read.
+pub fn open_input(location: &str) -> io::Result<Box<dyn Read>> {
+    let input = if is_url(location) {
+        open_url(location)?
+    } else {
+        Box::new(File::open(location)?)
+    };
+    if is_gzip(location) {
+        Ok(Box::new(MultiGzDecoder::new(BufReader::new(input))))
+    } else {
+        Ok(input)
+    }
+}
+
+/// Whether the input location names a gzip-compressed file
+fn is_gzip(location: &str) -> bool {
+    location.ends_with(".gz")
+}
+
+/// Whether the input location is a URL rather than a file path
+fn is_url(location: &str) -> bool {
+    location.starts_with("http://") || location.starts_with("https://")
+}
+
+/// Fetch the input from a URL
+#[cfg(feature = "http")]
+fn open_url(url: &str) -> io::Result<Box<dyn Read>> {
+    let response = ureq::get(url).call()
+        .map_err(io::Error::other)?;
+    Ok(Box::new(response.into_reader()))
+}
+
+/// Fetching from a URL requires the `http` feature
+#[cfg(not(feature = "http"))]
+fn open_url(url: &str) -> io::Result<Box<dyn Read>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported,
+        format!("cannot fetch {}: built without the `http` feature", url)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_urls() {
+        assert!(is_url("http://example.com/input.csv"));
+        assert!(is_url("https://example.com/input.csv"));
+        assert!(!is_url("input.csv"));
+        assert!(!is_url("/data/http/input.csv"));
+    }
+
+    #[test]
+    fn detects_gzip() {
+        assert!(is_gzip("input.csv.gz"));
+        assert!(is_gzip("https://example.com/input.csv.gz"));
+        assert!(!is_gzip("input.csv"));
+        assert!(!is_gzip("input.gz.csv"));
+    }
+}
@@ -0,0 +1,38 @@
+//! # Financial Assessment e2324103
+//! 
+//! A transaction engine that processes a list of transactions, including deposits, withdrawals,
+//! and various dispute actions on the above, and computes the final state of accounts after those
+//! transactions are applied.
+//! 
+//! The `financial-assessment-e2324103` binary wraps this library in a command line tool that
+//! reads transactions from a CSV file and writes the accounts as CSV. The engine can also be
+//! embedded directly: parse rows into `InputRow`s (or `Transaction`s and `DisputeAction`s), apply
+//! them to `Account`s, and convert the results to `OutputRow`s. Or hand `process_reader` raw CSV
+//! input and an `EngineConfig`, and get the final accounts back.
+
+pub mod account;
+pub mod cli;
+pub mod config;
+pub mod csv_rows;
+pub mod engine;
+pub mod error;
+pub mod golden;
+pub mod history;
+pub mod input;
+pub mod money;
+pub mod prescan;
+pub mod snapshot;
+pub mod summary;
+pub mod table;
+pub mod sink;
+pub mod throttle;
+pub mod transaction;
+pub mod validate;
+
+pub use account::{ Account, TransactionOutcome };
+pub use config::EngineConfig;
+pub use csv_rows::{ InputRow, OutputRow };
+pub use engine::{ Ledger, process, process_reader };
+pub use error::AppError;
+pub use money::Money;
+pub use transaction::{ Transaction, DisputeAction };
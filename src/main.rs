@@ -8,16 +8,31 @@
 //! 
 //!     financial-assessment-e2324103 input.csv
 //! 
-//! where `input.csv` is a CSV file with the following columns in order, with a header row
-//! and one row per transaction:
+//! or, to read the transactions from stdin,
 //! 
-//! - `type`: one of `deposit`, `withdrawal`, `dispute`, `resolve`, or `chargeback`
+//!     cat input.csv | financial-assessment-e2324103
+//! 
+//! where `input.csv` is a CSV file with the following columns, with a header row naming them
+//! (in any order) and one row per transaction:
+//! 
+//! - `type`: one of `deposit`, `withdrawal`, `dispute`, `resolve`, `chargeback`, `open`, `accrue`,
+//!   `interest`, `hold`, or `release`
 //! - `client`: the account number the transaction is applied to, from 0-65535
 //! - `tx`: For `deposit` and `withdrawal` transactions, a unique ID number
 //!   (from 0-4294967295) for the transaction. For `dispute`, `resolve`, or `chargeback`
 //!   entries, the transaction ID under dispute.
 //! - `amount`: For `deposit` and `withdrawal` transactions, the amount being withdrawn
-//!   or deposited. Optional and ignored for `dispute`, `resolve`, and `chargeback`.
+//!   or deposited. For `accrue` and `interest`, the interest rate applied to the available
+//!   balance (e.g. `0.015` for 1.5%). For `hold` and `release`, the amount held or released.
+//!   Optional and ignored for `dispute`, `resolve`, `chargeback`, and `open`.
+//! - `timestamp`: Optional. When the row took place, as an RFC 3339 timestamp such as
+//!   `2024-03-01T12:00:00Z`. Only used by `--since` and `--until`.
+//! - `currency`: Optional. The currency code of the amount, e.g. `USD`. An account takes the
+//!   currency of its first deposit that gives one, after which deposits and withdrawals in any
+//!   other currency are rejected with a warning. Rows without a currency are always accepted.
+//! 
+//! The `amount`, `timestamp`, and `currency` columns may be left out entirely, but an input missing any of the others is rejected
+//! before any of its rows are processed.
 //! 
 //! The output is a CSV file with the following columns, with a header row and one row
 //! per account:
@@ -27,65 +42,592 @@
 //! - `total`: The total balance the account has
 //! - `locked`: Whether or not the account has been frozen by a successful chargeback
 //!   (meaning that future deposits and withdrawals are disabled)
+//! - `currency`: The account's currency, or empty if it has none. Only present if any
+//!   account has a currency
+//! 
+//! All amounts are accurate to four decimal places (see `--decimals`), and are always written out
+//! with exactly that many.
+//! 
+//! If `input.csv` is a directory, every file within it is processed in name order, as if
+//! the files were concatenated.
+//! 
+//! Inputs whose names end in `.gz` are decompressed as they are read.
+//! 
+//! When built with the `http` feature, `input.csv` may also be an `http://` or `https://` URL,
+//! which is fetched and processed as it streams in.
+//! 
+//! ## Options
+//! 
+//! - `--decimals <n>`: Round input amounts to `n` decimal places, and write output amounts
+//!   with exactly `n`, rather than four. `n` must be between 0 and 10, e.g. 0 for a currency
+//!   without subunits, or 8 for a cryptocurrency.
+//! - `--delimiter <,|;|\t>`: The character separating the fields of the input, and of the
+//!   output when it is CSV: a comma (the default), a semicolon, or a tab, given as `\t`.
+//! - `--dry-apply <type,client,tx,amount>`: After processing, report on stderr what would
+//!   happen if the given deposit or withdrawal were applied to the client's final state,
+//!   without including it in the output. Its amount is checked against `--max-amount` and
+//!   `--max-input-scale` like any input amount, and an invalid
+//!   one fails the run before any input is read.
+//! - `--assert-state <path>`: After writing the output, compare the accounts output with the
+//!   expected accounts in the CSV at `path`, written by this tool with the same columns, matching
+//!   them by client. If they differ, each differing row is reported on stderr, as `- ` for the
+//!   expected row and `+ ` for the one output in its place, and the run fails. Amounts only need
+//!   to be equal, not written with the same decimal places.
+//! - `--audit-precision-loss`: After processing, report on stderr how many deposits and
+//!   withdrawals had amounts changed by rounding to `--decimals` places, and the total of the
+//!   parsed amounts minus the stored amounts.
+//! - `--buffer-out-of-order-disputes`: Hold on to resolves and chargebacks for transactions
+//!   that are not under dispute, and apply them once a dispute on that transaction opens.
+//! - `--check`: Only check the input, without processing it or writing the accounts. Every row
+//!   must parse, each deposit and withdrawal must have a transaction ID of its own, and each
+//!   dispute, resolve, and chargeback must name an earlier transaction of the same client. The
+//!   first 20 invalid rows are reported on stderr with their line numbers, and any invalid row
+//!   fails the run.
+//! - `--check-unique-tx`: Before processing, scan the input for deposits and withdrawals
+//!   that reuse a transaction ID, reporting each on stderr with its line numbers. With
+//!   `--strict`, any duplicate fails the run before processing.
+//! - `--client <id>`: Only output the account for client `id`. May be given several times to
+//!   output several accounts. Every row is still processed, since disputes may refer back to
+//!   transactions on other rows.
+//! - `--control-total <amount>`: Fail the run, without output, unless the deposits in the
+//!   input sum to exactly `amount`.
+//! - `--diff-against <path>`: Only output the accounts whose `available`, `held`, or `locked`
+//!   values differ from those in the snapshot at `path`, written by `--snapshot-out`, along with
+//!   accounts the snapshot doesn't have. Usually given the same snapshot as `--snapshot-in`, to
+//!   report only the accounts the input changed.
+//! - `--dispute-lenient-client`: Apply disputes, resolves, and chargebacks that name a client other
+//!   than the one whose account holds the transaction to that account instead, logging each
+//!   correction as a warning. Without this option they are ignored with a warning. Cannot be
+//!   combined with `--threads` or `--parallel-files`.
+//! - `--enforce-unique-tx`: Ignore, with a warning, any deposit or withdrawal whose transaction ID
+//!   was already used by a transaction on any account, including accounts loaded with `--snapshot-in`.
+//!   Without this option, IDs only need to be unique within each account. Cannot be combined with
+//!   `--threads` or `--parallel-files`.
+//! - `--export-transactions <path>`: Also write every stored transaction to a CSV at `path`,
+//!   with columns `client`, `tx`, `type`, `amount`, and final `dispute_state` (`undisputed`,
+//!   `disputed`, or `charged_back`).
+//! - `--fail-on-negative`: Fail the run, without output, if any account ends with a negative
+//!   available balance. Such accounts are always reported on stderr, with or without this option,
+//!   and can be flagged in the output with `--show-negative`.
+//! - `--format <csv|json|table|bincode>`: The output format. `csv` (the default) is described
+//!   above; `json` writes an array of objects with the same fields, with amounts as strings to
+//!   keep their precision; `table` writes an aligned table of the same columns for reading in a
+//!   terminal; and `bincode` encodes the rows as a bincode `Vec<OutputRow>` for handoff to another
+//!   Rust service, always including the `--verbose-columns`, `--show-tx-count`, `--show-net`,
+//!   `--show-negative`, and `currency` fields, and with amounts as strings.
+//! - `--freeze-after-disputes <n>`: Freeze an account once `n` disputes have been opened on it,
+//!   whatever their outcome, as if it had been charged back. Its `lock_reason` is `disputes:<n>`.
+//! - `--hard-freeze`: Once an account is frozen, also ignore any `dispute`, `resolve`, or
+//!   `chargeback` of its transactions, and any `hold` or `release` of its funds, so that nothing
+//!   about it can change. By default, frozen accounts only ignore deposits and withdrawals.
+//! - `--held-interest-rate <rate>`: The interest rate applied to held funds by `accrue` rows.
+//!   Defaults to 0, so held funds earn no interest.
+//! - `--held-interest-to <available|held>`: Whether interest earned on held funds is credited
+//!   to the available balance (the default) or kept held.
+//! - `--max-history-bytes <bytes>`: Limit the estimated memory used by transaction history
+//!   across all accounts and inputs, including any history loaded with `--snapshot-in`. Past the
+//!   limit, the oldest transactions that are not under dispute are forgotten, and can no longer
+//!   be disputed or detected as duplicates. With `--parallel-files`, each file gets an equal
+//!   share of the budget.
+//! - `--input-format <csv|jsonl>`: The input format. `csv` (the default) is described above;
+//!   `jsonl` reads one JSON object per line, with the same fields as the CSV columns, e.g.
+//!   `{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}`. Amounts may be JSON numbers,
+//!   but are best written as strings, as numbers only keep the precision of an `f64`. Cannot be
+//!   combined with `--threads`, `--parallel-files`, `--check`, `--check-unique-tx`, or
+//!   `--delimiter`.
+//! - `--locale <en|de>`: How input amounts group thousands and mark decimals: `1,234.56` in
+//!   `en`, the default, or `1.234,56` in `de`. Amounts with misplaced thousands separators,
+//!   such as `1.5` in `de`, are rejected as malformed rather than guessed at. Amounts containing
+//!   the field delimiter must be quoted, e.g. `"1,234.56"`.
+//! - `--ledger <path>`: Another name for `--export-transactions`.
+//! - `--max-rows <n>`: Read at most `n` rows across all of the inputs, to guard against
+//!   accidentally processing a huge file. What happens to the rows beyond the limit depends on
+//!   `--on-limit`. With `--parallel-files`, each file is limited to `n` rows on its own.
+//! - `--max-amount <value>`: Reject deposits and withdrawals whose amount is more than `value`.
+//!   Regardless of this option, amounts with more than 64 digits are rejected as malformed.
+//! - `--max-client-rate <n>`: Limit each client to `n` deposits and withdrawals within any one
+//!   second, going by the rows' timestamps. Rows without a timestamp don't count towards the
+//!   limit. The clients that go over it are reported on stderr once processing is done, and
+//!   what happens to their transactions beyond the limit depends on `--on-client-rate`. Cannot
+//!   be combined with `--parallel-files`.
+//! - `--max-input-scale <n>`: Reject (rather than round) deposits and withdrawals whose
+//!   amount has more than `n` decimal places.
+//! - `--minor-units <scale>`: Read amounts as whole numbers of minor units with `scale` decimal
+//!   places each, e.g. `1050` with a scale of 2 is `10.50`. Amounts with a decimal point are
+//!   rejected. The rates of `accrue` and `interest` rows are read as decimals as usual.
+//! - `--normalize-on-dispute`: Round the available and held balances to the `--decimals`
+//!   places after every dispute, resolve, and chargeback, in case a fee, interest, or opening
+//!   balance left them at some other precision.
+//! - `--omit-dispute-only-accounts`: Has no effect, and is only accepted for compatibility.
+//!   Accounts are never created by `dispute`, `resolve`, or `chargeback` rows.
+//! - `--on-client-rate <flag|reject>`: What to do with the deposits and withdrawals of a client
+//!   going over `--max-client-rate`. `flag` (the default) applies them and only reports the
+//!   client, and `reject` ignores them as well. Ignored transactions still count towards the
+//!   limit, so a client has to slow down for its transactions to be applied again.
+//! - `--on-error <skip|warn|abort>`: What to do with a row that is malformed or is not a known
+//!   action, such as a non-numeric client or an unknown type. `warn` (the default) reports the
+//!   row on stderr and skips it, `skip` skips it silently, and `abort` stops with an error.
+//! - `--on-limit <abort|truncate>`: What to do when the inputs have more rows than `--max-rows`.
+//!   `abort` (the default) stops with an error, without output, and `truncate` reports the limit
+//!   on stderr and ignores the rest of the inputs.
+//! - `--on-invariant-violation <panic|warn|skip>`: What to do if a resolve or chargeback finds
+//!   the held balance in an impossible state. `warn` (the default) reports the problem on
+//!   stderr and skips the row, `skip` skips the row silently, and `panic` stops immediately.
+//! - `--order <client|first-seen>`: The order to list the accounts in: by client ID (the
+//!   default), or by the first input row naming each client. Accounts loaded with
+//!   `--snapshot-in` and not named in the input come last, by client ID.
+//! - `--output <path>`: Write the output to the file at `path` rather than to stdout. Warnings
+//!   and reports still go to stderr, so the file holds only the accounts.
+//! - `--overdraft <amount>`: Allow withdrawals to take an account's available balance as far
+//!   as `amount` below zero. Deposits can still only be disputed while the available balance
+//!   covers them, so an overdrawn account's deposits cannot be disputed.
+//! - `--parallel-files <threads>`: When processing a directory, process the files independently
+//!   on up to `threads` threads and merge the results. Intended for files with disjoint sets
+//!   of clients; an account appearing in several files has its balances summed.
+//! - `--replay-speed <rows>`: Process at most `rows` rows per second, to simulate realistic
+//!   traffic when replaying a feed. Unthrottled by default.
+//! - `--require-open`: Reject deposits and withdrawals for an account until an `open` row
+//!   for it has been processed. Without this option, `open` rows have no effect.
+//! - `--rounding <half-up|half-even|truncate>`: How amounts with more than `--decimals` places
+//!   are rounded: to the nearest place with halves rounded away from zero (`half-up`, the
+//!   default), to the nearest place with halves rounded to an even last digit (`half-even`, or
+//!   banker's rounding), or towards zero (`truncate`). Applies to every input amount, including
+//!   opening balances, but not to interest, which is always rounded half up.
+//! - `--sink <url>`: After processing, also write each output account to an external store.
+//!   When built with the `redis` feature, this may be a `redis://` URL; each account is stored
+//!   as a hash keyed `client:<id>`, with the output columns as fields.
+//! - `--seed-accounts <path>`: Before processing, give accounts the opening balances in `path`,
+//!   a CSV file with `client` and `opening_balance` columns. Each balance is added to the
+//!   account's available balance, on top of any `--snapshot-in`, and the account is opened. An
+//!   opening balance is not a transaction, so it can't be disputed. Cannot be combined with
+//!   `--parallel-files`.
+//! - `--self-check`: After processing, check that every account's held balance is not
+//!   negative and equals what its open disputes and manual holds have placed on hold,
+//!   reporting each account that fails. With `--strict`, any failure fails the run. Cannot
+//!   be combined with `--held-interest-to held`, whose interest is held outside any hold.
+//! - `--show-net`: Add a `net` column to the output with each account's net position for risk
+//!   reporting: its balance less anything it owes. For now this is the same as the `total`.
+//! - `--show-negative`: Add a `negative` column to the output, `true` for each account whose
+//!   available balance is negative, as an overdraft or withdrawal fee can leave it.
+//! - `--show-tx-count`: Add a `tx_count` column to the output, counting the transactions stored
+//!   for each account. Transactions forgotten under `--max-history-bytes` are not counted.
+//! - `--since <timestamp>`: Only apply rows timestamped at or after `timestamp`, given in RFC 3339
+//!   format. Rows without a timestamp are always applied. Disputes, resolves, and chargebacks
+//!   naming a transaction left out of the window are ignored, as the transaction is unknown.
+//! - `--snapshot-in <path>`: Before processing, load the accounts, with their transaction
+//!   histories and any buffered dispute actions, from a snapshot written by `--snapshot-out`,
+//!   so that the input continues from the previous run. Deposits and withdrawals for an account
+//!   that was already frozen in the snapshot are reported on stderr as they are ignored. Cannot
+//!   be combined with `--parallel-files`.
+//! - `--snapshot-out <path>`: After processing, write the accounts, with their transaction
+//!   histories and any buffered dispute actions, to a snapshot at `path`. Snapshots are versioned,
+//!   and a snapshot written by an incompatible version is rejected.
+//! - `--strict`: Fail the run, rather than only reporting, when an integrity check fails.
+//! - `--strict-disputes`: Apply each kind of dispute action (`dispute`, `resolve`, or `chargeback`)
+//!   to a transaction at most once. A repeated action is ignored even if it would otherwise apply,
+//!   such as a `dispute` re-sent after its transaction was resolved, so that replaying a feed
+//!   can't count a dispute twice.
+//! - `--summary`: After the accounts, write a blank line and then the totals of the `available`,
+//!   `held`, and `total` balances of the accounts output, as CSV with a `currency` column. If any
+//!   account has a currency, there is a row for each currency, with accounts without one totalled
+//!   under an empty currency, and the last row, with the currency `all`, totals every account.
+//!   Requires `--format csv`, unless the totals are written to `--summary-out`.
+//! - `--summary-out <path>`: Write the `--summary` totals to the file at `path` instead, in any
+//!   output format.
+//! - `--threads <threads>`: Spread the accounts across `threads` worker threads, each owning
+//!   the clients whose number modulo `threads` is its own, while the input is read on the main
+//!   thread. The output is the same as without this option, except with `--max-history-bytes`:
+//!   each worker prunes against an equal share of the budget, so which transactions are pruned,
+//!   and so which disputes still apply, depends on how the clients are spread across workers.
+//!   Cannot be combined with `--parallel-files`.
+//! - `--total-excludes-held`: Report only the available balance as the `total`, treating held
+//!   funds as pending. The held balance is still reported in its own column.
+//! - `--trace <tx>`: Log every decision made about the transaction `tx` to stderr, such as
+//!   whether it passed the duplicate check and why each dispute action on it was or wasn't applied.
+//! - `--until <timestamp>`: Only apply rows timestamped before `timestamp`, given in RFC 3339
+//!   format. Rows without a timestamp are always applied.
+//! - `--progress-every <n>`: Report on stderr every `n` rows how many rows have been processed
+//!   and how many clients have been seen so far, to follow the progress of large inputs. With
+//!   `--parallel-files`, each file is counted separately.
+//! - `--quiet`: Write nothing to stderr: no log messages, whatever `RUST_LOG` says, and no
+//!   usage hint when the arguments are malformed. Reports asked for by other options, such as
+//!   `--check` and `--dry-apply`, are still written. Cannot be combined with `--verbose`.
+//! - `--verbose`: After processing, print a one-line summary to stderr counting the rows read,
+//!   the transactions and dispute actions applied, and the rows ignored, by reason.
+//!   While processing, also report each withdrawal rejected for insufficient funds, with its
+//!   client, transaction ID, and amount, and the available balance at the time.
+//! - `--verbose-columns`: Add diagnostic columns to the output:
+//!   - `lock_reason`: Why the account is locked, e.g. `chargeback:<tx>` or `disputes:<n>`, or empty if unlocked
+//!   - `last_seq`: The position (counting data rows from 1) of the last input row that changed
+//!     the account
+//! - `--withdrawal-fee <amount>`: Take a flat fee of `amount` from the available balance with
+//!   each withdrawal. A withdrawal is only applied if the available balance (plus any `--overdraft`)
+//!   covers both it and the fee. The total of the fees collected is printed to stderr.
+//! 
+//! ## Logging
+//! 
+//! Warnings and errors, such as skipped rows and unreadable files, are logged to stderr, while
+//! the output itself only ever goes to stdout (or `--output`). With `--verbose`, informational
+//! messages such as withdrawals and disputes rejected for insufficient funds, and amounts
+//! rounded to fewer decimal places than they were given with, are logged too.
+//! The `RUST_LOG` environment variable overrides these levels, e.g. `RUST_LOG=debug` also logs
+//! every valid row that was ignored, such as a dispute of an unknown transaction, and
+//! `RUST_LOG=error` silences the warnings. `--quiet` turns logging off entirely.
 //! 
-//! All amounts are accurate to four decimal places.
+//! ## Exit Codes
+//! 
+//! - `0`: Success
+//! - `1`: The command line arguments were malformed
+//! - `2`: An input or output file could not be opened, read, or written, the `--sink`
+//!   could not be written to, the input lacks a required column, a bad row was read with
+//!   `--on-error abort`, or the inputs had more rows than `--max-rows` with `--on-limit abort`
+//! - `3`: An integrity check (`--check`, `--control-total`, `--assert-state`, `--check-unique-tx`
+//!   or `--self-check` with `--strict`, or `--fail-on-negative`) failed
 
-mod account;
-mod csv_rows;
-mod transaction;
+use std::fs::File;
+use std::io::{ BufReader, BufWriter, Read, Write };
+use std::path::Path;
 
-use std::collections::BTreeMap;
+use num_traits::Zero;
 
-use account::Account;
-use csv::Trim;
-use transaction::{ Transaction, DisputeAction, DisputeActionType };
-use csv_rows::{ InputRow, OutputRow };
+use financial_assessment_e2324103::{ cli, engine, golden, input, prescan, sink, snapshot, summary, table };
+use financial_assessment_e2324103::validate::Validator;
+use financial_assessment_e2324103::cli::{ CliOptions, InputFormat, OutputFormat };
+use financial_assessment_e2324103::{ Account, AppError, Ledger, OutputRow };
+use financial_assessment_e2324103::csv_rows::OutputColumns;
 
 /// Application entry point
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: cargo run -- filename.csv");
+    let mut args = std::env::args();
+    let program = args.next().unwrap_or_else(|| env!("CARGO_PKG_NAME").to_string());
+    let args: Vec<String> = args.collect();
+    let options = cli::parse_args(args.clone()).map_err(AppError::Usage);
+    // Even malformed arguments are reported silently if --quiet was asked for, unless
+    // the problem is that it was given with --verbose
+    let quiet = match &options {
+        Ok(options) => options.quiet,
+        Err(_) => args.iter().any(|arg| arg == "--quiet") && !args.iter().any(|arg| arg == "--verbose")
+    };
+    init_logger(options.as_ref().is_ok_and(|options| options.verbose), quiet);
+    if let Err(err) = options.and_then(run) {
+        log::error!("{}", err);
+        if matches!(err, AppError::Usage(_)) && !quiet {
+            eprintln!("Usage: {} [filename.csv] [options]", program);
+        }
+        std::process::exit(err.exit_code());
+    }
+}
+
+/// Send log messages to stderr. Unless `RUST_LOG` says otherwise, warnings and
+/// errors are logged, and with `--verbose` informational messages too. With
+/// `--quiet`, nothing is logged whatever `RUST_LOG` says
+fn init_logger(verbose: bool, quiet: bool) {
+    if quiet {
+        // Without a logger, every log message is discarded
         return;
     }
-    let filename = &(std::env::args().collect::<Vec<String>>()[1]);
-    let mut reader = csv::ReaderBuilder::new()
-        .flexible(true)
-        .trim(Trim::All)
-        .from_path(filename)
-        .expect("File does not exist");
-
-    // Keep a dictionary of accounts by account number
-    let mut accounts: BTreeMap<u16, Account> = BTreeMap::new();
-    // Read and process each transaction row one at a time
-    for result in reader.deserialize() {
-        // NOTE: This could be parallelized - multiple accounts do not interact.
-        // However, since the application is mostly IO, there isn't much to gain here
-        let input_row: InputRow = result.expect("IO error when reading file");
-        
-        // Load the account, creating it if it does not exist
-        if !accounts.contains_key(&input_row.client) {
-            accounts.insert(input_row.client, Account::new(input_row.client));
+    let default_level = if verbose { "info" } else { "warn" };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_level))
+        .format_timestamp(None)
+        .format_target(false)
+        .init();
+}
+
+/// Process the input and write the output as the command line options say
+fn run(options: CliOptions) -> Result<(), AppError> {
+    // Continue from the previous run's state, if given
+    let mut ledger = Ledger::default();
+    if let Some(path) = &options.snapshot_in {
+        let file = File::open(path).map_err(|err| AppError::OpenFile(path.clone(), err))?;
+        ledger = snapshot::read_snapshot(BufReader::new(file)).map_err(|err| AppError::OpenFile(path.clone(), err))?;
+        for account in ledger.accounts.values_mut() {
+            options.engine.configure_account(account);
+        }
+    }
+    // Start the accounts from their opening balances, if given
+    if let Some(path) = &options.seed_accounts {
+        let mut reader = engine::open_reader_with_delimiter(path, options.delimiter.unwrap_or(engine::DEFAULT_DELIMITER))?;
+        for (client, opening_balance) in engine::read_opening_balances(&mut reader, options.engine.decimals, options.engine.rounding)? {
+            ledger.seed_account(client, &opening_balance, &options.engine);
+        }
+    }
+
+    if options.check {
+        return check_input(&options, &ledger);
+    }
+
+    let delimiter = options.delimiter.unwrap_or(engine::DEFAULT_DELIMITER);
+
+    let ledger = match &options.filename {
+        Some(filename) => process_location(filename, &options, ledger)?,
+        None => {
+            if options.check_unique_tx {
+                return Err(AppError::Usage("--check-unique-tx requires an input file rather than stdin".to_string()));
+            }
+            let reader = || engine::csv_reader_with_delimiter(Box::new(std::io::stdin()), delimiter);
+            match (options.input_format, options.threads) {
+                (InputFormat::Jsonl, _) => {
+                    engine::process_jsonl_into(std::io::stdin(), &options.engine, &mut ledger)?;
+                    ledger
+                },
+                (InputFormat::Csv, Some(threads)) => engine::process_sharded([Ok(reader())], &options.engine, threads, ledger)?,
+                (InputFormat::Csv, None) => {
+                    engine::process_into(&mut reader(), &options.engine, &mut ledger)?;
+                    ledger
+                }
+            }
+        }
+    };
+
+    if options.verbose {
+        eprintln!("{}", ledger.stats);
+    }
+    if options.engine.audit_precision_loss {
+        eprintln!("{}", ledger.precision_loss);
+    }
+    if !options.engine.withdrawal_fee.is_zero() && !options.quiet {
+        eprintln!("Collected {:.*} in withdrawal fees", options.engine.decimals as usize, ledger.fees_collected);
+    }
+
+    if let Some(max_rate) = options.engine.max_client_rate {
+        for client in &ledger.over_rate_clients {
+            log::warn!("Client {} went over the limit of {} transactions per second", client, max_rate);
+        }
+    }
+
+    let negative_balances = engine::find_negative_balances(&ledger.accounts);
+    for negative_balance in &negative_balances {
+        log::warn!("{}", negative_balance);
+    }
+    if options.fail_on_negative && !negative_balances.is_empty() {
+        return Err(AppError::CheckFailed(format!("{} accounts have a negative available balance", negative_balances.len())));
+    }
+
+    if options.self_check {
+        let violations = engine::find_invariant_violations(&ledger.accounts);
+        for violation in &violations {
+            log::error!("{}", violation);
+        }
+        if options.strict && !violations.is_empty() {
+            return Err(AppError::CheckFailed(format!("{} accounts failed the self-check", violations.len())));
+        }
+    }
+
+    if let Some(control_total) = &options.control_total {
+        engine::check_control_total(control_total, &ledger.deposit_total).map_err(AppError::CheckFailed)?;
+    }
+
+    // Report the effect of the hypothetical transaction, if one was requested
+    if let Some(dry_apply_row) = &options.dry_apply {
+        let client = dry_apply_row.client;
+        let transaction = dry_apply_row.clone().into_transaction(options.engine.max_input_scale,
+            options.engine.max_amount.as_ref(), options.engine.decimals, options.engine.rounding)
+            .expect("--dry-apply transaction is validated while parsing arguments");
+        let report = ledger.accounts.get(&client).cloned()
+            .unwrap_or_else(|| Account::new(client))
+            .dry_apply(transaction);
+        eprintln!("{:.*}", options.engine.decimals as usize, report);
+    }
+
+    if let Some(path) = &options.export_transactions {
+        let file = std::fs::File::create(path).map_err(|err| AppError::OpenFile(path.clone(), err))?;
+        engine::write_transactions(&ledger.accounts, file).map_err(AppError::WriteOutput)?;
+    }
+
+    if let Some(path) = &options.snapshot_out {
+        let write_error = |err| AppError::WriteSnapshot(path.clone(), err);
+        let mut file = BufWriter::new(File::create(path).map_err(|err| AppError::OpenFile(path.clone(), err))?);
+        snapshot::write_snapshot(&ledger, &mut file).map_err(write_error)?;
+        file.flush().map_err(write_error)?;
+    }
+
+    // Only output the accounts that changed since the baseline, if given
+    let changed_clients = match &options.diff_against {
+        Some(path) => {
+            let file = File::open(path).map_err(|err| AppError::OpenFile(path.clone(), err))?;
+            let baseline = snapshot::read_snapshot(BufReader::new(file)).map_err(|err| AppError::OpenFile(path.clone(), err))?;
+            Some(engine::changed_clients(&ledger.accounts, &baseline.accounts))
+        },
+        None => None
+    };
+
+    // Write the final state of all accounts to stdout, or the output file if given
+    // Bincode output always has the optional columns, so that its layout is fixed
+    let bincode = options.format == OutputFormat::Bincode;
+    let columns = OutputColumns {
+        verbose: options.verbose_columns || bincode,
+        tx_count: options.show_tx_count || bincode,
+        net: options.show_net || bincode,
+        negative: options.show_negative || bincode,
+        currency: ledger.accounts.values().any(|account| account.currency.is_some()) || bincode,
+        total_excludes_held: options.total_excludes_held,
+        decimals: options.engine.decimals,
+    };
+    let output_rows: Vec<OutputRow> = ledger.into_output_rows(options.order, columns)
+        .filter(|output_row| options.client_filter.is_empty() || options.client_filter.contains(&output_row.client))
+        .filter(|output_row| changed_clients.as_ref().is_none_or(|changed| changed.contains(&output_row.client)))
+        .collect();
+    let summary = options.summary.then(|| summary::summarize(&output_rows));
+    let state_diff = match &options.assert_state {
+        Some(path) => {
+            let expected = golden::read_expected(&mut engine::open_reader_with_delimiter(path, delimiter)?).map_err(AppError::ReadRow)?;
+            golden::diff_rows(&expected, &output_rows)
+        },
+        None => Vec::new()
+    };
+    if let Some(location) = &options.sink {
+        sink::write_sink(location, &output_rows).map_err(|err| AppError::WriteSink(location.clone(), err))?;
+    }
+    let mut output: Box<dyn Write> = match &options.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path).map_err(|err| AppError::OpenFile(path.clone(), err))?)),
+        None => Box::new(std::io::stdout().lock())
+    };
+    match options.format {
+        OutputFormat::Csv => {
+            let mut writer = csv::WriterBuilder::new().delimiter(delimiter).from_writer(&mut output);
+            for output_row in output_rows {
+                writer.serialize(output_row).map_err(AppError::WriteOutput)?;
+            }
+            writer.flush().map_err(|err| AppError::WriteOutput(err.into()))?;
+        },
+        OutputFormat::Json => {
+            serde_json::to_writer(&mut output, &output_rows).map_err(std::io::Error::from)
+                .and_then(|_| writeln!(output))
+                .map_err(|err| AppError::WriteOutput(err.into()))?;
+        },
+        OutputFormat::Table => {
+            write!(output, "{}", table::render_table(&output_rows)).map_err(|err| AppError::WriteOutput(err.into()))?;
+        },
+        OutputFormat::Bincode => {
+            bincode::serialize_into(&mut output, &output_rows).map_err(std::io::Error::other)
+                .map_err(|err| AppError::WriteOutput(err.into()))?;
         }
-        let account: &mut Account = accounts.get_mut(&input_row.client).expect("Account should have been ensured by previous line");
-
-        // Attempt parsing as a transaction, then as a dispute, executing the action
-        // if either parse succeeds. Ignore all lines that do not specify appropriate actions.
-        if let Ok(transaction) = input_row.clone().try_into() as Result<Transaction, _> {
-            account.register_transaction(transaction);
-        } else if let Ok(dispute_action) = input_row.try_into() as Result<DisputeAction, _> {
-            match dispute_action.action_type {
-                DisputeActionType::Dispute => account.dispute_transaction(dispute_action.transaction_id),
-                DisputeActionType::Resolve => account.resolve_disputed_transaction(dispute_action.transaction_id),
-                DisputeActionType::Chargeback => account.chargeback_disputed_transaction(dispute_action.transaction_id)
+    }
+
+    // Write the totals to their own file, or after the accounts, separated by a blank line
+    if let Some(summary) = summary {
+        let summary_output: Box<dyn Write> = match &options.summary_out {
+            Some(path) => Box::new(File::create(path).map_err(|err| AppError::OpenFile(path.clone(), err))?),
+            None => {
+                writeln!(output).map_err(|err| AppError::WriteOutput(err.into()))?;
+                Box::new(&mut output)
             }
+        };
+        let mut writer = csv::WriterBuilder::new().delimiter(delimiter).from_writer(summary_output);
+        for summary_row in summary {
+            writer.serialize(summary_row).map_err(AppError::WriteOutput)?;
         }
+        writer.flush().map_err(|err| AppError::WriteOutput(err.into()))?;
     }
+    output.flush().map_err(|err| AppError::WriteOutput(err.into()))?;
+
+    // Fail once the output is written if it isn't what was expected, showing how it differs
+    if !state_diff.is_empty() {
+        for line in &state_diff {
+            eprintln!("{}", line);
+        }
+        return Err(AppError::CheckFailed(format!("The accounts differ from the expected state in {}",
+            options.assert_state.as_deref().unwrap_or_default())));
+    }
+    Ok(())
+}
+
+/// The number of invalid rows `--check` reports individually
+const CHECK_REPORT_LIMIT: usize = 20;
 
-    // Write the final state of all accounts as a CSV to stdout
-    let mut writer = csv::Writer::from_writer(std::io::stdout());
-    for (_, account) in accounts.into_iter() {
-        let output_row: OutputRow = account.into();
-        writer.serialize(output_row).expect("Error when serializing record");
+/// Check every row of the input without processing it, reporting the first
+/// invalid rows on stderr and failing if there are any
+fn check_input(options: &CliOptions, ledger: &Ledger) -> Result<(), AppError> {
+    let delimiter = options.delimiter.unwrap_or(engine::DEFAULT_DELIMITER);
+    let mut validator = Validator::from_ledger(ledger);
+    let mut reported = 0;
+    let mut check = |location: &str, mut reader: csv::Reader<Box<dyn Read>>| {
+        engine::check_headers(&mut reader)?;
+        let problems = validator.check(&mut reader, &options.engine, CHECK_REPORT_LIMIT - reported)
+            .map_err(AppError::ReadRow)?;
+        for problem in &problems {
+            eprintln!("{}: {}", location, problem);
+        }
+        reported += problems.len();
+        Ok::<(), AppError>(())
+    };
+    match &options.filename {
+        Some(location) => {
+            let locations = input_locations(location)
+                .map_err(|err| AppError::OpenFile(location.to_string(), err))?;
+            for location in &locations {
+                check(location, engine::open_reader_with_delimiter(location, delimiter)?)?;
+            }
+        },
+        None => check("stdin", engine::csv_reader_with_delimiter(Box::new(std::io::stdin()), delimiter))?
+    }
+    match validator.invalid_rows {
+        0 => Ok(()),
+        invalid_rows => Err(AppError::CheckFailed(format!("{} invalid rows found", invalid_rows)))
+    }
+}
+
+/// Process the input file or directory of files at `location`, continuing from `ledger`
+fn process_location(location: &str, options: &CliOptions, mut ledger: Ledger) -> Result<Ledger, AppError> {
+    let locations = input_locations(location)
+        .map_err(|err| AppError::OpenFile(location.to_string(), err))?;
+    let delimiter = options.delimiter.unwrap_or(engine::DEFAULT_DELIMITER);
+
+    if options.check_unique_tx {
+        let mut any_duplicates = false;
+        for location in &locations {
+            let mut reader = engine::open_reader_with_delimiter(location, delimiter)?;
+            let duplicates = prescan::find_duplicate_tx(&mut reader).map_err(AppError::ReadRow)?;
+            for duplicate in &duplicates {
+                eprintln!("{}: {}", location, duplicate);
+            }
+            any_duplicates |= !duplicates.is_empty();
+        }
+        if options.strict && any_duplicates {
+            return Err(AppError::CheckFailed("Duplicate transaction IDs found".to_string()));
+        }
+    }
+
+    match (options.parallel_files, options.threads) {
+        (Some(threads), _) => {
+            ledger.merge(engine::process_files_parallel(&locations, &options.engine, threads, delimiter)?);
+            Ok(ledger)
+        },
+        (None, Some(threads)) => {
+            let readers = locations.iter().map(|location| engine::open_reader_with_delimiter(location, delimiter));
+            engine::process_sharded(readers, &options.engine, threads, ledger)
+        },
+        (None, None) if options.input_format == InputFormat::Jsonl => {
+            for location in &locations {
+                let input = input::open_input(location)
+                    .map_err(|err| AppError::OpenFile(location.to_string(), err))?;
+                engine::process_jsonl_into(input, &options.engine, &mut ledger)?;
+            }
+            Ok(ledger)
+        },
+        (None, None) => {
+            for location in &locations {
+                let mut reader = engine::open_reader_with_delimiter(location, delimiter)?;
+                engine::process_into(&mut reader, &options.engine, &mut ledger)?;
+            }
+            Ok(ledger)
+        }
+    }
+}
+
+/// List the inputs to process: the given location itself, or if it is a
+/// directory, every file within it in name order
+fn input_locations(location: &str) -> std::io::Result<Vec<String>> {
+    if !Path::new(location).is_dir() {
+        return Ok(vec![location.to_string()]);
+    }
+    let mut locations = Vec::new();
+    for entry in std::fs::read_dir(location)? {
+        let path = entry?.path();
+        if path.is_file() {
+            locations.push(path.to_string_lossy().into_owned());
+        }
     }
+    locations.sort();
+    Ok(locations)
 }
@@ -0,0 +1,282 @@
+use std::fmt;
+use std::ops::{ Add, AddAssign, Sub, SubAssign };
+use std::str::FromStr;
+use bigdecimal::BigDecimal;
+use bigdecimal::num_bigint::BigInt;
+use num_traits::{ Signed, Zero };
+use serde::{ Deserialize, Serialize };
+
+/// An amount of money, held at no more than `Money::MAX_SCALE` decimal places.
+/// Amounts are rounded once, when they are made, usually to the scale the engine
+/// is configured with. Sums and differences are exact, so they never have more
+/// decimal places than the amounts they are made from
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(from = "BigDecimal", into = "BigDecimal")]
+pub struct Money(BigDecimal);
+
+/// How amounts with more decimal places than they are held at are rounded
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum RoundingMode {
+    /// Round to the nearest place, with halves rounded away from zero
+    #[default]
+    HalfUp,
+    /// Round to the nearest place, with halves rounded to an even last digit
+    HalfEven,
+    /// Drop the extra places, rounding towards zero
+    Truncate,
+}
+
+impl RoundingMode {
+    /// Round a decimal to `decimals` places
+    pub fn round(self, value: &BigDecimal, decimals: i64) -> BigDecimal {
+        // Scaling down drops the extra places, which truncates towards zero
+        let truncated = value.with_scale(decimals);
+        let remainder = (value - &truncated).abs();
+        let half = BigDecimal::new(BigInt::from(5), decimals + 1);
+        let away_from_zero = match self {
+            RoundingMode::HalfUp => remainder >= half,
+            RoundingMode::HalfEven => remainder > half
+                || (remainder == half && truncated.as_bigint_and_exponent().0 % 2 != BigInt::zero()),
+            RoundingMode::Truncate => false,
+        };
+        if !away_from_zero {
+            return truncated;
+        }
+        let unit = BigDecimal::new(BigInt::from(1), decimals);
+        if value.is_negative() { truncated - unit } else { truncated + unit }
+    }
+}
+
+/// Why text could not be parsed as an amount of money
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseMoneyError {
+    /// The text is not a decimal number
+    Invalid,
+    /// The amount is below zero
+    Negative,
+}
+
+impl fmt::Display for ParseMoneyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseMoneyError::Invalid => write!(f, "not a decimal number"),
+            ParseMoneyError::Negative => write!(f, "amount must not be negative"),
+        }
+    }
+}
+
+impl Money {
+    /// The number of decimal places amounts are held at unless configured otherwise
+    pub const DEFAULT_SCALE: i64 = 4;
+    /// The most decimal places any amount is held at
+    pub const MAX_SCALE: i64 = 10;
+
+    /// Round a decimal half up to exactly `decimals` places (at most `MAX_SCALE`) to make an amount
+    pub fn round(value: &BigDecimal, decimals: i64) -> Money {
+        Money::round_with(value, decimals, RoundingMode::default())
+    }
+
+    /// Round a decimal to exactly `decimals` places (at most `MAX_SCALE`) with the
+    /// given rounding mode to make an amount
+    pub fn round_with(value: &BigDecimal, decimals: i64, rounding: RoundingMode) -> Money {
+        let decimals = decimals.min(Money::MAX_SCALE);
+        Money(rounding.round(value, decimals).with_scale(decimals))
+    }
+
+    /// The amount as a decimal with exactly `decimals` places, for output
+    pub fn rounded(&self, decimals: i64) -> BigDecimal {
+        self.0.round(decimals).with_scale(decimals)
+    }
+
+    /// The amount as a decimal
+    pub fn as_decimal(&self) -> &BigDecimal {
+        &self.0
+    }
+
+    /// Whether the amount is below zero
+    pub fn is_negative(&self) -> bool {
+        self.0.is_negative()
+    }
+
+    /// Scale the amount by a rate, as when paying interest, rounding the
+    /// result to `decimals` places
+    pub fn times_rate(&self, rate: &BigDecimal, decimals: i64) -> Money {
+        Money::round(&(&self.0 * rate), decimals)
+    }
+
+    /// Add `other` to the amount, or `None` if the sum would be negative
+    pub fn checked_add(&self, other: &Money) -> Option<Money> {
+        Some(self + other).filter(|sum| !sum.is_negative())
+    }
+
+    /// Take `other` from the amount, or `None` if the difference would be negative
+    pub fn checked_sub(&self, other: &Money) -> Option<Money> {
+        Some(self - other).filter(|difference| !difference.is_negative())
+    }
+}
+
+impl Default for Money {
+    fn default() -> Self {
+        Money::zero()
+    }
+}
+
+impl Zero for Money {
+    fn zero() -> Self {
+        Money(BigDecimal::zero())
+    }
+
+    fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+}
+
+impl From<BigDecimal> for Money {
+    /// Make an amount from a decimal, keeping its decimal places
+    /// unless it has more than `MAX_SCALE`
+    fn from(value: BigDecimal) -> Money {
+        let (_, scale) = value.as_bigint_and_exponent();
+        if scale > Money::MAX_SCALE { Money::round(&value, Money::MAX_SCALE) } else { Money(value) }
+    }
+}
+
+impl From<Money> for BigDecimal {
+    fn from(money: Money) -> BigDecimal {
+        money.0
+    }
+}
+
+impl From<i32> for Money {
+    fn from(value: i32) -> Money {
+        Money(BigDecimal::from(value))
+    }
+}
+
+impl FromStr for Money {
+    type Err = ParseMoneyError;
+    /// Parse a non-negative amount, rounding it to `MAX_SCALE` places if it has more
+    fn from_str(text: &str) -> Result<Money, ParseMoneyError> {
+        let value = BigDecimal::from_str(text).map_err(|_| ParseMoneyError::Invalid)?;
+        if value.is_negative() {
+            return Err(ParseMoneyError::Negative);
+        }
+        Ok(value.into())
+    }
+}
+
+impl fmt::Display for Money {
+    /// Write the amount with exactly the decimal places given as the precision, as in
+    /// `{:.2}`, or otherwise with the places it is held at, padded to `DEFAULT_SCALE`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (_, scale) = self.0.as_bigint_and_exponent();
+        let decimals = f.precision().map_or(scale.max(Money::DEFAULT_SCALE), |precision| precision as i64);
+        write!(f, "{}", self.rounded(decimals))
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+    fn add(self, other: Money) -> Money {
+        Money(self.0 + other.0)
+    }
+}
+
+impl Add for &Money {
+    type Output = Money;
+    fn add(self, other: &Money) -> Money {
+        Money(&self.0 + &other.0)
+    }
+}
+
+impl Sub for &Money {
+    type Output = Money;
+    fn sub(self, other: &Money) -> Money {
+        Money(&self.0 - &other.0)
+    }
+}
+
+impl AddAssign<&Money> for Money {
+    fn add_assign(&mut self, other: &Money) {
+        self.0 += &other.0;
+    }
+}
+
+impl AddAssign for Money {
+    fn add_assign(&mut self, other: Money) {
+        self.0 += other.0;
+    }
+}
+
+impl SubAssign<&Money> for Money {
+    fn sub_assign(&mut self, other: &Money) {
+        self.0 -= &other.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rounds_to_the_given_decimal_places() {
+        for text in ["5", "5.0", "5.00000", "4.99996"] {
+            assert_eq!(Money::round(&text.parse().unwrap(), 4).to_string(), "5.0000");
+        }
+        assert_eq!(Money::round(&"1.2349".parse().unwrap(), 2).as_decimal().to_string(), "1.23");
+        assert_eq!(Money::round(&"10.7".parse().unwrap(), 0).as_decimal().to_string(), "11");
+        assert_eq!(Money::round(&"1.12345678915".parse().unwrap(), 12).to_string(), "1.1234567892");
+        assert_eq!("1.12345678915".parse::<Money>().unwrap().to_string(), "1.1234567892");
+        assert_eq!("1.5".parse::<Money>().unwrap().as_decimal().to_string(), "1.5");
+    }
+
+    #[test]
+    fn displays_at_least_the_default_scale_or_the_given_precision() {
+        assert_eq!("1.5".parse::<Money>().unwrap().to_string(), "1.5000");
+        assert_eq!(Money::from(7).to_string(), "7.0000");
+        assert_eq!(Money::zero().to_string(), "0.0000");
+        assert_eq!("1.12345678".parse::<Money>().unwrap().to_string(), "1.12345678");
+        assert_eq!(format!("{:.2}", "1.5".parse::<Money>().unwrap()), "1.50");
+        assert_eq!(format!("{:.2}", "1.2351".parse::<Money>().unwrap()), "1.24");
+    }
+
+    #[test]
+    fn rounds_with_each_rounding_mode() {
+        let round = |text: &str, rounding| Money::round_with(&text.parse().unwrap(), 4, rounding).to_string();
+        assert_eq!(round("1.23455", RoundingMode::HalfUp), "1.2346");
+        assert_eq!(round("1.23455", RoundingMode::HalfEven), "1.2346");
+        assert_eq!(round("1.23455", RoundingMode::Truncate), "1.2345");
+        assert_eq!(round("1.23445", RoundingMode::HalfUp), "1.2345");
+        assert_eq!(round("1.23445", RoundingMode::HalfEven), "1.2344");
+        assert_eq!(round("1.234451", RoundingMode::HalfEven), "1.2345");
+        assert_eq!(round("1.23459", RoundingMode::Truncate), "1.2345");
+        assert_eq!(round("-1.23455", RoundingMode::HalfUp), "-1.2346");
+        assert_eq!(round("-1.23445", RoundingMode::HalfEven), "-1.2344");
+        assert_eq!(round("-1.23459", RoundingMode::Truncate), "-1.2345");
+    }
+
+    #[test]
+    fn rejects_negative_and_invalid_text() {
+        assert_eq!("-1".parse::<Money>(), Err(ParseMoneyError::Negative));
+        assert_eq!("abc".parse::<Money>(), Err(ParseMoneyError::Invalid));
+    }
+
+    #[test]
+    fn arithmetic_keeps_the_scale() {
+        let a = Money::round(&"1.5".parse().unwrap(), 4);
+        let b = Money::round(&"0.25".parse().unwrap(), 4);
+        assert_eq!((&a + &b).to_string(), "1.7500");
+        assert_eq!((&b - &a).to_string(), "-1.2500");
+        assert_eq!(a.times_rate(&"0.0001".parse().unwrap(), 4).to_string(), "0.0002");
+        assert_eq!(a.rounded(2).to_string(), "1.50");
+    }
+
+    #[test]
+    fn checked_arithmetic_refuses_to_go_negative() {
+        let a: Money = 5.into();
+        let b: Money = 3.into();
+        assert_eq!(a.checked_sub(&b), Some(2.into()));
+        assert_eq!(b.checked_sub(&a), None);
+        assert_eq!(a.checked_add(&b), Some(8.into()));
+        assert_eq!((&b - &a).checked_add(&1.into()), None);
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+
+/// A deposit or withdrawal that reuses the ID of an earlier one
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DuplicateTx {
+    /// The reused transaction ID
+    pub tx: u32,
+    /// The line the ID was first used on
+    pub first_line: u64,
+    /// The line reusing the ID
+    pub line: u64,
+}
+
+impl fmt::Display for DuplicateTx {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Duplicate tx {} on line {} (first used on line {})", self.tx, self.line, self.first_line)
+    }
+}
+
+/// Scan the `type` and `tx` columns of the input for deposits and withdrawals
+/// that reuse a transaction ID, without otherwise processing the rows. Dispute
+/// actions are skipped, since they refer to existing transaction IDs by design,
+/// as are rows whose `tx` is not a valid ID.
+pub fn find_duplicate_tx<R: Read>(reader: &mut csv::Reader<R>) -> csv::Result<Vec<DuplicateTx>> {
+    let headers = reader.headers()?;
+    let (Some(type_column), Some(tx_column)) = (
+        headers.iter().position(|header| header == "type"),
+        headers.iter().position(|header| header == "tx"),
+    ) else {
+        return Ok(Vec::new());
+    };
+
+    let mut first_lines: HashMap<u32, u64> = HashMap::new();
+    let mut duplicates = Vec::new();
+    for result in reader.records() {
+        let record = result?;
+        if !matches!(record.get(type_column), Some("deposit") | Some("withdrawal")) {
+            continue;
+        }
+        let Some(tx) = record.get(tx_column).and_then(|tx| tx.trim_start_matches('+').parse().ok()) else {
+            continue;
+        };
+        let line = record.position().map(|position| position.line()).unwrap_or_default();
+        match first_lines.get(&tx) {
+            Some(&first_line) => duplicates.push(DuplicateTx { tx, first_line, line }),
+            None => { first_lines.insert(tx, line); }
+        }
+    }
+    Ok(duplicates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use csv::Trim;
+
+    fn scan(input: &str) -> Vec<DuplicateTx> {
+        let mut reader = csv::ReaderBuilder::new()
+            .flexible(true)
+            .trim(Trim::All)
+            .from_reader(input.as_bytes());
+        find_duplicate_tx(&mut reader).expect("Scan failed")
+    }
+
+    #[test]
+    fn reports_duplicate_with_both_lines() {
+        let duplicates = scan("type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+dispute, 1, 1,
+withdrawal, 2, 1, 1.0
+");
+        assert_eq!(duplicates, vec![DuplicateTx { tx: 1, first_line: 2, line: 5 }]);
+    }
+
+    #[test]
+    fn unique_ids_pass() {
+        let duplicates = scan("type, client, tx, amount
+deposit, 1, 1, 1.0
+dispute, 1, 1,
+resolve, 1, 1,
+");
+        assert!(duplicates.is_empty());
+    }
+}
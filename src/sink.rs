@@ -0,0 +1,128 @@
+use std::io;
+
+use crate::csv_rows::OutputRow;
+
+/// Write the output rows to an external store at `location`. With the `redis`
+/// feature enabled, this may be a `redis://` URL, and each account is written as
+/// a hash keyed `client:<id>`, with the fields of its output row as strings.
+pub fn write_sink(location: &str, rows: &[OutputRow]) -> io::Result<()> {
+    if location.starts_with("redis://") || location.starts_with("rediss://") {
+        write_redis(location, rows)
+    } else {
+        Err(io::Error::new(io::ErrorKind::Unsupported,
+            format!("unknown sink {}: expected a redis:// URL", location)))
+    }
+}
+
+/// The fields of an output row as stored in a hash
+#[cfg_attr(not(feature = "redis"), allow(dead_code))]
+fn hash_fields(row: &OutputRow) -> Vec<(&'static str, String)> {
+    let mut fields = vec![
+        ("client", row.client.to_string()),
+        ("available", row.available.to_string()),
+        ("held", row.held.to_string()),
+        ("total", row.total.to_string()),
+        ("locked", row.locked.to_string()),
+    ];
+    if let Some(lock_reason) = &row.lock_reason {
+        fields.push(("lock_reason", lock_reason.clone()));
+    }
+    if let Some(last_seq) = &row.last_seq {
+        fields.push(("last_seq", last_seq.clone()));
+    }
+    if let Some(tx_count) = row.tx_count {
+        fields.push(("tx_count", tx_count.to_string()));
+    }
+    fields
+}
+
+/// Write every row to Redis in a single pipeline
+#[cfg(feature = "redis")]
+fn write_redis(url: &str, rows: &[OutputRow]) -> io::Result<()> {
+    let client = redis::Client::open(url).map_err(io::Error::other)?;
+    let mut connection = client.get_connection().map_err(io::Error::other)?;
+    let mut pipeline = redis::pipe();
+    for row in rows {
+        pipeline.cmd("HSET").arg(format!("client:{}", row.client)).arg(hash_fields(row)).ignore();
+    }
+    pipeline.query::<()>(&mut connection).map_err(io::Error::other)
+}
+
+/// Writing to Redis requires the `redis` feature
+#[cfg(not(feature = "redis"))]
+fn write_redis(url: &str, _rows: &[OutputRow]) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported,
+        format!("cannot write to {}: built without the `redis` feature", url)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::{ Account, FreezeReason };
+
+    #[test]
+    fn rejects_unknown_sinks() {
+        let error = write_sink("memcached://localhost", &[]).expect_err("Unknown sink was accepted");
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn hash_fields_use_four_decimal_places() {
+        let account = Account {
+            available_balance: "1.5".parse().unwrap(),
+            freeze_reason: Some(FreezeReason::Chargeback { tx: 4 }),
+            ..Account::new(7)
+        };
+        assert_eq!(hash_fields(&account.into()), vec![
+            ("client", "7".to_string()),
+            ("available", "1.5000".to_string()),
+            ("held", "0.0000".to_string()),
+            ("total", "1.5000".to_string()),
+            ("locked", "true".to_string()),
+        ]);
+    }
+
+    #[cfg(feature = "redis")]
+    #[test]
+    fn writes_accounts_to_redis() {
+        use std::io::{ BufRead, BufReader, Write };
+        use std::net::TcpListener;
+
+        // A minimal Redis server that records every command and replies OK
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Could not bind mock server");
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().expect("No connection");
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut read_line = || {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                line.trim_end().to_string()
+            };
+            let mut commands = Vec::new();
+            loop {
+                let header = read_line();
+                let Some(count) = header.strip_prefix('*') else { return commands };
+                let command: Vec<String> = (0..count.parse().unwrap()).map(|_| {
+                    read_line();
+                    read_line()
+                }).collect();
+                commands.push(command);
+                writer.write_all(b"+OK\r\n").unwrap();
+            }
+        });
+
+        let account = Account {
+            available_balance: "2.25".parse().unwrap(),
+            held_balance: "1".parse().unwrap(),
+            ..Account::new(3)
+        };
+        write_sink(&url, &[account.into()]).expect("Write failed");
+
+        let commands = server.join().unwrap();
+        let hset = commands.iter().find(|command| command[0] == "HSET").expect("No HSET sent");
+        assert_eq!(hset, &["HSET", "client:3", "client", "3", "available", "2.2500",
+            "held", "1.0000", "total", "3.2500", "locked", "false"]);
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::{ BTreeMap, HashMap, VecDeque };
+use std::io::{ self, Read, Write };
+
+use serde::{ Deserialize, Serialize };
+
+use crate::account::Account;
+use crate::engine::{ Ledger, TxOwners };
+use crate::transaction::DisputeActionType;
+
+/// The version of the snapshot format written by this build. Snapshots of
+/// any other version are rejected rather than misread
+pub const SNAPSHOT_VERSION: u32 = 7;
+
+/// The state carried from one run to the next: every account with its full
+/// transaction history, and any dispute actions still waiting for their dispute
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    version: u32,
+    accounts: &'a BTreeMap<u16, Account>,
+    pending_disputes: &'a HashMap<(u16, u32), VecDeque<DisputeActionType>>,
+}
+
+/// The start of every snapshot, for checking the version before reading the rest
+#[derive(Deserialize)]
+struct SnapshotHeader {
+    version: u32,
+}
+
+/// Write the ledger's accounts and pending disputes as a snapshot
+pub fn write_snapshot<W: Write>(ledger: &Ledger, output: W) -> io::Result<()> {
+    let snapshot = SnapshotRef {
+        version: SNAPSHOT_VERSION,
+        accounts: &ledger.accounts,
+        pending_disputes: &ledger.pending_disputes,
+    };
+    bincode::serialize_into(output, &snapshot).map_err(io::Error::other)
+}
+
+/// Read a snapshot back into a ledger to continue processing from. Totals and
+/// counts only cover a single run, so they start from zero
+pub fn read_snapshot<R: Read>(mut input: R) -> io::Result<Ledger> {
+    let mut bytes = Vec::new();
+    input.read_to_end(&mut bytes)?;
+    let invalid = |err| io::Error::new(io::ErrorKind::InvalidData, err);
+    let header: SnapshotHeader = bincode::deserialize(&bytes).map_err(invalid)?;
+    if header.version != SNAPSHOT_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("snapshot version {} is not supported, expected {}", header.version, SNAPSHOT_VERSION)));
+    }
+    // Read the fields of `SnapshotRef` back in order
+    let (_, mut accounts, pending_disputes): (u32, BTreeMap<u16, Account>, _) = bincode::deserialize(&bytes).map_err(invalid)?;
+    for account in accounts.values_mut() {
+        account.frozen_when_loaded = account.is_frozen();
+    }
+    Ok(Ledger {
+        tx_ids: accounts.values().flat_map(|account| account.transactions.keys().copied()).collect(),
+        tx_owners: TxOwners::from_accounts(&accounts),
+        accounts,
+        pending_disputes,
+        ..Ledger::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EngineConfig;
+    use crate::engine::process_into;
+    use crate::transaction::DisputeState;
+
+    fn process_into_str(input: &str, ledger: &mut Ledger) {
+        let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(input.as_bytes());
+        process_into(&mut reader, &EngineConfig::default(), ledger).expect("Processing failed");
+    }
+
+    #[test]
+    fn disputes_reach_transactions_from_a_previous_run() {
+        let mut first_run = Ledger::default();
+        process_into_str("type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,1,2,2.5\ndispute,1,2,\n", &mut first_run);
+        let mut bytes = Vec::new();
+        write_snapshot(&first_run, &mut bytes).expect("Write failed");
+
+        let mut second_run = read_snapshot(bytes.as_slice()).expect("Read failed");
+        assert_eq!(second_run.accounts[&1].transactions[&2].dispute_state, DisputeState::Disputed);
+        process_into_str("type,client,tx,amount\nchargeback,1,2,\nresolve,1,1,\ndispute,1,1,\n", &mut second_run);
+
+        let account = &second_run.accounts[&1];
+        assert_eq!(account.available_balance, "0".parse().unwrap());
+        assert_eq!(account.held_balance, "5".parse().unwrap());
+        assert_eq!(account.transactions[&1].dispute_state, DisputeState::Disputed);
+        assert_eq!(account.transactions[&2].dispute_state, DisputeState::ChargedBack);
+        assert!(account.is_frozen());
+    }
+
+    #[test]
+    fn rejects_other_snapshot_versions() {
+        let mut bytes = Vec::new();
+        write_snapshot(&Ledger::default(), &mut bytes).expect("Write failed");
+        bytes[..4].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+        let err = read_snapshot(bytes.as_slice()).expect_err("Future version was accepted");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
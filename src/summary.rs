@@ -0,0 +1,111 @@
+use std::collections::BTreeMap;
+
+use bigdecimal::BigDecimal;
+use serde::Serialize;
+
+use crate::csv_rows::{ serialize_plain_decimal, OutputRow };
+
+/// The label of the summary row totalling every account, whatever its currency
+pub const GRAND_TOTAL: &str = "all";
+
+/// The balances of a group of accounts added together
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct SummaryRow {
+    /// The currency of the accounts totalled, empty for accounts without a
+    /// currency, or `all` for every account
+    pub currency: String,
+    #[serde(serialize_with = "serialize_plain_decimal")]
+    pub available: BigDecimal,
+    #[serde(serialize_with = "serialize_plain_decimal")]
+    pub held: BigDecimal,
+    #[serde(serialize_with = "serialize_plain_decimal")]
+    pub total: BigDecimal,
+}
+
+impl SummaryRow {
+    fn new(currency: &str) -> SummaryRow {
+        SummaryRow {
+            currency: currency.to_string(),
+            available: BigDecimal::default(),
+            held: BigDecimal::default(),
+            total: BigDecimal::default(),
+        }
+    }
+
+    fn add(&mut self, row: &OutputRow) {
+        self.available += &row.available;
+        self.held += &row.held;
+        self.total += &row.total;
+    }
+}
+
+/// Total the balances of the output rows, with one row per currency in currency order
+/// if the rows have currencies, followed by the grand total of every row. Amounts are
+/// totalled as written, so the totals match the output
+pub fn summarize(rows: &[OutputRow]) -> Vec<SummaryRow> {
+    let has_currencies = rows.iter().any(|row| row.currency.is_some());
+    let mut by_currency: BTreeMap<&str, SummaryRow> = BTreeMap::new();
+    let mut grand_total = SummaryRow::new(GRAND_TOTAL);
+    for row in rows {
+        if has_currencies {
+            let currency = row.currency.as_deref().unwrap_or_default();
+            by_currency.entry(currency).or_insert_with(|| SummaryRow::new(currency)).add(row);
+        }
+        grand_total.add(row);
+    }
+    by_currency.into_values().chain([grand_total]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::Account;
+    use crate::csv_rows::DEFAULT_DECIMALS;
+
+    fn output_row(client: u16, available: &str, held: &str, currency: Option<&str>) -> OutputRow {
+        let account = Account {
+            available_balance: available.parse().unwrap(),
+            held_balance: held.parse().unwrap(),
+            currency: currency.map(str::to_string),
+            ..Account::new(client)
+        };
+        let row = OutputRow::from_account(account.clone(), false, DEFAULT_DECIMALS);
+        if currency.is_some() { row.with_currency(account.currency) } else { row }
+    }
+
+    #[test]
+    fn totals_every_account() {
+        let rows = [output_row(1, "10.5", "0", None), output_row(2, "4", "2.25", None), output_row(3, "0", "1", None)];
+        let summary = summarize(&rows);
+        assert_eq!(summary, [SummaryRow {
+            currency: "all".to_string(),
+            available: "14.5".parse().unwrap(),
+            held: "3.25".parse().unwrap(),
+            total: "17.75".parse().unwrap(),
+        }]);
+
+        let mut writer = csv::Writer::from_writer(vec![]);
+        for row in summary {
+            writer.serialize(row).expect("Serialize failed");
+        }
+        let csv = String::from_utf8(writer.into_inner().expect("Flush failed")).unwrap();
+        assert_eq!(csv, "currency,available,held,total\nall,14.5000,3.2500,17.7500\n");
+    }
+
+    #[test]
+    fn totals_each_currency() {
+        let rows = [
+            output_row(1, "10", "0", Some("USD")),
+            output_row(2, "4", "2", Some("EUR")),
+            output_row(3, "1", "1", Some("USD")),
+            output_row(4, "5", "0", None),
+        ];
+        let summary: Vec<(String, BigDecimal)> = summarize(&rows).into_iter().map(|row| (row.currency, row.total)).collect();
+        assert_eq!(summary, [
+            (String::new(), 5.into()),
+            ("EUR".to_string(), 6.into()),
+            ("USD".to_string(), 12.into()),
+            ("all".to_string(), 23.into()),
+        ]);
+    }
+}
@@ -0,0 +1,67 @@
+use crate::csv_rows::OutputRow;
+
+/// Render the output rows as an aligned text table for reading in a terminal,
+/// with a header row and separator. Amounts are right-aligned
+pub fn render_table(rows: &[OutputRow]) -> String {
+    let header = ["client", "available", "held", "total", "locked"];
+    let cells: Vec<[String; 5]> = rows.iter().map(|row| [
+        row.client.to_string(),
+        row.available.to_string(),
+        row.held.to_string(),
+        row.total.to_string(),
+        row.locked.to_string(),
+    ]).collect();
+
+    let mut widths = header.map(str::len);
+    for row in &cells {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut table = String::new();
+    let header_cells = header.map(str::to_string);
+    push_line(&mut table, &header_cells, &widths);
+    let separator = widths.map(|width| "-".repeat(width));
+    table.push_str(&separator.join("-+-"));
+    table.push('\n');
+    for row in &cells {
+        push_line(&mut table, row, &widths);
+    }
+    table
+}
+
+
+/// Append one line of the table. Numbers are right-aligned, and text left-aligned
+fn push_line(table: &mut String, cells: &[String; 5], widths: &[usize; 5]) {
+    let line: Vec<String> = cells.iter().zip(widths).enumerate().map(|(column, (cell, &width))| {
+        if column == 4 { format!("{:<width$}", cell) } else { format!("{:>width$}", cell) }
+    }).collect();
+    table.push_str(line.join(" | ").trim_end());
+    table.push('\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::{ Account, FreezeReason };
+
+    #[test]
+    fn renders_aligned_columns() {
+        let rows: Vec<OutputRow> = vec![
+            Account { available_balance: "1.5".parse().unwrap(), ..Account::new(1) }.into(),
+            Account {
+                available_balance: "1234.25".parse().unwrap(),
+                held_balance: "10".parse().unwrap(),
+                freeze_reason: Some(FreezeReason::ManualFreeze),
+                ..Account::new(65535)
+            }.into(),
+        ];
+        assert_eq!(render_table(&rows), "\
+client | available |    held |     total | locked
+-------+-----------+---------+-----------+-------
+     1 |    1.5000 |  0.0000 |    1.5000 | false
+ 65535 | 1234.2500 | 10.0000 | 1244.2500 | true
+");
+    }
+}
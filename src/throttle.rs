@@ -0,0 +1,51 @@
+use std::time::{ Duration, Instant };
+
+/// A simple rate limiter that paces a loop to a fixed number of iterations per second
+pub struct Throttle {
+    /// The time to spend on each iteration
+    interval: Duration,
+    /// The earliest time the next iteration may proceed
+    next: Option<Instant>,
+}
+
+impl Throttle {
+    /// Create a throttle allowing `rate` iterations per second
+    pub fn new(rate: u32) -> Self {
+        Self {
+            interval: Duration::from_secs(1) / rate.max(1),
+            next: None,
+        }
+    }
+
+    /// Block until the next iteration may proceed. The first call never blocks
+    pub fn wait(&mut self) {
+        let now = Instant::now();
+        let next = match self.next {
+            Some(next) if next > now => {
+                std::thread::sleep(next - now);
+                next
+            },
+            // If we've fallen behind, don't try to catch up with a burst
+            _ => now
+        };
+        self.next = Some(next + self.interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_by_expected_time() {
+        let mut throttle = Throttle::new(500);
+        let start = Instant::now();
+        for _ in 0..11 {
+            throttle.wait();
+        }
+        let elapsed = start.elapsed();
+        // 10 intervals of 2ms each after the first free iteration
+        assert!(elapsed >= Duration::from_millis(20), "throttle too fast: {:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(500), "throttle too slow: {:?}", elapsed);
+    }
+}
@@ -1,14 +1,21 @@
 use bigdecimal::BigDecimal;
+use chrono::{ DateTime, Utc };
+use num_traits::Zero;
+use serde::{ Deserialize, Serialize };
+
+use crate::money::Money;
 
 /// The type of transaction being executed, either a deposit or withdrawal
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum TransactionType {
     Deposit,
     Withdrawal
 }
 
-/// The state of dispute a transaction is in
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+/// The state of dispute a transaction is in, named as in transaction exports
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum DisputeState {
     /// The transaction has either never been disputed, or has been disputed or resolved
     Undisputed,
@@ -19,7 +26,8 @@ pub enum DisputeState {
 }
 
 /// A state transition for a transaction dispute
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum DisputeActionType {
     /// Take an undisputed transaction into dispute
     Dispute,
@@ -30,24 +38,60 @@ pub enum DisputeActionType {
 }
 
 /// A structure representing a transaction
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Transaction {
     /// A globally unique transaction ID
     pub id: u32,
     /// The client ID of the account the transaction is acting on
     pub client_id: u16,
-    /// The amount of the transaction in 1/10000 currency units
-    /// (this is used instead of f64 to avoid rounding errors)
-    pub amount: BigDecimal,
+    /// The amount of the transaction, rounded to the configured decimal places
+    /// (a decimal is used instead of f64 to avoid rounding errors)
+    pub amount: Money,
     /// Whether the transaction is a deposit or a withdrawal
     pub transaction_type: TransactionType,
     /// Whether a transaction is OK, under dispute, or charged back
-    pub dispute_state: DisputeState
+    pub dispute_state: DisputeState,
+    /// The portion of the amount currently held in dispute. This is the full
+    /// amount when a dispute opens, and may shrink as parts of it are resolved
+    /// or charged back
+    pub held_amount: Money,
+    /// The portion of the amount that has been charged back
+    pub charged_back_amount: Money,
+    /// When the transaction took place, if the input gave a timestamp
+    pub timestamp: Option<DateTime<Utc>>,
+    /// The currency of the amount, if the input gave one
+    pub currency: Option<String>
+}
+
+impl Transaction {
+    /// Create an undisputed transaction, without a timestamp or currency
+    pub fn new(id: u32, client_id: u16, amount: Money, transaction_type: TransactionType) -> Transaction {
+        Transaction {
+            id,
+            client_id,
+            amount,
+            transaction_type,
+            dispute_state: DisputeState::Undisputed,
+            held_amount: Zero::zero(),
+            charged_back_amount: Zero::zero(),
+            timestamp: None,
+            currency: None,
+        }
+    }
+
+    /// A rough estimate of the memory used to keep this transaction in an
+    /// account's history, including its key and the digits of its amount
+    pub fn estimated_size(&self) -> usize {
+        std::mem::size_of::<u32>()
+            + std::mem::size_of::<Transaction>()
+            + (self.amount.as_decimal().digits() as usize) / 2 + 1
+            + self.currency.as_ref().map_or(0, String::len)
+    }
 }
 
 /// A structure representing a change in the dispute state for
 /// a transaction
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct DisputeAction {
     /// The desired action for the transaction
     pub action_type: DisputeActionType,
@@ -55,4 +99,85 @@ pub struct DisputeAction {
     pub client_id: u16,
     /// The transaction ID of the transaction of concern
     pub transaction_id: u32,
-}
\ No newline at end of file
+}
+
+/// An action on an account as a whole, rather than on a transaction
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccountActionType {
+    /// Open the account, allowing deposits and withdrawals when
+    /// accounts must be explicitly opened
+    Open,
+    /// Credit the account with interest, with the amount giving the rate
+    /// applied to the available balance
+    Accrue,
+    /// Credit the account with interest on its available balance only, with
+    /// the amount giving the rate. Held funds earn nothing, whatever their rate
+    Interest,
+    /// Manually hold the amount of the available balance, apart from any dispute
+    Hold,
+    /// Make the amount of a manual hold available again
+    Release
+}
+
+/// A structure representing an action on an account as a whole
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccountAction {
+    /// The desired action for the account
+    pub action_type: AccountActionType,
+    /// The client ID of the account of concern
+    pub client_id: u16,
+    /// The amount associated with the action, if any. For `Accrue` and `Interest`,
+    /// this is the interest rate (e.g. 0.015 for 1.5%), and for `Hold` and
+    /// `Release`, the amount of money held or released
+    pub amount: Option<BigDecimal>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::de::DeserializeOwned;
+
+    /// Check that each value serializes to the name used in the CSV vocabulary and back
+    fn assert_round_trips<T: Serialize + DeserializeOwned + PartialEq + std::fmt::Debug>(cases: &[(T, &str)]) {
+        for (value, name) in cases {
+            let json = serde_json::to_string(value).expect("Serialize failed");
+            assert_eq!(json, format!("\"{}\"", name));
+            assert_eq!(&serde_json::from_str::<T>(&json).expect("Deserialize failed"), value);
+        }
+    }
+
+    #[test]
+    fn transaction_type_round_trips() {
+        assert_round_trips(&[(TransactionType::Deposit, "deposit"), (TransactionType::Withdrawal, "withdrawal")]);
+    }
+
+    #[test]
+    fn dispute_state_round_trips() {
+        assert_round_trips(&[
+            (DisputeState::Undisputed, "undisputed"),
+            (DisputeState::Disputed, "disputed"),
+            (DisputeState::ChargedBack, "charged_back"),
+        ]);
+    }
+
+    #[test]
+    fn dispute_action_type_round_trips() {
+        assert_round_trips(&[
+            (DisputeActionType::Dispute, "dispute"),
+            (DisputeActionType::Resolve, "resolve"),
+            (DisputeActionType::Chargeback, "chargeback"),
+        ]);
+    }
+
+    #[test]
+    fn account_action_type_round_trips() {
+        assert_round_trips(&[
+            (AccountActionType::Open, "open"),
+            (AccountActionType::Accrue, "accrue"),
+            (AccountActionType::Interest, "interest"),
+            (AccountActionType::Hold, "hold"),
+            (AccountActionType::Release, "release"),
+        ]);
+    }
+}
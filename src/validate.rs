@@ -0,0 +1,191 @@
+use std::collections::{ BTreeSet, HashMap };
+use std::fmt;
+use std::io::Read;
+
+use crate::config::EngineConfig;
+use crate::csv_rows::{ localize_amount, InputRow };
+use crate::engine::{ describe_row_problem, parse_row, Ledger, RowAction };
+
+/// A row that would be rejected by processing, with the reason
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RowProblem {
+    /// The line of the input the row is on
+    pub line: u64,
+    /// Why the row is invalid
+    pub reason: String,
+}
+
+impl fmt::Display for RowProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.reason)
+    }
+}
+
+/// Checks that input rows parse and that dispute actions refer to known
+/// transactions, without applying any of them to accounts. The transactions
+/// seen are remembered, so that several inputs can be checked in sequence
+#[derive(Debug, Default)]
+pub struct Validator {
+    /// The clients owning a deposit or withdrawal with each transaction ID seen so far.
+    /// Clients may reuse each other's IDs unless `enforce_unique_tx` is set
+    owners: HashMap<u32, BTreeSet<u16>>,
+    /// The number of invalid rows found so far
+    pub invalid_rows: u64,
+}
+
+impl Validator {
+    /// Start from the transactions already held by the accounts of `ledger`,
+    /// such as those loaded from a snapshot
+    pub fn from_ledger(ledger: &Ledger) -> Validator {
+        let mut owners: HashMap<u32, BTreeSet<u16>> = HashMap::new();
+        for account in ledger.accounts.values() {
+            for &tx in account.transactions.keys() {
+                owners.entry(tx).or_default().insert(account.id);
+            }
+        }
+        Validator { owners, invalid_rows: 0 }
+    }
+
+    /// Check every row of the input, returning the first `limit` problems found.
+    /// Every problem is counted in `invalid_rows`, whether or not it is returned
+    pub fn check<R: Read>(&mut self, reader: &mut csv::Reader<R>, config: &EngineConfig, limit: usize) -> csv::Result<Vec<RowProblem>> {
+        let headers = reader.headers()?.clone();
+        let amount_column = headers.iter().position(|header| header == "amount");
+        let mut problems = Vec::new();
+        for result in reader.records() {
+            let mut record = result?;
+            let line = record.position().map(|position| position.line()).unwrap_or_default();
+            let reason = match localize_amount(&mut record, amount_column, config.locale) {
+                Ok(()) => match record.deserialize::<InputRow>(Some(&headers)) {
+                    Ok(mut input_row) => self.check_row(&mut input_row, config),
+                    Err(err) => Some(err.to_string())
+                },
+                Err(problem) => Some(problem)
+            };
+            if let Some(reason) = reason {
+                self.invalid_rows += 1;
+                if problems.len() < limit {
+                    problems.push(RowProblem { line, reason });
+                }
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Check a single row, returning why it is invalid, if it is
+    fn check_row(&mut self, input_row: &mut InputRow, config: &EngineConfig) -> Option<String> {
+        let action = match parse_row(input_row, config) {
+            Ok(action) => action,
+            Err(err) => return Some(describe_row_problem(input_row, err, config))
+        };
+        match action {
+            RowAction::Transaction(transaction) => {
+                let owners = self.owners.entry(transaction.id).or_default();
+                // Like the engine, only reject another client's ID if IDs must be unique
+                let used = owners.contains(&transaction.client_id) || (config.enforce_unique_tx && !owners.is_empty());
+                if used {
+                    Some(format!("{} {}: transaction ID was already used", input_row.transaction_type, transaction.id))
+                } else {
+                    owners.insert(transaction.client_id);
+                    None
+                }
+            },
+            RowAction::Dispute(dispute_action) => {
+                // Like the engine, look for the transaction in the named client's account first
+                let owners = self.owners.get(&dispute_action.transaction_id);
+                match owners.and_then(BTreeSet::first) {
+                    None => Some(format!("{} {}: no such transaction", input_row.transaction_type, dispute_action.transaction_id)),
+                    Some(_) if owners.is_some_and(|owners| owners.contains(&dispute_action.client_id)) => None,
+                    // The engine applies it to the owner's account instead
+                    Some(_) if config.lenient_dispute_client => None,
+                    Some(owner) => Some(format!("{} {}: transaction belongs to client {}",
+                        input_row.transaction_type, dispute_action.transaction_id, owner)),
+                }
+            },
+            RowAction::Account(_) => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::csv_reader;
+
+    fn check(input: &str, limit: usize) -> (u64, Vec<RowProblem>) {
+        let mut validator = Validator::default();
+        let problems = validator.check(&mut csv_reader(input.as_bytes()), &EngineConfig::default(), limit)
+            .expect("Check failed");
+        (validator.invalid_rows, problems)
+    }
+
+    #[test]
+    fn valid_input_has_no_problems() {
+        let (invalid_rows, problems) = check("type, client, tx, amount
+deposit, 1, 1, 5.0
+withdrawal, 1, 2, 9.0
+dispute, 1, 1,
+open, 2, 0,
+", 10);
+        assert_eq!(invalid_rows, 0);
+        assert!(problems.is_empty());
+    }
+
+    #[test]
+    fn reports_each_kind_of_problem_with_its_line() {
+        let (invalid_rows, problems) = check("type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 1, 1, 2.0
+deposit, 1, 2, abc
+refund, 1, 3, 1.0
+dispute, 1, 9,
+dispute, 2, 1,
+", 10);
+        assert_eq!(invalid_rows, 5);
+        let lines: Vec<u64> = problems.iter().map(|problem| problem.line).collect();
+        assert_eq!(lines, vec![3, 4, 5, 6, 7]);
+        assert_eq!(problems[0].reason, "deposit 1: transaction ID was already used");
+        assert_eq!(problems[4].reason, "dispute 1: transaction belongs to client 1");
+    }
+
+    #[test]
+    fn transaction_ids_are_only_unique_per_client_unless_enforced() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 2, 1, 2.0
+dispute, 2, 1,
+";
+        let (invalid_rows, problems) = check(input, 10);
+        assert_eq!(invalid_rows, 0, "{:?}", problems);
+
+        let mut validator = Validator::default();
+        let config = EngineConfig { enforce_unique_tx: true, ..EngineConfig::default() };
+        let problems = validator.check(&mut csv_reader(input.as_bytes()), &config, 10).expect("Check failed");
+        assert_eq!(validator.invalid_rows, 2);
+        assert_eq!(problems[0].reason, "deposit 1: transaction ID was already used");
+        assert_eq!(problems[1].reason, "dispute 1: transaction belongs to client 1");
+    }
+
+    #[test]
+    fn accepts_wrong_client_disputes_when_lenient() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 5.0
+dispute, 2, 1,
+";
+        let mut validator = Validator::default();
+        let config = EngineConfig { lenient_dispute_client: true, ..EngineConfig::default() };
+        let problems = validator.check(&mut csv_reader(input.as_bytes()), &config, 10).expect("Check failed");
+        assert_eq!(validator.invalid_rows, 0, "{:?}", problems);
+    }
+
+    #[test]
+    fn counts_problems_beyond_the_limit() {
+        let (invalid_rows, problems) = check("type, client, tx, amount
+dispute, 1, 1,
+dispute, 1, 2,
+dispute, 1, 3,
+", 2);
+        assert_eq!(invalid_rows, 3);
+        assert_eq!(problems.len(), 2);
+    }
+}
@@ -0,0 +1,405 @@
+use financial_assessment_e2324103::{ process, EngineConfig, OutputRow };
+use financial_assessment_e2324103::csv_rows::OutputColumns;
+use financial_assessment_e2324103::engine::{ open_reader, open_reader_with_delimiter, AccountOrder };
+
+/// Process one of the sample input files and render the accounts as the CLI would
+fn process_file(path: &str) -> String {
+    let mut reader = open_reader(path).expect("Sample file missing");
+    let ledger = process(&mut reader, &EngineConfig::default()).expect("Processing failed");
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for output_row in ledger.into_output_rows(AccountOrder::default(), OutputColumns::default()) {
+        writer.serialize(output_row).expect("Error when serializing record");
+    }
+    String::from_utf8(writer.into_inner().unwrap()).unwrap()
+}
+
+#[test]
+fn processes_deposits_and_withdrawals() {
+    assert_eq!(process_file("transactions.csv"), "client,available,held,total,locked
+1,1.5000,0.0000,1.5000,false
+2,2.0000,0.0000,2.0000,false
+");
+}
+
+#[test]
+fn processes_disputes() {
+    assert_eq!(process_file("txs-with-disputes.csv"), "client,available,held,total,locked
+1,17.0000,10.1000,27.1000,true
+");
+}
+
+#[test]
+fn writes_output_to_file() {
+    let dir = tempfile::tempdir().expect("Could not create temp dir");
+    let path = dir.path().join("accounts.csv");
+    let result = std::process::Command::new(env!("CARGO_BIN_EXE_financial-assessment-e2324103"))
+        .args(["transactions.csv", "--output"])
+        .arg(&path)
+        .output()
+        .expect("Could not run binary");
+    assert!(result.status.success(), "run failed: {}", String::from_utf8_lossy(&result.stderr));
+    assert!(result.stdout.is_empty());
+    assert_eq!(std::fs::read_to_string(&path).expect("Output file missing"), process_file("transactions.csv"));
+}
+
+#[test]
+fn reads_gzipped_input() {
+    assert_eq!(process_file("transactions.csv.gz"), process_file("transactions.csv"));
+}
+
+#[test]
+fn reads_semicolon_delimited_input() {
+    let mut reader = open_reader_with_delimiter("transactions-semicolon.csv", b';').expect("Sample file missing");
+    let ledger = process(&mut reader, &EngineConfig::default()).expect("Processing failed");
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for output_row in ledger.into_output_rows(AccountOrder::default(), OutputColumns::default()) {
+        writer.serialize(output_row).expect("Error when serializing record");
+    }
+    assert_eq!(String::from_utf8(writer.into_inner().unwrap()).unwrap(), process_file("transactions.csv"));
+}
+
+#[test]
+fn writes_output_with_delimiter() {
+    let result = std::process::Command::new(env!("CARGO_BIN_EXE_financial-assessment-e2324103"))
+        .args(["transactions-semicolon.csv", "--delimiter", ";"])
+        .output()
+        .expect("Could not run binary");
+    assert!(result.status.success(), "run failed: {}", String::from_utf8_lossy(&result.stderr));
+    assert_eq!(String::from_utf8(result.stdout).unwrap(), process_file("transactions.csv").replace(',', ";"));
+}
+
+#[test]
+fn malformed_arguments_exit_with_usage() {
+    let binary = env!("CARGO_BIN_EXE_financial-assessment-e2324103");
+    let result = std::process::Command::new(binary)
+        .args(["transactions.csv", "txs-with-disputes.csv"])
+        .output()
+        .expect("Could not run binary");
+    assert_eq!(result.status.code(), Some(1));
+    assert!(result.stdout.is_empty());
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    assert!(stderr.contains(&format!("Usage: {} [filename.csv] [options]", binary)), "stderr: {}", stderr);
+}
+
+#[test]
+fn reads_json_lines_input() {
+    let result = std::process::Command::new(env!("CARGO_BIN_EXE_financial-assessment-e2324103"))
+        .args(["txs-with-disputes.jsonl", "--input-format", "jsonl"])
+        .output()
+        .expect("Could not run binary");
+    assert!(result.status.success(), "run failed: {}", String::from_utf8_lossy(&result.stderr));
+    assert_eq!(String::from_utf8(result.stdout).unwrap(), process_file("txs-with-disputes.csv"));
+}
+
+#[test]
+fn output_rows_match_cli_output() {
+    let result = std::process::Command::new(env!("CARGO_BIN_EXE_financial-assessment-e2324103"))
+        .args(["transactions.csv", "--verbose-columns", "--show-tx-count", "--order", "first-seen"])
+        .output()
+        .expect("Could not run binary");
+    assert!(result.status.success(), "run failed: {}", String::from_utf8_lossy(&result.stderr));
+
+    let config = EngineConfig { track_last_seq: true, ..EngineConfig::default() };
+    let ledger = process(&mut open_reader("transactions.csv").expect("Sample file missing"), &config)
+        .expect("Processing failed");
+    let columns = OutputColumns { verbose: true, tx_count: true, ..OutputColumns::default() };
+    let output_rows: Vec<OutputRow> = ledger.into_output_rows(AccountOrder::FirstSeen, columns).collect();
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for output_row in output_rows {
+        writer.serialize(output_row).expect("Error when serializing record");
+    }
+    assert_eq!(String::from_utf8(result.stdout).unwrap(), String::from_utf8(writer.into_inner().unwrap()).unwrap());
+}
+
+#[test]
+fn fails_on_negative_balance() {
+    let dir = tempfile::tempdir().expect("Could not create temp dir");
+    let path = dir.path().join("overdrawn.csv");
+    std::fs::write(&path, "type, client, tx, amount\ndeposit, 1, 1, 1.0\nwithdrawal, 1, 2, 3.0\n").unwrap();
+    let run = |extra_args: &[&str]| std::process::Command::new(env!("CARGO_BIN_EXE_financial-assessment-e2324103"))
+        .arg(&path)
+        .args(["--overdraft", "5"])
+        .args(extra_args)
+        .output()
+        .expect("Could not run binary");
+
+    let result = run(&[]);
+    assert!(result.status.success());
+    assert!(String::from_utf8_lossy(&result.stderr).contains("Client 1 ends with a negative available balance of -2.0000"));
+
+    let result = run(&["--show-negative"]);
+    assert!(result.status.success());
+    assert_eq!(String::from_utf8(result.stdout).unwrap(), "client,available,held,total,locked,negative
+1,-2.0000,0.0000,-2.0000,false,true
+");
+
+    let result = run(&["--fail-on-negative"]);
+    assert_eq!(result.status.code(), Some(3));
+    assert!(result.stdout.is_empty());
+}
+
+#[test]
+fn limits_rows_read() {
+    let run = |extra_args: &[&str]| std::process::Command::new(env!("CARGO_BIN_EXE_financial-assessment-e2324103"))
+        .args(["transactions.csv", "--max-rows", "3"])
+        .args(extra_args)
+        .output()
+        .expect("Could not run binary");
+
+    let result = run(&[]);
+    assert_eq!(result.status.code(), Some(2));
+    assert!(result.stdout.is_empty());
+
+    let result = run(&["--on-limit", "truncate"]);
+    assert!(result.status.success());
+    assert_eq!(String::from_utf8(result.stdout).unwrap(), "client,available,held,total,locked
+1,3.0000,0.0000,3.0000,false
+2,2.0000,0.0000,2.0000,false
+");
+}
+
+#[test]
+fn ledger_lists_every_applied_transaction() {
+    let dir = tempfile::tempdir().expect("Could not create temp dir");
+    let path = dir.path().join("ledger.csv");
+    let result = std::process::Command::new(env!("CARGO_BIN_EXE_financial-assessment-e2324103"))
+        .arg("txs-with-disputes.csv")
+        .arg("--ledger")
+        .arg(&path)
+        .output()
+        .expect("Could not run binary");
+    assert!(result.status.success(), "run failed: {}", String::from_utf8_lossy(&result.stderr));
+    // The summary output is unchanged
+    assert_eq!(String::from_utf8(result.stdout).unwrap(), process_file("txs-with-disputes.csv"));
+
+    let written = std::fs::read_to_string(&path).expect("Ledger not written");
+    let mut lines = written.lines();
+    assert_eq!(lines.next(), Some("client,tx,type,amount,dispute_state"));
+    let listed: Vec<(u16, u32)> = lines
+        .map(|line| {
+            let mut fields = line.split(',');
+            (fields.next().unwrap().parse().unwrap(), fields.next().unwrap().parse().unwrap())
+        })
+        .collect();
+    // Withdrawal 4 overdraws the account, and deposit 6 arrives after it is frozen
+    assert_eq!(listed, vec![(1, 1), (1, 2), (1, 3), (1, 5)]);
+}
+
+#[test]
+fn log_level_follows_rust_log() {
+    let dir = tempfile::tempdir().expect("Could not create temp dir");
+    let path = dir.path().join("bad-row.csv");
+    std::fs::write(&path, "type, client, tx, amount\ndeposit, 1, 1, 1.0\nbogus, 1, 2, 1.0\n").unwrap();
+    let run = |rust_log: Option<&str>| {
+        let mut command = std::process::Command::new(env!("CARGO_BIN_EXE_financial-assessment-e2324103"));
+        command.arg(&path).env_remove("RUST_LOG");
+        if let Some(rust_log) = rust_log {
+            command.env("RUST_LOG", rust_log);
+        }
+        command.output().expect("Could not run binary")
+    };
+
+    let result = run(None);
+    assert!(String::from_utf8_lossy(&result.stderr).contains("Ignoring row 2: bogus 2: unknown transaction type"));
+    assert_eq!(String::from_utf8(result.stdout).unwrap(), "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n");
+
+    let result = run(Some("error"));
+    assert!(result.stderr.is_empty());
+    assert_eq!(String::from_utf8(result.stdout).unwrap(), "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n");
+}
+
+#[test]
+fn quiet_writes_nothing_to_stderr() {
+    let dir = tempfile::tempdir().expect("Could not create temp dir");
+    let path = dir.path().join("bad-row.csv");
+    std::fs::write(&path, "type, client, tx, amount\ndeposit, 1, 1, 1.0\nbogus, 1, 2, 1.0\nwithdrawal, 1, 3, 5.0\n").unwrap();
+    let result = std::process::Command::new(env!("CARGO_BIN_EXE_financial-assessment-e2324103"))
+        .arg(&path)
+        .arg("--quiet")
+        .env("RUST_LOG", "debug")
+        .output().expect("Could not run binary");
+    assert!(result.status.success());
+    assert!(result.stderr.is_empty(), "stderr: {}", String::from_utf8_lossy(&result.stderr));
+    assert_eq!(String::from_utf8(result.stdout).unwrap(), "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n");
+
+    let result = std::process::Command::new(env!("CARGO_BIN_EXE_financial-assessment-e2324103"))
+        .args(["--quiet", "--bogus-flag"])
+        .output().expect("Could not run binary");
+    assert_eq!(result.status.code(), Some(1));
+    assert!(result.stderr.is_empty());
+}
+
+#[test]
+fn writes_currency_column_when_currencies_are_used() {
+    let dir = tempfile::tempdir().expect("Could not create temp dir");
+    let path = dir.path().join("currencies.csv");
+    std::fs::write(&path, "type,client,tx,amount,currency\ndeposit,1,1,1.0,USD\ndeposit,2,2,2.0,\ndeposit,1,3,3.0,EUR\n").unwrap();
+    let result = std::process::Command::new(env!("CARGO_BIN_EXE_financial-assessment-e2324103"))
+        .arg(&path)
+        .output().expect("Could not run binary");
+    assert_eq!(String::from_utf8(result.stdout).unwrap(), "client,available,held,total,locked,currency
+1,1.0000,0.0000,1.0000,false,USD
+2,2.0000,0.0000,2.0000,false,
+");
+}
+
+#[test]
+fn reports_progress_every_n_rows() {
+    let result = std::process::Command::new(env!("CARGO_BIN_EXE_financial-assessment-e2324103"))
+        .args(["transactions.csv", "--progress-every", "2"])
+        .output().expect("Could not run binary");
+    assert!(result.status.success());
+    assert_eq!(String::from_utf8(result.stdout).unwrap(), process_file("transactions.csv"));
+    let progress: Vec<String> = String::from_utf8(result.stderr).unwrap().lines()
+        .filter(|line| line.starts_with("Processed"))
+        .map(str::to_string)
+        .collect();
+    assert_eq!(progress, [
+        "Processed 2 rows, 2 clients seen so far",
+        "Processed 4 rows, 2 clients seen so far",
+    ]);
+}
+
+#[test]
+fn seeds_accounts_with_opening_balances() {
+    let dir = tempfile::tempdir().expect("Could not create temp dir");
+    let seed_path = dir.path().join("opening.csv");
+    std::fs::write(&seed_path, "client,opening_balance\n1,500\n3,20.5\n").unwrap();
+    let input_path = dir.path().join("input.csv");
+    std::fs::write(&input_path, "type,client,tx,amount\ndeposit,1,1,100\n").unwrap();
+    let result = std::process::Command::new(env!("CARGO_BIN_EXE_financial-assessment-e2324103"))
+        .arg(&input_path)
+        .arg("--seed-accounts")
+        .arg(&seed_path)
+        .output().expect("Could not run binary");
+    assert!(result.status.success());
+    assert_eq!(String::from_utf8(result.stdout).unwrap(), "client,available,held,total,locked
+1,600.0000,0.0000,600.0000,false
+3,20.5000,0.0000,20.5000,false
+");
+}
+
+#[test]
+fn writes_summary_after_accounts() {
+    let result = std::process::Command::new(env!("CARGO_BIN_EXE_financial-assessment-e2324103"))
+        .args(["transactions.csv", "--summary"])
+        .output().expect("Could not run binary");
+    assert!(result.status.success(), "run failed: {}", String::from_utf8_lossy(&result.stderr));
+    assert_eq!(String::from_utf8(result.stdout).unwrap(), process_file("transactions.csv") + "
+currency,available,held,total
+all,3.5000,0.0000,3.5000
+");
+}
+
+#[test]
+fn diff_against_outputs_only_changed_accounts() {
+    let dir = tempfile::tempdir().expect("Could not create temp dir");
+    let baseline_path = dir.path().join("baseline.snapshot");
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_financial-assessment-e2324103"))
+        .args(["transactions.csv", "--snapshot-out"])
+        .arg(&baseline_path)
+        .output().expect("Could not run binary").status;
+    assert!(status.success());
+
+    let run_against_baseline = |input: &str| {
+        let input_path = dir.path().join("input.csv");
+        std::fs::write(&input_path, input).unwrap();
+        let result = std::process::Command::new(env!("CARGO_BIN_EXE_financial-assessment-e2324103"))
+            .arg(&input_path)
+            .arg("--snapshot-in")
+            .arg(&baseline_path)
+            .arg("--diff-against")
+            .arg(&baseline_path)
+            .output().expect("Could not run binary");
+        assert!(result.status.success(), "run failed: {}", String::from_utf8_lossy(&result.stderr));
+        String::from_utf8(result.stdout).unwrap()
+    };
+    assert_eq!(run_against_baseline("type,client,tx,amount\n"), "");
+    assert_eq!(run_against_baseline("type,client,tx,amount\ndeposit,2,6,1.0\n"), "client,available,held,total,locked
+2,3.0000,0.0000,3.0000,false
+");
+}
+
+#[test]
+fn warns_of_transactions_for_accounts_frozen_in_the_snapshot() {
+    let dir = tempfile::tempdir().expect("Could not create temp dir");
+    let run = |input: &str, snapshot_arg: &str| {
+        let input_path = dir.path().join("input.csv");
+        std::fs::write(&input_path, input).unwrap();
+        let result = std::process::Command::new(env!("CARGO_BIN_EXE_financial-assessment-e2324103"))
+            .arg(&input_path)
+            .arg(snapshot_arg)
+            .arg(dir.path().join("resume.snapshot"))
+            .env_remove("RUST_LOG")
+            .output().expect("Could not run binary");
+        assert!(result.status.success(), "run failed: {}", String::from_utf8_lossy(&result.stderr));
+        String::from_utf8(result.stderr).unwrap()
+    };
+    run("type,client,tx,amount\ndeposit,1,1,5.0\ndispute,1,1,\nchargeback,1,1,\ndeposit,2,2,1.0\n", "--snapshot-out");
+
+    let stderr = run("type,client,tx,amount\ndeposit,1,3,2.0\ndeposit,2,4,2.0\n", "--snapshot-in");
+    assert!(stderr.contains("Ignoring row 1: client 1 was already frozen in the snapshot it was resumed from"), "stderr was: {}", stderr);
+    assert!(!stderr.contains("row 2"), "stderr was: {}", stderr);
+}
+
+#[test]
+fn verbose_reports_rounded_amounts() {
+    let dir = tempfile::tempdir().expect("Could not create temp dir");
+    let path = dir.path().join("precise.csv");
+    std::fs::write(&path, "type,client,tx,amount\ndeposit,1,1,10.123456\ndeposit,1,2,10.12\n").unwrap();
+    let result = std::process::Command::new(env!("CARGO_BIN_EXE_financial-assessment-e2324103"))
+        .arg(&path)
+        .arg("--verbose")
+        .env_remove("RUST_LOG")
+        .output().expect("Could not run binary");
+    assert!(result.status.success());
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert_eq!(stderr.matches("Rounding amount 10.123456 of tx 1 to 4 decimal places: 10.1235").count(), 1, "stderr was: {}", stderr);
+    assert!(!stderr.contains("of tx 2"), "stderr was: {}", stderr);
+}
+
+#[test]
+fn assert_state_compares_output_with_expected_accounts() {
+    let dir = tempfile::tempdir().expect("Could not create temp dir");
+    let expected_path = dir.path().join("expected.csv");
+    let run = |expected: &str| {
+        std::fs::write(&expected_path, expected).unwrap();
+        std::process::Command::new(env!("CARGO_BIN_EXE_financial-assessment-e2324103"))
+            .arg("transactions.csv")
+            .arg("--assert-state")
+            .arg(&expected_path)
+            .output().expect("Could not run binary")
+    };
+
+    let result = run("client,available,held,total,locked\n1,1.5,0,1.5,false\n2,2.0,0,2.0,false\n");
+    assert!(result.status.success(), "run failed: {}", String::from_utf8_lossy(&result.stderr));
+    assert_eq!(String::from_utf8(result.stdout).unwrap(), process_file("transactions.csv"));
+
+    let result = run("client,available,held,total,locked\n1,1.5,0,1.5,false\n2,5.0,0,5.0,false\n");
+    assert_eq!(result.status.code(), Some(3));
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(stderr.contains("- 2,5.0,0,5.0,false\n+ 2,2.0000,0.0000,2.0000,false\n"), "stderr was: {}", stderr);
+}
+
+#[test]
+fn dry_apply_respects_amount_limits() {
+    let run = |extra_args: &[&str]| std::process::Command::new(env!("CARGO_BIN_EXE_financial-assessment-e2324103"))
+        .args(["transactions.csv", "--dry-apply", "deposit,1,99,500.5"])
+        .args(extra_args)
+        .output()
+        .expect("Could not run binary");
+
+    let result = run(&[]);
+    assert!(result.status.success());
+    assert!(String::from_utf8_lossy(&result.stderr).contains("would succeed"));
+
+    let result = run(&["--max-amount", "100"]);
+    assert_eq!(result.status.code(), Some(1));
+    assert!(result.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&result.stderr).contains("Invalid --dry-apply transaction: amount is too large"));
+
+    let result = run(&["--max-input-scale", "0"]);
+    assert_eq!(result.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&result.stderr).contains("Invalid --dry-apply transaction: amount has too many decimal places (1)"));
+}